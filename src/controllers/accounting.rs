@@ -0,0 +1,23 @@
+use super::*;
+use chrono::Duration;
+
+#[derive(FromFormField)]
+pub enum ExportFormat {
+  Csv,
+  Journal,
+}
+
+/* Both dates are inclusive. */
+#[get("/export?<from>&<until>&<format>")]
+pub async fn export<'a>(site: &'a State<Site>, from: &str, until: &str, format: Option<ExportFormat>, _session: AdminSession) -> Result<(ContentType, String)> {
+  let from = parse_date("from", from)?;
+  let until = parse_date("until", until)? + Duration::days(1);
+  let export = AccountingExport::build(site, from, until).await?;
+
+  let body = match format.unwrap_or(ExportFormat::Csv) {
+    ExportFormat::Csv => export.to_csv(),
+    ExportFormat::Journal => export.to_journal(),
+  };
+
+  Ok((ContentType::CSV, body))
+}
@@ -0,0 +1,43 @@
+use crate::models::CohortForm;
+use super::*;
+
+#[get("/?<program>")]
+pub async fn index<'a>(site: &'a State<Site>, program: Option<Program>, _session: AdminSession) -> JsonResult<Vec<Cohort>> {
+  let mut select = site.cohort().select();
+  if let Some(p) = program {
+    select = select.program_eq(&p);
+  }
+  Ok(Json(select.order_by(CohortOrderBy::StartsAt).all().await?))
+}
+
+#[get("/open?<program>")]
+pub async fn open<'a>(site: &'a State<Site>, program: Option<Program>) -> JsonResult<Vec<Cohort>> {
+  Ok(Json(site.cohort().open(program.unwrap_or_default()).await?))
+}
+
+#[post("/", data = "<form>")]
+pub async fn create<'a>(form: Json<CohortForm>, site: &'a State<Site>, _session: AdminSession) -> JsonResult<Cohort> {
+  Ok(Json(site.cohort().insert().use_struct(form.0.into_insert_cohort()?).save().await?))
+}
+
+#[get("/<cohort_id>")]
+pub async fn show<'a>(site: &'a State<Site>, cohort_id: i32, _session: AdminSession) -> JsonResult<CohortState> {
+  let cohort = site.cohort().find(&cohort_id).await?;
+  Ok(Json(CohortState::new(cohort).await?))
+}
+
+#[post("/<cohort_id>/enrollment?<open>")]
+pub async fn set_enrollment<'a>(site: &'a State<Site>, cohort_id: i32, open: bool, _session: AdminSession) -> JsonResult<Cohort> {
+  let mut cohort = site.cohort().find(&cohort_id).await?;
+  cohort.set_enrollment_open(open).await?;
+  Ok(Json(cohort))
+}
+
+#[post("/<cohort_id>/move_student/<student_id>?<force>")]
+pub async fn move_student<'a>(site: &'a State<Site>, cohort_id: i32, student_id: i32, force: Option<bool>, _session: AdminSession) -> JsonResult<StudentState> {
+  let cohort = site.cohort().find(&cohort_id).await?;
+  let student = site.student().find(&student_id).await?;
+  let mut subscription = student.subscription(cohort.attrs.program).await?;
+  subscription.move_to_cohort(&cohort, force.unwrap_or(false)).await?;
+  Ok(Json(StudentState::new(student).await?))
+}
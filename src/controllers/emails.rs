@@ -0,0 +1,46 @@
+use super::*;
+use crate::templating;
+
+/* Sendinblue can't sign its webhooks, the url they post to carries a shared secret instead. */
+#[post("/sendinblue_webhook?<secret>", data = "<event>")]
+pub async fn sendinblue_webhook<'a>(site: &'a State<Site>, secret: &str, event: Json<SendinblueEvent>) -> JsonResult<&'static str> {
+  if secret != site.settings.sendinblue.webhooks_secret {
+    return Err(Error::validation("secret", "invalid webhook secret"));
+  }
+
+  site.sent_email().process_sendinblue_event(&event).await?;
+  Ok(Json("OK"))
+}
+
+#[post("/<sent_email_id>/resend")]
+pub async fn resend<'a>(site: &'a State<Site>, sent_email_id: i32, _session: AdminSession) -> JsonResult<SentEmail> {
+  let sent = site.sent_email().find(&sent_email_id).await?;
+  Ok(Json(sent.resend().await?))
+}
+
+/* Renders any template with made up values, or with those of a real student, in the language asked for. */
+#[get("/preview?<template>&<student_id>&<locale>")]
+pub async fn preview<'a>(site: &'a State<Site>, template: &str, student_id: Option<i32>, locale: Option<Locale>, _session: AdminSession) -> Result<(ContentType, String)> {
+  let student = match student_id {
+    Some(id) => Some(site.student().find(&id).await?),
+    None => None,
+  };
+  let locale = locale.or_else(|| student.as_ref().map(|s| s.attrs.locale)).unwrap_or_default();
+  let context = preview_context(site, student).await?;
+  let rendered = templating::render(&locale.template(template), &context)?;
+
+  let content_type = if template.starts_with("emails/") { ContentType::HTML } else { ContentType::Plain };
+  Ok((content_type, rendered))
+}
+
+#[get("/templates")]
+pub async fn templates(_session: AdminSession) -> JsonResult<Vec<String>> {
+  Ok(Json(templating::names()))
+}
+
+#[post("/templates/reload")]
+pub async fn reload_templates<'a>(site: &'a State<Site>, _session: AdminSession) -> JsonResult<usize> {
+  let dir = site.settings.templates_dir.as_ref()
+    .ok_or_else(|| Error::validation("templates_dir", "no templates directory is configured"))?;
+  Ok(Json(templating::load_overrides(std::path::Path::new(dir))?))
+}
@@ -0,0 +1,16 @@
+use crate::models::ExchangeRateForm;
+use super::*;
+
+#[get("/?<currency>")]
+pub async fn index<'a>(site: &'a State<Site>, currency: Option<Currency>, _session: AdminSession) -> JsonResult<Vec<ExchangeRate>> {
+  let mut select = site.exchange_rate().select();
+  if let Some(c) = currency {
+    select = select.currency_eq(&c);
+  }
+  Ok(Json(select.order_by(ExchangeRateOrderBy::Id).all().await?))
+}
+
+#[post("/", data = "<form>")]
+pub async fn create<'a>(form: Json<ExchangeRateForm>, site: &'a State<Site>, _session: AdminSession) -> JsonResult<ExchangeRate> {
+  Ok(Json(site.exchange_rate().create(form.0).await?))
+}
@@ -2,14 +2,15 @@ use rocket::{
   self,
   get,
   post,
-  request::{FromRequest, Outcome, Request},
+  request::{FromRequest, FromParam, Outcome, Request},
   serde::json::Json,
   State,
-  http::Status,
+  http::{Status, ContentType},
   data::{self, Data, FromData, ToByteUnit},
 };
 use crate::error::*;
 use crate::models::*;
+use chrono::{NaiveDate, TimeZone};
 use sha2::Sha256;
 use hmac::{Hmac, Mac, NewMac};
 
@@ -17,9 +18,18 @@ use hmac::{Hmac, Mac, NewMac};
 type HmacSha256 = Hmac<Sha256>;
 
 pub type JsonResult<T> = Result<Json<T>>;
+pub type PdfResult = Result<(ContentType, Vec<u8>)>;
 
 pub mod students;
 pub mod payments;
+pub mod cohorts;
+pub mod waitlist;
+pub mod scholarships;
+pub mod organizations;
+pub mod exchange_rates;
+pub mod accounting;
+pub mod reports;
+pub mod emails;
 
 #[rocket::async_trait]
 impl<'r> FromRequest<'r> for Country {
@@ -30,6 +40,27 @@ impl<'r> FromRequest<'r> for Country {
   }
 }
 
+/* Admin report and export ranges come as plain dates. */
+pub fn parse_date(field: &str, value: &str) -> Result<UtcDateTime> {
+  let date = NaiveDate::parse_from_str(value, "%Y-%m-%d")
+    .map_err(|_| Error::validation(field, "must be a date like 2022-06-30"))?;
+  Ok(Utc.from_utc_datetime(&date.and_hms(0, 0, 0)))
+}
+
+/* A path segment like "12.pdf", used for downloadable documents. */
+pub struct PdfName(pub i32);
+
+impl<'a> FromParam<'a> for PdfName {
+  type Error = &'a str;
+
+  fn from_param(param: &'a str) -> std::result::Result<Self, Self::Error> {
+    param.strip_suffix(".pdf")
+      .and_then(|id| id.parse::<i32>().ok())
+      .map(PdfName)
+      .ok_or(param)
+  }
+}
+
 pub struct StripeWebhook{
   event: stripe::Event
 }
@@ -0,0 +1,40 @@
+use crate::models::OrganizationForm;
+use super::*;
+
+#[get("/")]
+pub async fn index<'a>(site: &'a State<Site>, _session: AdminSession) -> JsonResult<Vec<Organization>> {
+  Ok(Json(site.organization().select().order_by(OrganizationOrderBy::Id).all().await?))
+}
+
+#[post("/", data = "<form>")]
+pub async fn create<'a>(form: Json<OrganizationForm>, site: &'a State<Site>, _session: AdminSession) -> JsonResult<Organization> {
+  Ok(Json(site.organization().insert().use_struct(form.0.into_insert_organization(site).await?).save().await?))
+}
+
+#[get("/<organization_id>")]
+pub async fn show<'a>(site: &'a State<Site>, organization_id: i32, _session: AdminSession) -> JsonResult<OrganizationState> {
+  let organization = site.organization().find(&organization_id).await?;
+  Ok(Json(OrganizationState::new(organization).await?))
+}
+
+#[post("/<organization_id>/sponsor/<student_id>")]
+pub async fn sponsor<'a>(site: &'a State<Site>, organization_id: i32, student_id: i32, _session: AdminSession) -> JsonResult<OrganizationState> {
+  let organization = site.organization().find(&organization_id).await?;
+  let mut student = site.student().find(&student_id).await?;
+  organization.sponsor(&mut student).await?;
+  Ok(Json(OrganizationState::new(organization).await?))
+}
+
+#[post("/<organization_id>/stop_sponsoring/<student_id>")]
+pub async fn stop_sponsoring<'a>(site: &'a State<Site>, organization_id: i32, student_id: i32, _session: AdminSession) -> JsonResult<OrganizationState> {
+  let organization = site.organization().find(&organization_id).await?;
+  let mut student = site.student().find(&student_id).await?;
+  organization.stop_sponsoring(&mut student).await?;
+  Ok(Json(OrganizationState::new(organization).await?))
+}
+
+#[post("/<organization_id>/invoice")]
+pub async fn invoice<'a>(site: &'a State<Site>, organization_id: i32, _session: AdminSession) -> JsonResult<Vec<OrganizationInvoice>> {
+  let organization = site.organization().find(&organization_id).await?;
+  Ok(Json(organization.invoice_all_not_invoiced_yet().await?))
+}
@@ -19,7 +19,33 @@ pub async fn from_invoice<'a>(site: &'a State<Site>, invoice_id: i32, _session:
   Ok(Json("OK"))
 }
 
-#[get("/get_pricing")]
-pub async fn get_pricing(country: Country, site: &State<Site>) -> Json<(Plan, Plan)> {
-  Json((site.settings.pricing.global.clone(), country.plan()))
+#[post("/from_organization_invoice?<organization_invoice_id>")]
+pub async fn from_organization_invoice<'a>(site: &'a State<Site>, organization_invoice_id: i32, _session: AdminSession) -> JsonResult<&str> {
+  site.payment().from_organization_invoice(organization_invoice_id).await?;
+  Ok(Json("OK"))
+}
+
+#[post("/<payment_id>/refund", data = "<form>")]
+pub async fn refund<'a>(form: Json<RefundForm>, site: &'a State<Site>, payment_id: i32, _session: AdminSession) -> JsonResult<Refund> {
+  let payment = site.payment().find(&payment_id).await?;
+  Ok(Json(payment.refund(form.0).await?))
+}
+
+#[post("/dunning")]
+pub async fn dunning<'a>(site: &'a State<Site>, _session: AdminSession) -> JsonResult<&str> {
+  site.invoice().process_dunning().await?;
+  Ok(Json("OK"))
+}
+
+/* Looks back 3 days unless told otherwise. */
+#[post("/reconcile?<days>")]
+pub async fn reconcile<'a>(site: &'a State<Site>, days: Option<i64>, _session: AdminSession) -> JsonResult<ReconciliationReport> {
+  let since = Utc::now() - chrono::Duration::days(days.unwrap_or(3));
+  Ok(Json(ReconciliationReport::build(site, since).await?))
+}
+
+#[get("/get_pricing?<program>")]
+pub async fn get_pricing(country: Country, program: Option<Program>, site: &State<Site>) -> Json<(Plan, Plan)> {
+  let program = program.unwrap_or_default();
+  Json((site.settings.pricing.get(program).global.clone(), country.plan(program)))
 }
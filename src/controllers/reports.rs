@@ -0,0 +1,26 @@
+use super::*;
+use chrono::Duration;
+
+fn report_range(from: &str, until: &str, period: Option<ReportPeriod>) -> Result<ReportRange> {
+  Ok(ReportRange{
+    from: parse_date("from", from)?,
+    until: parse_date("until", until)? + Duration::days(1),
+    period: period.unwrap_or(ReportPeriod::Month),
+  })
+}
+
+/* Both dates are inclusive, periods default to months. */
+#[get("/funnel?<from>&<until>&<period>")]
+pub async fn funnel<'a>(site: &'a State<Site>, from: &str, until: &str, period: Option<ReportPeriod>, _session: AdminSession) -> JsonResult<Vec<FunnelRow>> {
+  Ok(Json(report_range(from, until, period)?.funnel(site).await?))
+}
+
+#[get("/revenue?<from>&<until>&<period>")]
+pub async fn revenue<'a>(site: &'a State<Site>, from: &str, until: &str, period: Option<ReportPeriod>, _session: AdminSession) -> JsonResult<RevenueReport> {
+  Ok(Json(report_range(from, until, period)?.revenue(site).await?))
+}
+
+#[get("/receivables")]
+pub async fn receivables<'a>(site: &'a State<Site>, _session: AdminSession) -> JsonResult<Vec<ReceivablesRow>> {
+  Ok(Json(report::receivables(site).await?))
+}
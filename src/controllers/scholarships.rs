@@ -0,0 +1,33 @@
+use crate::models::ScholarshipForm;
+use super::*;
+
+#[get("/?<status>")]
+pub async fn index<'a>(site: &'a State<Site>, status: Option<ScholarshipStatus>, _session: AdminSession) -> JsonResult<Vec<Scholarship>> {
+  let mut select = site.scholarship().select();
+  if let Some(s) = status {
+    select = select.status_eq(&s);
+  }
+  Ok(Json(select.order_by(ScholarshipOrderBy::Id).all().await?))
+}
+
+#[post("/", data = "<form>")]
+pub async fn create<'a>(form: Json<ScholarshipForm>, site: &'a State<Site>, _session: AdminSession) -> JsonResult<Scholarship> {
+  let form = form.0;
+  site.student().find(&form.student_id).await?;
+  Ok(Json(site.scholarship().request(form.student_id, form.program, form.request).await?))
+}
+
+#[post("/<scholarship_id>/approve")]
+pub async fn approve<'a>(site: &'a State<Site>, scholarship_id: i32, _session: AdminSession) -> JsonResult<StudentState> {
+  let mut scholarship = site.scholarship().find(&scholarship_id).await?;
+  scholarship.approve().await?;
+  let student = site.student().find(&scholarship.attrs.student_id).await?;
+  Ok(Json(StudentState::new(student).await?))
+}
+
+#[post("/<scholarship_id>/reject")]
+pub async fn reject<'a>(site: &'a State<Site>, scholarship_id: i32, _session: AdminSession) -> JsonResult<Scholarship> {
+  let mut scholarship = site.scholarship().find(&scholarship_id).await?;
+  scholarship.reject().await?;
+  Ok(Json(scholarship))
+}
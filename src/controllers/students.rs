@@ -1,4 +1,4 @@
-use crate::models::{PublicStudentForm, DiscordToken};
+use crate::models::{PublicStudentForm, DiscordAuthorization};
 use super::*;
 
 #[get("/")]
@@ -6,39 +6,189 @@ pub async fn index<'a>(site: &'a State<Site>, _session: AdminSession) -> JsonRes
   Ok(Json(site.student().select().order_by(StudentOrderBy::Id).all().await?))
 }
 
-#[post("/discord_success?<discord_data..>")]
-pub async fn discord_success(site: &State<Site>, discord_data: DiscordToken) -> Result<String> {
-  site.student().process_discord_response(discord_data).await
+#[post("/discord_success?<authorization..>")]
+pub async fn discord_success(site: &State<Site>, authorization: DiscordAuthorization) -> Result<String> {
+  site.student().process_discord_response(authorization).await
+}
+
+/* For students that left the server by mistake, using the grant they gave us when they linked their account. */
+#[post("/<student_id>/discord/rejoin")]
+pub async fn rejoin_discord<'a>(site: &'a State<Site>, student_id: i32, _session: AdminSession) -> JsonResult<GuildMembership> {
+  let mut grant = site.discord_grant().select().student_id_eq(&student_id).one().await?;
+  let membership = grant.join_guild().await?;
+  site.student().reconcile_access(Some(student_id), false).await?;
+  Ok(Json(membership))
 }
 
 #[post("/", data = "<form>")]
-pub async fn create<'a>(form: Json<PublicStudentForm>, country: Country, site: &'a State<Site>) -> JsonResult<StudentState> {
+pub async fn create<'a>(form: Json<PublicStudentForm>, country: Country, site: &'a State<Site>) -> JsonResult<SignupOutcome> {
+  let program = form.program;
+  let scholarship_request = form.scholarship.clone();
+  let cohort = site.cohort().for_enrollment(program, form.cohort_id).await?;
+  let seat_lock = match cohort {
+    Some(ref c) => Some(c.lock_seats().await?),
+    None => None,
+  };
+
+  let full = match cohort {
+    Some(ref c) => c.seats_left().await? == 0,
+    None => false,
+  };
+
+  if full {
+    let waitlist_entry = site.waitlist_entry().join(form.0, &country).await?;
+    return Ok(Json(SignupOutcome::Waitlisted{ waitlist_entry }));
+  }
+
   let student = site.student().insert()
-    .use_struct(form.0.into_insert_student(&country))
-    .save_and_subscribe(country.plan()).await?;
+    .use_struct(form.0.into_insert_student(&country, site).await?)
+    .save_and_subscribe(country.plan(program), program, cohort.as_ref()).await?;
+
+  if let Some(lock) = seat_lock {
+    lock.commit().await?;
+  }
+
+  if let Some(request) = scholarship_request {
+    site.scholarship().request(student.attrs.id, program, request).await?;
+  }
+
   let billing = BillingSummary::new(student).await?;
   billing.invoice_all_not_invoiced_yet().await?;
   billing.student.send_payment_reminder().await?;
-  Ok(Json(StudentState::new(billing.student).await?))
+  Ok(Json(SignupOutcome::Enrolled(StudentState::new(billing.student).await?)))
 }
 
 #[get("/<student_id>")]
 pub async fn show<'a>(site: &'a State<Site>, student_id: i32, _session: AdminSession) -> JsonResult<StudentState> {
   let student = site.student().find(&student_id).await?;
-  Ok(Json(StudentState::new(student).await?))
+  Ok(Json(StudentState::new(student).await?.with_email_log().await?))
 }
 
 #[post("/create_guest", data = "<form>")]
 pub async fn create_guest<'a>(form: Json<PublicStudentForm>, _session: AdminSession, site: &'a State<Site>) -> JsonResult<StudentState> {
+  let program = form.program;
+  let cohort = site.cohort().for_enrollment(program, form.cohort_id).await?;
+  let seat_lock = match cohort {
+    Some(ref c) => Some(c.lock_seats().await?),
+    None => None,
+  };
+  let country = Country("XX".to_string());
   let student = site.student().insert()
-    .use_struct(form.0.into_insert_student(&Country("XX".to_string())))
-    .save_and_subscribe(site.settings.pricing.guest.clone()).await?;
-  student.subscription().await?.on_paid().await?;
+    .use_struct(form.0.into_insert_student(&country, site).await?)
+    .save_and_subscribe(country.plan(program), program, cohort.as_ref()).await?;
+  if let Some(lock) = seat_lock {
+    lock.commit().await?;
+  }
+
+  site.scholarship().request(student.attrs.id, program, ScholarshipRequest{
+    kind: ScholarshipKind::Percentage,
+    value: Decimal::ONE_HUNDRED,
+    sponsor: None,
+    reason: "Guest".to_string(),
+    expires_at: None,
+  }).await?.approve().await?;
+
   Ok(Json(StudentState::new(student).await?))
 }
 
+#[post("/<student_id>/enroll?<program>&<cohort_id>")]
+pub async fn enroll<'a>(site: &'a State<Site>, student_id: i32, program: Program, cohort_id: Option<i32>, _session: AdminSession) -> JsonResult<StudentState> {
+  let student = site.student().find(&student_id).await?;
+  let cohort = site.cohort().for_enrollment(program, cohort_id).await?;
+  let seat_lock = match cohort {
+    Some(ref c) => Some(c.lock_seats().await?),
+    None => None,
+  };
+  student.subscribe(Country(student.attrs.country.clone()).plan(program), program, cohort.as_ref()).await?;
+  if let Some(lock) = seat_lock {
+    lock.commit().await?;
+  }
+  let billing = BillingSummary::new(student).await?;
+  billing.invoice_all_not_invoiced_yet().await?;
+  billing.student.send_payment_reminder().await?;
+  Ok(Json(StudentState::new(billing.student).await?))
+}
+
+/* Optionally switching payment method. Students authenticate with the access token they got on signup. */
+#[post("/<student_id>/invoices/regenerate?<payment_method>&<token>")]
+pub async fn regenerate_invoices<'a>(site: &'a State<Site>, student_id: i32, payment_method: Option<PaymentMethod>, token: Option<String>, session: Option<AdminSession>) -> JsonResult<StudentState> {
+  let mut student = site.student().find(&student_id).await?;
+
+  if session.is_none() {
+    student.check_access_token(&token.unwrap_or_default())?;
+  }
+
+  student.regenerate_invoices(payment_method).await?;
+  Ok(Json(StudentState::new(student).await?))
+}
+
+#[get("/<student_id>/invoices")]
+pub async fn fiscal_invoices<'a>(site: &'a State<Site>, student_id: i32, _session: AdminSession) -> JsonResult<Vec<FiscalInvoiceState>> {
+  let student = site.student().find(&student_id).await?;
+  let mut states = vec![];
+  for invoice in student.fiscal_invoices().await? {
+    states.push(FiscalInvoiceState::new(invoice).await?);
+  }
+  Ok(Json(states))
+}
+
+/* Students reach their invoices through the link we email them, which carries the invoice access token. */
+#[get("/<student_id>/invoices/<invoice_id>?<token>", rank = 2)]
+pub async fn fiscal_invoice<'a>(site: &'a State<Site>, student_id: i32, invoice_id: i32, token: Option<String>, session: Option<AdminSession>) -> JsonResult<FiscalInvoiceState> {
+  let invoice = site.fiscal_invoice().select()
+    .id_eq(&invoice_id)
+    .student_id_eq(&Some(student_id))
+    .one().await?;
+
+  if session.is_none() {
+    invoice.check_access_token(&token.unwrap_or_default())?;
+  }
+
+  Ok(Json(FiscalInvoiceState::new(invoice).await?))
+}
+
+#[get("/<student_id>/invoices/<invoice>?<token>", rank = 1)]
+pub async fn fiscal_invoice_pdf<'a>(site: &'a State<Site>, student_id: i32, invoice: PdfName, token: Option<String>, session: Option<AdminSession>) -> PdfResult {
+  let invoice = site.fiscal_invoice().select()
+    .id_eq(&invoice.0)
+    .student_id_eq(&Some(student_id))
+    .one().await?;
+
+  if session.is_none() {
+    invoice.check_access_token(&token.unwrap_or_default())?;
+  }
+
+  Ok((ContentType::PDF, invoice.pdf().await?))
+}
+
+#[get("/<student_id>/receipts/<payment>?<token>")]
+pub async fn receipt_pdf<'a>(site: &'a State<Site>, student_id: i32, payment: PdfName, token: Option<String>, session: Option<AdminSession>) -> PdfResult {
+  let payment = site.payment().select()
+    .id_eq(&payment.0)
+    .student_id_eq(&student_id)
+    .one().await?;
+
+  if session.is_none() {
+    payment.check_access_token(&token.unwrap_or_default())?;
+  }
+
+  Ok((ContentType::PDF, payment.receipt_pdf().await?))
+}
+
 #[get("/by_wordpress_id/<wordpress_id>")]
 pub async fn by_wordpress_id<'a>(site: &'a State<Site>, wordpress_id: String, _session: AdminSession) -> JsonResult<StudentState> {
   let student = site.student().select().wordpress_user_eq(&Some(wordpress_id)).one().await?;
   Ok(Json(StudentState::new(student).await?))
 }
+
+#[post("/sync_course_progress?<student_id>")]
+pub async fn sync_course_progress<'a>(site: &'a State<Site>, student_id: Option<i32>, _session: AdminSession) -> JsonResult<&str> {
+  site.student().sync_course_progress(student_id).await?;
+  Ok(Json("OK"))
+}
+
+/* Reports what would change unless dry_run=false is given explicitly. */
+#[post("/reconcile_access?<student_id>&<dry_run>")]
+pub async fn reconcile_access<'a>(site: &'a State<Site>, student_id: Option<i32>, dry_run: Option<bool>, _session: AdminSession) -> JsonResult<Vec<AccessReport>> {
+  Ok(Json(site.student().reconcile_access(student_id, dry_run.unwrap_or(true)).await?))
+}
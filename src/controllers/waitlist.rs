@@ -0,0 +1,27 @@
+use super::*;
+
+#[get("/?<program>")]
+pub async fn index<'a>(site: &'a State<Site>, program: Option<Program>, _session: AdminSession) -> JsonResult<Vec<WaitlistEntry>> {
+  let mut select = site.waitlist_entry().select();
+  if let Some(p) = program {
+    select = select.program_eq(&p);
+  }
+  Ok(Json(select.order_by(WaitlistEntryOrderBy::CreatedAt).all().await?))
+}
+
+#[post("/process")]
+pub async fn process<'a>(site: &'a State<Site>, _session: AdminSession) -> JsonResult<&str> {
+  site.waitlist_entry().process().await?;
+  Ok(Json("OK"))
+}
+
+#[get("/invitations/<token>")]
+pub async fn show_invitation<'a>(site: &'a State<Site>, token: String) -> JsonResult<WaitlistEntry> {
+  Ok(Json(site.waitlist_entry().by_invitation_token(&token).await?))
+}
+
+#[post("/invitations/<token>/accept")]
+pub async fn accept_invitation<'a>(site: &'a State<Site>, token: String) -> JsonResult<StudentState> {
+  let student = site.waitlist_entry().by_invitation_token(&token).await?.accept().await?;
+  Ok(Json(StudentState::new(student).await?))
+}
@@ -0,0 +1,258 @@
+use crate::{
+  error::Result,
+  http::ApiClient,
+  models::Site,
+};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use tokio::io::AsyncWriteExt;
+
+/* Who an email comes from. Replies may go somewhere else, like the treasury inbox. */
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+pub struct Identity {
+  pub name: String,
+  pub email: String,
+  pub reply_to: Option<String>,
+}
+
+#[derive(sqlx::Type, Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[sqlx(type_name = "email_sender", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum Sender {
+  Default,
+  Billing,
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum EmailBackend {
+  Sendinblue,
+  Smtp {
+    host: String,
+    port: u16,
+    username: Option<String>,
+    password: Option<String>,
+  },
+  /* Appends every email as a JSON line to a local file, for development and tests. */
+  FileSink {
+    path: String,
+  },
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+pub struct EmailSettings {
+  pub backend: EmailBackend,
+  pub sender: Identity,
+  pub billing_sender: Option<Identity>,
+}
+
+impl EmailSettings {
+  pub fn identity(&self, sender: Sender) -> &Identity {
+    match (sender, &self.billing_sender) {
+      (Sender::Billing, Some(billing)) => billing,
+      _ => &self.sender,
+    }
+  }
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+pub struct Attachment {
+  pub filename: String,
+  pub content_base64: String,
+}
+
+impl Attachment {
+  pub fn new(filename: &str, content: &[u8]) -> Attachment {
+    Attachment{ filename: filename.to_string(), content_base64: base64::encode(content) }
+  }
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+pub struct Email {
+  pub from: Identity,
+  pub to_name: String,
+  pub to_email: String,
+  pub subject: String,
+  pub html: String,
+  pub attachments: Vec<Attachment>,
+}
+
+/* Backends return the id their provider gave the message, if any, to match delivery events later. */
+#[rocket::async_trait]
+pub trait EmailSender: Send + Sync {
+  async fn send(&self, email: &Email) -> Result<Option<String>>;
+}
+
+pub fn sender_for(site: &Site) -> Box<dyn EmailSender> {
+  match site.settings.email.backend {
+    EmailBackend::Sendinblue => Box::new(SendinblueSender{ api: site.sendinblue_api() }),
+    EmailBackend::Smtp{ ref host, port, ref username, ref password } => Box::new(SmtpSender{
+      host: host.clone(),
+      port,
+      credentials: username.clone().zip(password.clone()),
+    }),
+    EmailBackend::FileSink{ ref path } => Box::new(FileSink{ path: PathBuf::from(path) }),
+  }
+}
+
+pub struct SendinblueSender {
+  pub api: ApiClient,
+}
+
+#[rocket::async_trait]
+impl EmailSender for SendinblueSender {
+  async fn send(&self, email: &Email) -> Result<Option<String>> {
+    let mut message = serde_json::json!({
+      "sender": {
+        "name": email.from.name,
+        "email": email.from.email,
+      },
+      "to": [{
+        "email": email.to_email,
+        "name": email.to_name,
+      }],
+      "subject": email.subject,
+      "htmlContent": email.html
+    });
+
+    if let Some(ref reply_to) = email.from.reply_to {
+      message["replyTo"] = serde_json::json!({"email": reply_to});
+    }
+
+    if !email.attachments.is_empty() {
+      message["attachment"] = email.attachments.iter()
+        .map(|a| serde_json::json!({"name": a.filename, "content": a.content_base64}))
+        .collect();
+    }
+
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct Sent {
+      message_id: Option<String>,
+    }
+
+    let sent: Sent = self.api.post("/smtp/email", &message).await?.json()?;
+    Ok(sent.message_id)
+  }
+}
+
+pub struct SmtpSender {
+  pub host: String,
+  pub port: u16,
+  pub credentials: Option<(String, String)>,
+}
+
+#[rocket::async_trait]
+impl EmailSender for SmtpSender {
+  async fn send(&self, email: &Email) -> Result<Option<String>> {
+    use lettre::{
+      AsyncSmtpTransport, AsyncTransport, Tokio1Executor, Message,
+      message::{header::ContentType, Attachment as Part, Mailbox, MultiPart, SinglePart},
+      transport::smtp::authentication::Credentials,
+    };
+
+    let mut builder = Message::builder()
+      .from(Mailbox::new(Some(email.from.name.clone()), email.from.email.parse()?))
+      .to(Mailbox::new(Some(email.to_name.clone()), email.to_email.parse()?))
+      .subject(email.subject.clone());
+
+    if let Some(ref reply_to) = email.from.reply_to {
+      builder = builder.reply_to(Mailbox::new(None, reply_to.parse()?));
+    }
+
+    let mut body = MultiPart::mixed().singlepart(SinglePart::html(email.html.clone()));
+    for a in email.attachments.iter() {
+      let content = base64::decode(&a.content_base64)
+        .map_err(|e| crate::error::Error::validation("attachment", &e.to_string()))?;
+      let content_type = ContentType::parse("application/pdf")
+        .map_err(|e| crate::error::Error::validation("attachment", &e.to_string()))?;
+      body = body.singlepart(Part::new(a.filename.clone()).body(content, content_type));
+    }
+
+    let mut transport = AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&self.host)?.port(self.port);
+    if let Some((ref user, ref pass)) = self.credentials {
+      transport = transport.credentials(Credentials::new(user.clone(), pass.clone()));
+    }
+
+    transport.build().send(builder.multipart(body)?).await?;
+    Ok(None)
+  }
+}
+
+pub struct FileSink {
+  pub path: PathBuf,
+}
+
+impl FileSink {
+  pub async fn read_all(&self) -> Result<Vec<Email>> {
+    let contents = match tokio::fs::read_to_string(&self.path).await {
+      Ok(c) => c,
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+      Err(e) => return Err(e.into()),
+    };
+
+    let mut emails = vec![];
+    for line in contents.lines().filter(|l| !l.is_empty()) {
+      emails.push(serde_json::from_str(line)?);
+    }
+    Ok(emails)
+  }
+}
+
+#[rocket::async_trait]
+impl EmailSender for FileSink {
+  async fn send(&self, email: &Email) -> Result<Option<String>> {
+    if let Some(dir) = self.path.parent() {
+      tokio::fs::create_dir_all(dir).await?;
+    }
+
+    let mut file = tokio::fs::OpenOptions::new().create(true).append(true).open(&self.path).await?;
+    file.write_all(format!("{}\n", serde_json::to_string(email)?).as_bytes()).await?;
+    Ok(None)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn identity(email: &str) -> Identity {
+    Identity{ name: "DAO Education".into(), email: email.into(), reply_to: None }
+  }
+
+  #[test]
+  fn picks_billing_identity_when_configured() {
+    let mut settings = EmailSettings{
+      backend: EmailBackend::Sendinblue,
+      sender: identity("hola@dao.education"),
+      billing_sender: None,
+    };
+    assert_eq!(settings.identity(Sender::Billing).email, "hola@dao.education");
+
+    settings.billing_sender = Some(identity("tesoreria@dao.education"));
+    assert_eq!(settings.identity(Sender::Billing).email, "tesoreria@dao.education");
+    assert_eq!(settings.identity(Sender::Default).email, "hola@dao.education");
+  }
+
+  #[tokio::test]
+  async fn file_sink_keeps_every_email() {
+    let path = std::env::temp_dir().join(format!("daoe-file-sink-{}.jsonl", std::process::id()));
+    let sink = FileSink{ path: path.clone() };
+    let _ = tokio::fs::remove_file(&path).await;
+
+    let email = Email{
+      from: identity("hola@dao.education"),
+      to_name: "Testing Testinger".into(),
+      to_email: "yo+testing@nubis.im".into(),
+      subject: "Hola".into(),
+      html: "<p>Hola</p>".into(),
+      attachments: vec![Attachment::new("a.pdf", b"%PDF")],
+    };
+
+    sink.send(&email).await.unwrap();
+    sink.send(&email).await.unwrap();
+    assert_eq!(sink.read_all().await.unwrap(), vec![email.clone(), email]);
+
+    tokio::fs::remove_file(&path).await.unwrap();
+  }
+}
@@ -1,5 +1,7 @@
 use sqlx::postgres::PgDatabaseError;
 use std::error::Error as ErrorTrait;
+use crate::http::HttpError;
+use crate::models::{Locale, ValidationMessage};
 
 use rocket::{
   http::Status,
@@ -16,7 +18,7 @@ pub enum Error {
   #[error(transparent)]
   DatabaseError(sqlx::Error),
   #[error("Invalid {field}: {message}")]
-  Validation { field: String, message: String },
+  Validation { field: String, message: ValidationMessage },
   #[error(transparent)]
   ValidationError(#[from] validator::ValidationErrors),
   #[error(transparent)]
@@ -29,12 +31,28 @@ pub enum Error {
   JsonSerde(#[from] serde_json::Error),
   #[error("A configured stripe price is wrong")]
   InvalidStripePrice,
+  #[error("A stored secret could not be sealed or opened")]
+  Sealing,
   #[error(transparent)]
   Stripe(#[from] stripe::Error),
   #[error(transparent)]
   ParseIdError(#[from] stripe::ParseIdError),
+  #[error("WordPress: {0}")]
+  Wordpress(HttpError),
+  #[error("Discord: {0}")]
+  Discord(HttpError),
+  #[error("Sendinblue: {0}")]
+  Sendinblue(HttpError),
+  #[error("BTCPay: {0}")]
+  Btcpay(HttpError),
+  #[error("VIES: {0}")]
+  Vies(HttpError),
   #[error(transparent)]
-  UreqError(#[from] ureq::Error),
+  Smtp(#[from] lettre::transport::smtp::Error),
+  #[error(transparent)]
+  EmailMessage(#[from] lettre::error::Error),
+  #[error(transparent)]
+  EmailAddress(#[from] lettre::address::AddressError),
 }
 
 impl From<rocket::form::Errors<'_>> for Error {
@@ -66,7 +84,38 @@ impl Error {
   pub fn validation(field: &str, message: &str) -> Error {
     Error::Validation {
       field: field.to_string(),
-      message: message.to_string(),
+      message: ValidationMessage::Untranslated(message.to_string()),
+    }
+  }
+
+  pub fn invalid(field: &str, message: ValidationMessage) -> Error {
+    Error::Validation {
+      field: field.to_string(),
+      message,
+    }
+  }
+}
+
+impl Error {
+  /* In the language the client asks for, and in spanish when it doesn't ask for one we have.
+   * Messages without a translation are sent in english, with a localized prefix. */
+  fn localized(&self, request: &Request<'_>) -> String {
+    let locale = request.headers().get_one("Accept-Language")
+      .and_then(Locale::from_accept_language)
+      .unwrap_or_default();
+
+    match self {
+      Error::Validation { field, message } => locale.invalid_field(field, &message.in_locale(locale)),
+      Error::ValidationError(errors) => {
+        let mut fields: Vec<_> = errors.field_errors().into_iter().collect();
+        fields.sort_by_key(|(field, _)| *field);
+        fields.into_iter()
+          .flat_map(|(field, errors)| errors.iter().map(move |e| (field, e)))
+          .map(|(field, e)| locale.invalid_field(field, &ValidationMessage::from_rule(&e.code).in_locale(locale)))
+          .collect::<Vec<_>>()
+          .join(". ")
+      },
+      _ => self.to_string(),
     }
   }
 }
@@ -76,7 +125,7 @@ impl<'r> Responder<'r, 'static> for Error {
     let response = match self {
       Error::ValidationError(_) | Error::Validation { .. } => (
         Status::UnprocessableEntity,
-        Json(json![{"error": self.to_string()}]),
+        Json(json![{"error": self.localized(request)}]),
       ),
       Error::DatabaseError(sqlx::Error::RowNotFound) => {
         (Status::NotFound, Json(json![{ "error": "Not found" }]))
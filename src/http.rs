@@ -0,0 +1,276 @@
+use crate::error::{Error, Result};
+use reqwest::{Method, StatusCode};
+use serde::{de::DeserializeOwned, Serialize};
+use std::time::Duration;
+
+pub const TIMEOUT: Duration = Duration::from_secs(15);
+const MAX_ATTEMPTS: u32 = 3;
+const PER_PAGE: usize = 100;
+
+/* Every third party API we talk to. Failures are reported as their own Error variant. */
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum Integration {
+  Wordpress,
+  Discord,
+  Sendinblue,
+  Btcpay,
+  Vies,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("{method} {url} failed with status {status:?}: {message}")]
+pub struct HttpError {
+  pub method: Method,
+  pub url: String,
+  pub status: Option<u16>,
+  pub message: String,
+}
+
+impl HttpError {
+  pub fn is_not_found(&self) -> bool {
+    self.status == Some(404)
+  }
+}
+
+impl Integration {
+  pub fn error(self, e: HttpError) -> Error {
+    match self {
+      Integration::Wordpress => Error::Wordpress(e),
+      Integration::Discord => Error::Discord(e),
+      Integration::Sendinblue => Error::Sendinblue(e),
+      Integration::Btcpay => Error::Btcpay(e),
+      Integration::Vies => Error::Vies(e),
+    }
+  }
+}
+
+pub fn build_client() -> Result<reqwest::Client> {
+  reqwest::Client::builder()
+    .timeout(TIMEOUT)
+    .build()
+    .map_err(|e| Error::validation("http_client", &e.to_string()))
+}
+
+pub struct ApiResponse {
+  pub status: StatusCode,
+  pub body: String,
+  /* From the X-WP-TotalPages header WordPress sends on listings. */
+  pub total_pages: Option<usize>,
+}
+
+enum Body {
+  Json(serde_json::Value),
+  Form(serde_json::Value),
+}
+
+impl ApiResponse {
+  pub fn json<T: DeserializeOwned>(&self) -> Result<T> {
+    Ok(serde_json::from_str(&self.body)?)
+  }
+}
+
+/* A base url and the headers to authenticate with it. Cheap to build, the connection pool is shared.
+ * Only idempotent requests are retried, and only on timeouts, rate limits and server errors. */
+#[derive(Clone)]
+pub struct ApiClient {
+  http: reqwest::Client,
+  integration: Integration,
+  base_url: String,
+  headers: Vec<(&'static str, String)>,
+}
+
+impl ApiClient {
+  pub fn new(http: &reqwest::Client, integration: Integration, base_url: &str) -> ApiClient {
+    ApiClient{
+      http: http.clone(),
+      integration,
+      base_url: base_url.trim_end_matches('/').to_string(),
+      headers: vec![],
+    }
+  }
+
+  pub fn header(mut self, name: &'static str, value: String) -> ApiClient {
+    self.headers.push((name, value));
+    self
+  }
+
+  pub fn url(&self, path: &str) -> String {
+    format!("{}/{}", self.base_url, path.trim_start_matches('/'))
+  }
+
+  pub async fn get(&self, path: &str) -> Result<ApiResponse> {
+    self.send(Method::GET, self.url(path), None).await
+  }
+
+  /* Unlike the path, parameters are escaped, so they can carry user input like an email address. */
+  pub async fn get_with_params(&self, path: &str, params: &[(&str, &str)]) -> Result<ApiResponse> {
+    let mut url = reqwest::Url::parse(&self.url(path)).map_err(|e| Error::validation("url", &e.to_string()))?;
+    url.query_pairs_mut().extend_pairs(params);
+    self.send(Method::GET, url.to_string(), None).await
+  }
+
+  /* Fetches every page of a WordPress listing. Without an X-WP-TotalPages header, a short page is the last one. */
+  pub async fn get_all_pages<T: DeserializeOwned>(&self, path: &str, params: &[(&str, &str)]) -> Result<Vec<T>> {
+    let per_page = PER_PAGE.to_string();
+    let mut all = vec![];
+    let mut page = 1;
+
+    loop {
+      let page_param = page.to_string();
+      let mut paged = params.to_vec();
+      paged.push(("per_page", &per_page));
+      paged.push(("page", &page_param));
+
+      let response = self.get_with_params(path, &paged).await?;
+      let items: Vec<T> = response.json()?;
+      let last = items.is_empty() || match response.total_pages {
+        Some(total) => page >= total,
+        None => items.len() < PER_PAGE,
+      };
+      all.extend(items);
+
+      if last {
+        return Ok(all);
+      }
+      page += 1;
+    }
+  }
+
+  pub async fn post<B: Serialize>(&self, path: &str, body: &B) -> Result<ApiResponse> {
+    self.send(Method::POST, self.url(path), Some(Body::Json(serde_json::to_value(body)?))).await
+  }
+
+  /* For the few endpoints that only take what an html form would send. */
+  pub async fn post_form<B: Serialize>(&self, path: &str, body: &B) -> Result<ApiResponse> {
+    self.send(Method::POST, self.url(path), Some(Body::Form(serde_json::to_value(body)?))).await
+  }
+
+  pub async fn put<B: Serialize>(&self, path: &str, body: &B) -> Result<ApiResponse> {
+    self.send(Method::PUT, self.url(path), Some(Body::Json(serde_json::to_value(body)?))).await
+  }
+
+  pub async fn delete<B: Serialize>(&self, path: &str, body: &B) -> Result<ApiResponse> {
+    self.send(Method::DELETE, self.url(path), Some(Body::Json(serde_json::to_value(body)?))).await
+  }
+
+  async fn send(&self, method: Method, url: String, body: Option<Body>) -> Result<ApiResponse> {
+    let retries = if method == Method::POST { 1 } else { MAX_ATTEMPTS };
+    let mut attempt = 0;
+
+    loop {
+      attempt += 1;
+      let mut request = self.http.request(method.clone(), &url);
+      for (name, value) in self.headers.iter() {
+        request = request.header(*name, value);
+      }
+      request = match body {
+        Some(Body::Json(ref json)) => request.json(json),
+        Some(Body::Form(ref form)) => request.form(form),
+        None => request,
+      };
+
+      let failure = match request.send().await {
+        Ok(response) => {
+          let status = response.status();
+          let total_pages = response.headers().get("x-wp-totalpages")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok());
+          let text = response.text().await.unwrap_or_default();
+          if status.is_success() {
+            return Ok(ApiResponse{ status, body: text, total_pages });
+          }
+          let retryable = status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error();
+          (HttpError{ method: method.clone(), url: url.clone(), status: Some(status.as_u16()), message: text }, retryable)
+        },
+        Err(e) => {
+          let retryable = e.is_timeout() || e.is_connect();
+          (HttpError{ method: method.clone(), url: url.clone(), status: None, message: e.to_string() }, retryable)
+        }
+      };
+
+      match failure {
+        (_, true) if attempt < retries => {
+          tokio::time::sleep(Duration::from_millis(250 * 2u64.pow(attempt))).await;
+        },
+        (e, _) => return Err(self.integration.error(e)),
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn joins_base_url_and_path() {
+    let client = ApiClient::new(&reqwest::Client::new(), Integration::Wordpress, "https://example.com/wp-json/");
+    assert_eq!(client.url("/ldlms/v2/users/1/groups"), "https://example.com/wp-json/ldlms/v2/users/1/groups");
+    assert_eq!(client.url("wp/v2/users/"), "https://example.com/wp-json/wp/v2/users/");
+  }
+
+  #[tokio::test]
+  async fn retries_idempotent_requests_only() {
+    let failing = mockito::mock("GET", "/flaky").with_status(503).expect(3).create();
+    let posted = mockito::mock("POST", "/flaky").with_status(503).expect(1).create();
+
+    let client = ApiClient::new(&build_client().unwrap(), Integration::Btcpay, &mockito::server_url());
+
+    let get_error = client.get("/flaky").await.err().unwrap();
+    assert!(matches!(get_error, Error::Btcpay(HttpError{ status: Some(503), .. })));
+
+    let post_error = client.post("/flaky", &serde_json::json!({})).await.err().unwrap();
+    assert!(matches!(post_error, Error::Btcpay(_)));
+
+    failing.assert();
+    posted.assert();
+  }
+
+  #[tokio::test]
+  async fn posts_forms_urlencoded() {
+    let form = mockito::mock("POST", "/wp-login.php?action=lostpassword")
+      .match_header("content-type", "application/x-www-form-urlencoded")
+      .match_body("user_login=yo%2Btesting%40nubis.im")
+      .with_status(200)
+      .create();
+
+    let client = ApiClient::new(&build_client().unwrap(), Integration::Wordpress, &mockito::server_url());
+    client.post_form("/wp-login.php?action=lostpassword", &serde_json::json!({ "user_login": "yo+testing@nubis.im" })).await.unwrap();
+
+    form.assert();
+  }
+
+  #[tokio::test]
+  async fn escapes_query_params() {
+    let search = mockito::mock("GET", "/wp/v2/users?context=edit&search=yo%2Btesting%40nubis.im")
+      .with_status(200)
+      .with_body("[]")
+      .create();
+
+    let client = ApiClient::new(&build_client().unwrap(), Integration::Wordpress, &mockito::server_url());
+    client.get_with_params("/wp/v2/users", &[("context", "edit"), ("search", "yo+testing@nubis.im")]).await.unwrap();
+
+    search.assert();
+  }
+
+  #[tokio::test]
+  async fn follows_wordpress_pages() {
+    let first = mockito::mock("GET", "/pages?context=edit&per_page=100&page=1")
+      .with_status(200)
+      .with_header("X-WP-TotalPages", "2")
+      .with_body(serde_json::to_string(&vec![1; 100]).unwrap())
+      .create();
+    let second = mockito::mock("GET", "/pages?context=edit&per_page=100&page=2")
+      .with_status(200)
+      .with_header("X-WP-TotalPages", "2")
+      .with_body("[1, 2]")
+      .create();
+
+    let client = ApiClient::new(&build_client().unwrap(), Integration::Wordpress, &mockito::server_url());
+    let items: Vec<i32> = client.get_all_pages("/pages", &[("context", "edit")]).await.unwrap();
+    assert_eq!(items.len(), 102);
+
+    first.assert();
+    second.assert();
+  }
+}
@@ -0,0 +1,60 @@
+use crate::{error::Result, models::{Site, ReconciliationReport}, templating};
+use std::{future::Future, path::Path, time::Duration};
+use rocket::{info, warn};
+
+/* Background work that runs for as long as the server is up.
+ * Every job can also be triggered manually from its admin endpoint, tests turn them all off. */
+pub fn spawn_all(site: Site) {
+  if !site.settings.jobs_enabled {
+    return;
+  }
+
+  spawn_every(site.clone(), "waitlist", Duration::from_secs(10 * 60), |site| async move {
+    site.waitlist_entry().process().await
+  });
+  spawn_every(site.clone(), "dunning", Duration::from_secs(60 * 60), |site| async move {
+    site.invoice().process_dunning().await
+  });
+  spawn_every(site.clone(), "payments reconciliation", Duration::from_secs(24 * 60 * 60), |site| async move {
+    let since = chrono::Utc::now() - chrono::Duration::days(3);
+    let report = ReconciliationReport::build(&site, since).await?;
+    if !report.discrepancies.is_empty() {
+      warn!("Payments reconciliation found discrepancies: {}", serde_json::to_string(&report.discrepancies)?);
+    }
+    Ok(())
+  });
+  spawn_every(site.clone(), "access", Duration::from_secs(24 * 60 * 60), |site| async move {
+    site.student().reconcile_access(None, false).await?;
+    Ok(())
+  });
+  spawn_every(site.clone(), "course progress", Duration::from_secs(6 * 60 * 60), |site| async move {
+    site.student().sync_course_progress(None).await
+  });
+  if site.settings.templates_dir.is_some() {
+    spawn_every(site, "templates", Duration::from_secs(5), |site| async move {
+      if let Some(ref dir) = site.settings.templates_dir {
+        if templating::reload_if_changed(Path::new(dir))? {
+          info!("Reloaded templates from {}", dir);
+        }
+      }
+      Ok(())
+    });
+  }
+}
+
+fn spawn_every<F, Fut>(site: Site, name: &'static str, period: Duration, job: F)
+where
+  F: Fn(Site) -> Fut + Send + 'static,
+  Fut: Future<Output = Result<()>> + Send,
+{
+  /* The first run waits for a whole period, so a deploy doesn't fire every job at once. */
+  tokio::spawn(async move {
+    let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    loop {
+      interval.tick().await;
+      if let Err(e) = job(site.clone()).await {
+        warn!("Background job {} failed: {:?}", name, e);
+      }
+    }
+  });
+}
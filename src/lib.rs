@@ -1,20 +1,43 @@
 #[macro_use]
 extern crate rocket;
 
-use tera::Tera;
+use std::sync::RwLock;
+
+/* Compiled into the binary. A templates_dir in the settings can override any of them at runtime. */
+pub const EMBEDDED_TEMPLATES: &[(&str, &str)] = &[
+  ("emails/welcome", include_str!("templates/emails/welcome.html.tera")),
+  ("emails/payment_link", include_str!("templates/emails/payment_link.html.tera")),
+  ("emails/waitlist_invitation", include_str!("templates/emails/waitlist_invitation.html.tera")),
+  ("emails/access_suspended", include_str!("templates/emails/access_suspended.html.tera")),
+  ("emails/payment_receipt", include_str!("templates/emails/payment_receipt.html.tera")),
+  ("emails/degree_paid", include_str!("templates/emails/degree_paid.html.tera")),
+  ("en/emails/welcome", include_str!("templates/en/emails/welcome.html.tera")),
+  ("en/emails/payment_link", include_str!("templates/en/emails/payment_link.html.tera")),
+  ("en/emails/payment_receipt", include_str!("templates/en/emails/payment_receipt.html.tera")),
+  ("en/emails/degree_paid", include_str!("templates/en/emails/degree_paid.html.tera")),
+  ("en/emails/waitlist_invitation", include_str!("templates/en/emails/waitlist_invitation.html.tera")),
+  ("en/emails/access_suspended", include_str!("templates/en/emails/access_suspended.html.tera")),
+  ("pt/emails/welcome", include_str!("templates/pt/emails/welcome.html.tera")),
+  ("pt/emails/payment_link", include_str!("templates/pt/emails/payment_link.html.tera")),
+  ("pt/emails/payment_receipt", include_str!("templates/pt/emails/payment_receipt.html.tera")),
+  ("pt/emails/degree_paid", include_str!("templates/pt/emails/degree_paid.html.tera")),
+  ("pt/emails/waitlist_invitation", include_str!("templates/pt/emails/waitlist_invitation.html.tera")),
+  ("pt/emails/access_suspended", include_str!("templates/pt/emails/access_suspended.html.tera")),
+  ("pdfs/fiscal_invoice", include_str!("templates/pdfs/fiscal_invoice.txt.tera")),
+  ("pdfs/receipt", include_str!("templates/pdfs/receipt.txt.tera")),
+];
+
 lazy_static::lazy_static! {
-  pub static ref TEMPLATES: Tera = {
-    let mut tera = Tera::default();
-    tera.add_raw_templates([
-      ("emails/welcome", include_str!("templates/emails/welcome.html.tera")),
-      ("emails/payment_link", include_str!("templates/emails/payment_link.html.tera"))
-    ]).expect("No static");
-    tera
-  };
+  pub static ref TEMPLATES: RwLock<tera::Tera> = RwLock::new(templating::embedded());
 }
 
 pub mod models;
 pub mod error;
 pub mod controllers; 
 pub use controllers::*;
+pub mod jobs;
+pub mod pdf;
+pub mod http;
+pub mod email;
+pub mod templating;
 
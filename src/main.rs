@@ -2,8 +2,9 @@ use rocket::{routes, fairing::AdHoc, http::Method};
 use rocket_cors::{AllowedHeaders, AllowedOrigins};
 
 use daoe_api::{
-  models::SiteSettings,
-  controllers::*
+  models::{Site, SiteSettings},
+  controllers::*,
+  jobs,
 };
 
 #[cfg(test)]
@@ -28,15 +29,75 @@ pub fn server() -> rocket::Rocket<rocket::Build> {
       payments::handle_stripe_events,
       payments::handle_btcpay_webhooks,
       payments::from_invoice,
+      payments::from_organization_invoice,
+      payments::refund,
+      payments::dunning,
+      payments::reconcile,
     ])
     .mount("/students/", routes![
       students::discord_success,
+      students::rejoin_discord,
       students::by_wordpress_id,
       students::create,
       students::create_guest,
+      students::enroll,
+      students::reconcile_access,
+      students::sync_course_progress,
+      students::regenerate_invoices,
+      students::fiscal_invoices,
+      students::fiscal_invoice,
+      students::fiscal_invoice_pdf,
+      students::receipt_pdf,
       students::show,
       students::index,
     ])
+    .mount("/cohorts/", routes![
+      cohorts::index,
+      cohorts::open,
+      cohorts::create,
+      cohorts::show,
+      cohorts::set_enrollment,
+      cohorts::move_student,
+    ])
+    .mount("/waitlist/", routes![
+      waitlist::index,
+      waitlist::process,
+      waitlist::show_invitation,
+      waitlist::accept_invitation,
+    ])
+    .mount("/scholarships/", routes![
+      scholarships::index,
+      scholarships::create,
+      scholarships::approve,
+      scholarships::reject,
+    ])
+    .mount("/organizations/", routes![
+      organizations::index,
+      organizations::create,
+      organizations::show,
+      organizations::sponsor,
+      organizations::stop_sponsoring,
+      organizations::invoice,
+    ])
+    .mount("/exchange_rates/", routes![
+      exchange_rates::index,
+      exchange_rates::create,
+    ])
+    .mount("/accounting/", routes![
+      accounting::export,
+    ])
+    .mount("/reports/", routes![
+      reports::funnel,
+      reports::revenue,
+      reports::receivables,
+    ])
+    .mount("/emails/", routes![
+      emails::sendinblue_webhook,
+      emails::resend,
+      emails::preview,
+      emails::templates,
+      emails::reload_templates,
+    ])
     .mount("/", rocket_cors::catch_all_options_routes())
     .attach(cors.clone())
     .manage(cors)
@@ -48,6 +109,11 @@ pub fn server() -> rocket::Rocket<rocket::Build> {
 
       rocket.manage(site)
     }))
+    .attach(AdHoc::on_liftoff("Background jobs", |rocket| Box::pin(async move {
+      if let Some(site) = rocket.state::<Site>() {
+        jobs::spawn_all(site.clone());
+      }
+    })))
 }
 
 #[rocket::launch]
@@ -88,6 +154,13 @@ mod test {
       rematch("btcpay.constata.eu")
     );
 
+    let emails = sent_emails().await;
+    assert_eq!(emails.len(), 1);
+    assert_eq!(emails[0].to_email, "yo+testing@nubis.im");
+    assert_eq!(emails[0].subject, "Acerca de tu pago a DAO Education");
+    assert_eq!(emails[0].from, site.settings.email.identity(Sender::Billing).clone());
+    assert_that!(&emails[0].html, rematch("btcpay.constata.eu"));
+
     client.post::<serde_json::Value, _>("/payments/from_invoice/?invoice_id=1&admin_key=adminusertoken", "").await;
 
     let fetch_user_billing = || async {
@@ -100,6 +173,11 @@ mod test {
     assert!(state.get("unpaid_charges").unwrap().as_array().unwrap().is_empty());
     assert_eq!(state.get("balance").unwrap().as_str().unwrap(), "0");
 
+    let receipt = sent_emails().await.pop().unwrap();
+    assert_eq!(receipt.subject, "Recibimos tu pago a DAO Education");
+    assert_that!(&receipt.html, rematch("BTCPay"));
+    assert_that!(&receipt.html, rematch("/receipt\\?student_id=1&payment_id=1"));
+
     let student = site.student().find(&1).await.unwrap();
     BillingSummary::new(student.clone()).await.unwrap();
 
@@ -108,4 +186,616 @@ mod test {
     assert!(state.get("unpaid_charges").unwrap().as_array().unwrap().is_empty());
     assert_eq!(state.get("balance").unwrap().as_str().unwrap(), "0");
   }
+
+  test!{ enrolls_in_several_programs(client, site)
+    client.post::<serde_json::Value, _>("/students/",
+      serde_json::json![{
+        "email": "yo+testing@nubis.im",
+        "full_name": "Testing Testinger",
+        "payment_method": "BtcPay",
+        "program": "zero_to_hero",
+      }].to_string()
+    ).await;
+
+    let res = client.post::<serde_json::Value, _>("/students/1/enroll?program=academy&admin_key=adminusertoken", "").await;
+    let state = res.get("billing").unwrap().clone();
+    assert_eq!(state.get("subscriptions").unwrap().as_array().unwrap().len(), 2);
+    assert_eq!(state.get("unpaid_charges").unwrap().as_array().unwrap().len(), 2);
+
+    let student = site.student().find(&1).await.unwrap();
+    assert_eq!(student.subscription(Program::Academy).await.unwrap().attrs.plan_code, PlanCode::Latam);
+
+    let again = client.post::<serde_json::Value, _>("/students/1/enroll?program=academy&admin_key=adminusertoken", "").await;
+    assert_that!(&again.get("error").unwrap().as_str().unwrap().to_string(), rematch("ya estás inscripto"));
+  }
+
+  test!{ waitlists_signups_once_the_cohort_is_full(client, _site)
+    client.post::<serde_json::Value, _>("/cohorts/?admin_key=adminusertoken",
+      serde_json::json![{
+        "program": "zero_to_hero",
+        "name": "Primera camada",
+        "starts_at": "2030-01-01T00:00:00Z",
+        "ends_at": "2030-06-01T00:00:00Z",
+        "capacity": 1,
+        "enrollment_open": true,
+      }].to_string()
+    ).await;
+
+    let signup = |email: &'static str| client.post::<serde_json::Value, _>("/students/",
+      serde_json::json![{
+        "email": email,
+        "full_name": "Testing Testinger",
+        "payment_method": "BtcPay",
+        "cohort_id": 1,
+      }].to_string()
+    );
+
+    let (first, second) = tokio::join!(signup("yo+first@nubis.im"), signup("yo+second@nubis.im"));
+    let outcomes = [&first, &second];
+    assert_eq!(outcomes.iter().filter(|o| o.get("billing").is_some()).count(), 1);
+    assert_eq!(outcomes.iter().filter(|o| o.get("waitlist_entry").is_some()).count(), 1);
+
+    let cohort = client.get::<serde_json::Value, _>("/cohorts/1?admin_key=adminusertoken").await;
+    assert_eq!(cohort.get("seats_left").unwrap().as_i64().unwrap(), 0);
+    assert_eq!(cohort.get("subscriptions").unwrap().as_array().unwrap().len(), 1);
+    assert_eq!(cohort.get("waitlist").unwrap().as_array().unwrap().len(), 1);
+
+    let third = signup("yo+third@nubis.im").await;
+    assert!(third.get("waitlist_entry").is_some());
+
+    let without_cohort = client.post::<serde_json::Value, _>("/students/",
+      serde_json::json![{
+        "email": "yo+fourth@nubis.im",
+        "full_name": "Testing Testinger",
+        "payment_method": "BtcPay",
+      }].to_string()
+    ).await;
+    assert!(without_cohort.get("waitlist_entry").is_some());
+  }
+
+  test!{ invites_accepts_and_expires_waitlist_entries(client, site)
+    client.post::<serde_json::Value, _>("/cohorts/?admin_key=adminusertoken",
+      serde_json::json![{
+        "program": "zero_to_hero",
+        "name": "Primera camada",
+        "starts_at": "2030-01-01T00:00:00Z",
+        "ends_at": "2030-06-01T00:00:00Z",
+        "capacity": 1,
+        "enrollment_open": true,
+      }].to_string()
+    ).await;
+
+    let signup = |email: &'static str| client.post::<serde_json::Value, _>("/students/",
+      serde_json::json![{
+        "email": email,
+        "full_name": "Testing Testinger",
+        "payment_method": "BtcPay",
+        "cohort_id": 1,
+      }].to_string()
+    );
+    signup("yo+first@nubis.im").await;
+    signup("yo+second@nubis.im").await;
+    signup("yo+third@nubis.im").await;
+
+    let free_a_seat = || sqlx::query("UPDATE cohorts SET capacity = capacity + 1").execute(&site.db);
+    free_a_seat().await.unwrap();
+    client.post::<serde_json::Value, _>("/waitlist/process?admin_key=adminusertoken", "").await;
+
+    let invited = site.waitlist_entry().find(&1).await.unwrap();
+    assert_eq!(invited.attrs.status, WaitlistStatus::Invited);
+    assert_eq!(site.waitlist_entry().find(&2).await.unwrap().attrs.status, WaitlistStatus::Waiting);
+
+    let token = invited.attrs.invitation_token.clone().unwrap();
+    let link = invited.invitation_link().unwrap();
+    assert_that!(&token, rematch("\\+"));
+    assert_that!(&link, rematch("waitlist-invitation\\?token=.*%2B"));
+
+    let invitation = sent_emails().await.pop().unwrap();
+    assert_eq!(invitation.to_email, "yo+second@nubis.im");
+    assert_that!(&invitation.html, rematch(&regex::escape(&link)));
+
+    let accept_path = format!("/waitlist/invitations/{}/accept", token.replace('+', "%2B"));
+    let accepted = client.post::<serde_json::Value, _>(&accept_path, "").await;
+    assert!(accepted.get("billing").is_some());
+    assert_eq!(site.waitlist_entry().find(&1).await.unwrap().attrs.status, WaitlistStatus::Accepted);
+
+    let again = client.post::<serde_json::Value, _>(&accept_path, "").await;
+    assert_that!(&again.get("error").unwrap().as_str().unwrap().to_string(), rematch("ya no es válida"));
+
+    free_a_seat().await.unwrap();
+    client.post::<serde_json::Value, _>("/waitlist/process?admin_key=adminusertoken", "").await;
+    let expiring = site.waitlist_entry().find(&2).await.unwrap();
+    assert_eq!(expiring.attrs.status, WaitlistStatus::Invited);
+
+    sqlx::query("UPDATE waitlist_entries SET invitation_expires_at = now() - interval '1 hour' WHERE id = 2")
+      .execute(&site.db).await.unwrap();
+    client.post::<serde_json::Value, _>("/waitlist/process?admin_key=adminusertoken", "").await;
+    assert_eq!(site.waitlist_entry().find(&2).await.unwrap().attrs.status, WaitlistStatus::Expired);
+
+    let late = client.post::<serde_json::Value, _>(
+      &format!("/waitlist/invitations/{}/accept", expiring.attrs.invitation_token.unwrap().replace('+', "%2B")),
+      "",
+    ).await;
+    assert_that!(&late.get("error").unwrap().as_str().unwrap().to_string(), rematch("ya no es válida"));
+  }
+
+  test!{ takes_fixed_scholarships_once_per_subscription(client, site)
+    client.post::<serde_json::Value, _>("/students/",
+      serde_json::json![{
+        "email": "yo+testing@nubis.im",
+        "full_name": "Testing Testinger",
+        "payment_method": "BtcPay",
+      }].to_string()
+    ).await;
+
+    client.post::<serde_json::Value, _>("/scholarships/?admin_key=adminusertoken",
+      serde_json::json![{
+        "student_id": 1,
+        "program": "zero_to_hero",
+        "request": { "kind": "fixed", "value": "40", "reason": "Early bird" },
+      }].to_string()
+    ).await;
+    client.post::<serde_json::Value, _>("/scholarships/1/approve?admin_key=adminusertoken", "").await;
+
+    let student = site.student().find(&1).await.unwrap();
+    let subscription = student.subscription(Program::ZeroToHero).await.unwrap();
+    assert_eq!(subscription.attrs.price, subscription.attrs.list_price - Decimal::new(40, 0));
+
+    let invoices = site.invoice().select().student_id_eq(&1).all().await.unwrap();
+    assert_eq!(invoices.iter().filter(|i| !i.attrs.expired).count(), 1);
+
+    let degree = subscription.award_degree().await.unwrap();
+    assert_eq!(degree.attrs.scholarship_id, Some(1));
+    assert_eq!(degree.attrs.price, degree.attrs.list_price);
+  }
+
+  test!{ invoices_and_pays_sponsored_students_once(client, site)
+    client.post::<serde_json::Value, _>("/students/",
+      serde_json::json![{
+        "email": "yo+testing@nubis.im",
+        "full_name": "Testing Testinger",
+        "payment_method": "BtcPay",
+      }].to_string()
+    ).await;
+
+    client.post::<serde_json::Value, _>("/organizations/?admin_key=adminusertoken",
+      serde_json::json![{
+        "name": "Constata",
+        "billing_contact_name": "Billing Contact",
+        "billing_email": "billing@constata.eu",
+        "country": "AR",
+        "payment_method": "BtcPay",
+      }].to_string()
+    ).await;
+    client.post::<serde_json::Value, _>("/organizations/1/sponsor/1?admin_key=adminusertoken", "").await;
+
+    let student_invoices = site.invoice().select().student_id_eq(&1).all().await.unwrap();
+    assert!(student_invoices.iter().all(|i| i.attrs.expired));
+
+    let invoices = client.post::<serde_json::Value, _>("/organizations/1/invoice?admin_key=adminusertoken", "").await;
+    let invoice = invoices.get(0).unwrap();
+    assert_eq!(invoice.get("amount").unwrap().as_str().unwrap(), "100");
+    assert_eq!(invoice.get("currency").unwrap().as_str().unwrap(), "eur");
+
+    let organization_invoice = site.organization_invoice().find(&1).await.unwrap();
+    let (first, second) = tokio::join!(organization_invoice.make_payment(None), organization_invoice.make_payment(None));
+    assert_eq!(first.unwrap().len() + second.unwrap().len(), 1);
+    assert!(organization_invoice.make_payment(None).await.unwrap().is_empty());
+
+    let payments = site.payment().select().student_id_eq(&1).all().await.unwrap();
+    assert_eq!(payments.len(), 1);
+    assert_eq!(payments[0].attrs.organization_invoice_id, Some(1));
+    assert!(site.organization_invoice().find(&1).await.unwrap().attrs.paid);
+
+    let receipts: Vec<_> = sent_emails().await.into_iter()
+      .filter(|e| e.subject == "Recibimos tu pago a DAO Education")
+      .collect();
+    assert_eq!(receipts.len(), 1);
+    assert_eq!(receipts[0].to_email, "billing@constata.eu");
+    assert_that!(&receipts[0].html, rematch("Testing Testinger"));
+
+    let state = client.get::<serde_json::Value, _>("/students/1?admin_key=adminusertoken").await;
+    let billing = state.get("billing").unwrap();
+    assert_eq!(billing.get("balance").unwrap().as_str().unwrap(), "0");
+    assert!(billing.get("unpaid_charges").unwrap().as_array().unwrap().is_empty());
+  }
+
+  test!{ releases_sponsored_students_from_open_organization_invoices(client, site)
+    client.post::<serde_json::Value, _>("/students/",
+      serde_json::json![{
+        "email": "yo+testing@nubis.im",
+        "full_name": "Testing Testinger",
+        "payment_method": "BtcPay",
+      }].to_string()
+    ).await;
+
+    for name in &["Constata", "Other"] {
+      client.post::<serde_json::Value, _>("/organizations/?admin_key=adminusertoken",
+        serde_json::json![{
+          "name": name,
+          "billing_contact_name": "Billing Contact",
+          "billing_email": "billing@constata.eu",
+          "country": "AR",
+          "payment_method": "BtcPay",
+        }].to_string()
+      ).await;
+    }
+    client.post::<serde_json::Value, _>("/organizations/1/sponsor/1?admin_key=adminusertoken", "").await;
+
+    let moved = client.post::<serde_json::Value, _>("/organizations/2/sponsor/1?admin_key=adminusertoken", "").await;
+    assert_that!(&moved.get("error").unwrap().as_str().unwrap().to_string(), rematch("another organization"));
+    assert_eq!(site.student().find(&1).await.unwrap().attrs.organization_id, Some(1));
+
+    client.post::<serde_json::Value, _>("/organizations/1/invoice?admin_key=adminusertoken", "").await;
+    client.post::<serde_json::Value, _>("/organizations/1/stop_sponsoring/1?admin_key=adminusertoken", "").await;
+
+    assert!(site.organization_invoice().find(&1).await.unwrap().attrs.expired);
+    assert!(site.organization_invoice().select().expired_eq(&false).all().await.unwrap().is_empty());
+
+    let open = site.invoice().select().student_id_eq(&1).expired_eq(&false).all().await.unwrap();
+    assert_eq!(open.len(), 1);
+    assert_eq!(open[0].attrs.amount, Decimal::new(100, 0));
+  }
+
+  test!{ records_an_invoice_payment_once(client, site)
+    client.post::<serde_json::Value, _>("/students/",
+      serde_json::json![{
+        "email": "yo+testing@nubis.im",
+        "full_name": "Testing Testinger",
+        "payment_method": "BtcPay",
+      }].to_string()
+    ).await;
+
+    let invoice = site.invoice().find(&1).await.unwrap();
+    let (webhook, reconciliation) = tokio::join!(invoice.make_payment(None), invoice.make_payment(None));
+    assert_eq!(webhook.unwrap().iter().chain(reconciliation.unwrap().iter()).count(), 1);
+    assert!(site.payment().from_invoice(1).await.unwrap().is_none());
+
+    let payments = site.payment().select().student_id_eq(&1).all().await.unwrap();
+    assert_eq!(payments.len(), 1);
+    assert_eq!(site.invoice().find(&1).await.unwrap().attrs.payment_id, Some(payments[0].attrs.id));
+  }
+
+  test!{ keeps_a_balance_per_currency(client, site)
+    client.post::<serde_json::Value, _>("/students/",
+      serde_json::json![{
+        "email": "yo+testing@nubis.im",
+        "full_name": "Testing Testinger",
+        "payment_method": "BtcPay",
+        "program": "zero_to_hero",
+      }].to_string()
+    ).await;
+
+    sqlx::query("UPDATE students SET country = 'BR' WHERE id = 1").execute(&site.db).await.unwrap();
+    let res = client.post::<serde_json::Value, _>("/students/1/enroll?program=academy&admin_key=adminusertoken", "").await;
+
+    let billing = res.get("billing").unwrap();
+    let balance = |billing: &serde_json::Value, currency: &str| billing.get("balances").unwrap().as_array().unwrap().iter()
+      .find(|b| b.get("currency").unwrap().as_str().unwrap() == currency)
+      .map(|b| b.get("balance").unwrap().as_str().unwrap().to_string());
+    assert_eq!(balance(billing, "eur").unwrap(), "-100");
+    assert_eq!(balance(billing, "brl").unwrap(), "-1000");
+    assert_eq!(billing.get("invoices").unwrap().as_array().unwrap().len(), 2);
+
+    let eur_invoice = site.invoice().select().student_id_eq(&1).currency_eq(&Currency::Eur).one().await.unwrap();
+    client.post::<serde_json::Value, _>(
+      &format!("/payments/from_invoice/?invoice_id={}&admin_key=adminusertoken", eur_invoice.attrs.id), ""
+    ).await;
+
+    let state = client.get::<serde_json::Value, _>("/students/1?admin_key=adminusertoken").await;
+    let billing = state.get("billing").unwrap();
+    assert_eq!(balance(billing, "eur").unwrap(), "0");
+    assert_eq!(balance(billing, "brl").unwrap(), "-1000");
+    assert_eq!(billing.get("unpaid_charges").unwrap().as_array().unwrap().len(), 1);
+
+    let fiscal_invoice = site.fiscal_invoice().select().student_id_eq(&Some(1)).one().await.unwrap();
+    assert_eq!(fiscal_invoice.attrs.currency, Currency::Eur);
+    assert_eq!(fiscal_invoice.attrs.total, Decimal::new(100, 0));
+  }
+
+  test!{ refunds_reprice_the_charges_they_come_from(client, site)
+    client.post::<serde_json::Value, _>("/students/",
+      serde_json::json![{
+        "email": "yo+testing@nubis.im",
+        "full_name": "Testing Testinger",
+        "payment_method": "BtcPay",
+      }].to_string()
+    ).await;
+    client.post::<serde_json::Value, _>("/payments/from_invoice/?invoice_id=1&admin_key=adminusertoken", "").await;
+
+    let refund = serde_json::json![{ "amount": "30", "reason": "Baja parcial" }].to_string();
+    let refunded = client.post::<serde_json::Value, _>("/payments/1/refund?admin_key=adminusertoken", refund.clone()).await;
+    assert_eq!(refunded.get("amount").unwrap().as_str().unwrap(), "30");
+
+    let state = client.get::<serde_json::Value, _>("/students/1?admin_key=adminusertoken").await;
+    let billing = state.get("billing").unwrap();
+    assert_eq!(billing.get("balance").unwrap().as_str().unwrap(), "0");
+    assert!(billing.get("unpaid_charges").unwrap().as_array().unwrap().is_empty());
+
+    let subscription = site.student().find(&1).await.unwrap().subscription(Program::ZeroToHero).await.unwrap();
+    assert!(subscription.attrs.paid);
+    assert_eq!(subscription.attrs.price, Decimal::new(70, 0));
+
+    let too_much = serde_json::json![{ "amount": "80", "reason": "Baja total" }].to_string();
+    let rejected = client.post::<serde_json::Value, _>("/payments/1/refund?admin_key=adminusertoken", too_much).await;
+    assert_that!(&rejected.get("error").unwrap().as_str().unwrap().to_string(), rematch("como máximo 70"));
+  }
+
+  test!{ reminds_and_regenerates_overdue_invoices(client, site)
+    client.post::<serde_json::Value, _>("/students/",
+      serde_json::json![{
+        "email": "yo+testing@nubis.im",
+        "full_name": "Testing Testinger",
+        "payment_method": "BtcPay",
+      }].to_string()
+    ).await;
+
+    sqlx::query("UPDATE invoices SET created_at = now() - interval '2 days', due_since = now() - interval '3 days'")
+      .execute(&site.db).await.unwrap();
+    let due_since = site.invoice().find(&1).await.unwrap().attrs.due_since;
+
+    client.post::<serde_json::Value, _>("/payments/dunning?admin_key=adminusertoken", "").await;
+
+    let old = site.invoice().find(&1).await.unwrap();
+    assert!(old.attrs.expired);
+
+    let fresh = site.invoice().find(&2).await.unwrap();
+    assert!(!fresh.attrs.expired);
+    assert_eq!(fresh.attrs.amount, old.attrs.amount);
+    assert_eq!(fresh.attrs.reminder_count, 2);
+    assert_eq!(fresh.attrs.due_since, due_since);
+
+    let emails = sent_emails().await;
+    assert_eq!(emails.len(), 2);
+    assert_eq!(emails[1].subject, "Recordatorio: tu pago a DAO Education está pendiente");
+    assert!(emails[1].html.contains(&fresh.attrs.url));
+
+    client.post::<serde_json::Value, _>("/payments/dunning?admin_key=adminusertoken", "").await;
+    assert_eq!(sent_emails().await.len(), 2);
+    assert_eq!(site.invoice().select().expired_eq(&false).all().await.unwrap().len(), 1);
+
+    sqlx::query("UPDATE invoices SET due_since = now() - interval '22 days'")
+      .execute(&site.db).await.unwrap();
+    client.post::<serde_json::Value, _>("/payments/dunning?admin_key=adminusertoken", "").await;
+    assert!(site.student().find(&1).await.unwrap().attrs.delinquent_since.is_some());
+  }
+
+  test!{ regenerates_invoices_with_the_student_token(client, site)
+    client.post::<serde_json::Value, _>("/students/",
+      serde_json::json![{
+        "email": "yo+testing@nubis.im",
+        "full_name": "Testing Testinger",
+        "payment_method": "BtcPay",
+      }].to_string()
+    ).await;
+
+    let student = site.student().find(&1).await.unwrap();
+    let emails = sent_emails().await;
+    assert!(emails[0].html.contains(&student.billing_link()));
+
+    client.post::<serde_json::Value, _>("/students/1/invoices/regenerate?token=wrong", "").await;
+    assert!(!site.invoice().find(&1).await.unwrap().attrs.expired);
+
+    let path = format!("/students/1/invoices/regenerate?token={}", student.access_token());
+    client.post::<serde_json::Value, _>(&path, "").await;
+
+    let old = site.invoice().find(&1).await.unwrap();
+    assert!(old.attrs.expired);
+    assert_eq!(btcpay::get_invoice(&site, &old.attrs.external_id).await.unwrap().status, "Invalid");
+
+    let fresh = site.invoice().find(&2).await.unwrap();
+    assert!(!fresh.attrs.expired);
+    assert_eq!(fresh.attrs.amount, old.attrs.amount);
+
+    let emails = sent_emails().await;
+    assert_eq!(emails.len(), 2);
+    assert!(emails[1].html.contains(&fresh.attrs.url));
+    assert!(emails[1].html.contains(&student.billing_link()));
+
+    /* Voiding twice is harmless, the processor already invalidated the link. */
+    old.void().await.unwrap();
+  }
+
+  test!{ links_discord_and_joins_the_guild(client, site)
+    use daoe_api::error::Error;
+    use mockito::Matcher;
+
+    client.post::<serde_json::Value, _>("/students/",
+      serde_json::json![{
+        "email": "yo+testing@nubis.im",
+        "full_name": "Testing Testinger",
+        "payment_method": "BtcPay",
+      }].to_string()
+    ).await;
+
+    let mut discord = site.clone();
+    discord.settings.discord.api_url = mockito::server_url();
+
+    let token_body = |access: &str, refresh: &str, expires_in: i64| serde_json::json![{
+      "access_token": access,
+      "token_type": "Bearer",
+      "expires_in": expires_in,
+      "refresh_token": refresh,
+      "scope": "identify email guilds.join",
+    }].to_string();
+
+    let exchanged = mockito::mock("POST", "/oauth2/token")
+      .match_body(Matcher::AllOf(vec![
+        Matcher::UrlEncoded("grant_type".into(), "authorization_code".into()),
+        Matcher::UrlEncoded("code".into(), "valid".into()),
+      ]))
+      .with_status(200)
+      .with_body(token_body("first-access", "first-refresh", 0))
+      .create();
+    let used = mockito::mock("POST", "/oauth2/token")
+      .match_body(Matcher::UrlEncoded("code".into(), "used".into()))
+      .with_status(400)
+      .with_body(r#"{"error": "invalid_grant"}"#)
+      .create();
+    let misconfigured = mockito::mock("POST", "/oauth2/token")
+      .match_body(Matcher::UrlEncoded("code".into(), "misconfigured".into()))
+      .with_status(400)
+      .with_body(r#"{"error": "invalid_client"}"#)
+      .create();
+
+    let token = discord.discord_grant().exchange_code("valid").await.unwrap();
+    assert!(matches!(discord.discord_grant().exchange_code("used").await, Err(Error::Validation{ .. })));
+    assert!(matches!(discord.discord_grant().exchange_code("misconfigured").await, Err(Error::Discord(_))));
+    exchanged.assert();
+    used.assert();
+    misconfigured.assert();
+
+    let mut grant = discord.discord_grant().store(1, "80351110224678912", &token).await.unwrap();
+
+    let refreshed = mockito::mock("POST", "/oauth2/token")
+      .match_body(Matcher::AllOf(vec![
+        Matcher::UrlEncoded("grant_type".into(), "refresh_token".into()),
+        Matcher::UrlEncoded("refresh_token".into(), "first-refresh".into()),
+      ]))
+      .with_status(200)
+      .with_body(token_body("second-access", "second-refresh", 604800))
+      .expect(1)
+      .create();
+
+    assert_eq!(grant.access_token().await.unwrap(), "second-access");
+    assert_eq!(grant.access_token().await.unwrap(), "second-access");
+    refreshed.assert();
+    let stored = discord.discord_grant().select().student_id_eq(&1).one().await.unwrap();
+    assert_eq!(stored.refresh_token().unwrap(), "second-refresh");
+    assert!(!stored.attrs.sealed_refresh_token.contains("second-refresh"));
+    assert!(!stored.attrs.sealed_access_token.contains("second-access"));
+    assert!(unseal(&discord, "discord_refresh_token:2", &stored.attrs.sealed_refresh_token).is_err());
+
+    let path = format!("/guilds/{}/members/80351110224678912", discord.settings.discord.guild_id);
+    let outcomes = vec![
+      (201, Some(GuildMembership::Joined)),
+      (204, Some(GuildMembership::AlreadyMember)),
+      (403, None),
+    ];
+    for (status, expected) in outcomes {
+      let joined = mockito::mock("PUT", path.as_str())
+        .match_body(Matcher::Json(serde_json::json![{"access_token": "second-access"}]))
+        .with_status(status)
+        .create();
+
+      match expected {
+        Some(membership) => assert_eq!(grant.join_guild().await.unwrap(), membership),
+        None => assert!(matches!(grant.join_guild().await, Err(Error::Validation{ .. }))),
+      }
+      joined.assert();
+    }
+  }
+
+  test!{ reports_funnel_and_receivables(client, site)
+    client.post::<serde_json::Value, _>("/students/",
+      serde_json::json![{
+        "email": "yo+testing@nubis.im",
+        "full_name": "Testing Testinger",
+        "payment_method": "BtcPay",
+      }].to_string()
+    ).await;
+
+    let today = chrono::Utc::now().format("%Y-%m-%d").to_string();
+    let range = format!("from={}&until={}&period=day&admin_key=adminusertoken", today, today);
+
+    let funnel = client.get::<serde_json::Value, _>(&format!("/reports/funnel?{}", range)).await;
+    let day = funnel.get(0).unwrap();
+    assert_eq!(day.get("new_students").unwrap().as_i64().unwrap(), 1);
+    assert_eq!(day.get("paid_subscriptions").unwrap().as_i64().unwrap(), 0);
+
+    let receivables = client.get::<serde_json::Value, _>("/reports/receivables?admin_key=adminusertoken").await;
+    assert_eq!(receivables.get(0).unwrap().get("outstanding").unwrap().as_str().unwrap(), "100");
+
+    client.post::<serde_json::Value, _>("/payments/from_invoice/?invoice_id=1&admin_key=adminusertoken", "").await;
+    sqlx::query("UPDATE students SET payment_method = 'stripe'").execute(&site.db).await.unwrap();
+
+    let revenue = client.get::<serde_json::Value, _>(&format!("/reports/revenue?{}", range)).await;
+    let row = revenue.get("subscriptions").unwrap().get(0).unwrap();
+    assert_eq!(row.get("plan_code").unwrap().as_str().unwrap(), "latam");
+    assert_eq!(row.get("payment_method").unwrap().as_str().unwrap(), "BtcPay");
+    assert_eq!(row.get("revenue").unwrap().as_str().unwrap(), "100");
+
+    let collected = revenue.get("collections").unwrap().get(0).unwrap();
+    assert_eq!(collected.get("plan_code").unwrap().as_str().unwrap(), "latam");
+    assert_eq!(collected.get("collected").unwrap().as_str().unwrap(), "100");
+
+    let receivables = client.get::<serde_json::Value, _>("/reports/receivables?admin_key=adminusertoken").await;
+    assert!(receivables.as_array().unwrap().is_empty());
+  }
+
+  test!{ logs_emails_and_tracks_delivery(client, site)
+    client.post::<serde_json::Value, _>("/students/",
+      serde_json::json![{
+        "email": "yo+testing@nubis.im",
+        "full_name": "Testing Testinger",
+        "payment_method": "BtcPay",
+      }].to_string()
+    ).await;
+
+    sqlx::query("UPDATE sent_emails SET provider_message_id = '<1@smtp-relay.mailin.fr>'")
+      .execute(&site.db).await.unwrap();
+
+    let event = serde_json::json![{ "event": "delivered", "message-id": "<1@smtp-relay.mailin.fr>" }].to_string();
+    let rejected = client.post::<serde_json::Value, _>("/emails/sendinblue_webhook?secret=wrong", event.clone()).await;
+    assert_that!(&rejected.get("error").unwrap().as_str().unwrap().to_string(), rematch("webhook secret"));
+    client.post::<serde_json::Value, _>(
+      &format!("/emails/sendinblue_webhook?secret={}", site.settings.sendinblue.webhooks_secret),
+      event,
+    ).await;
+
+    let state = client.get::<serde_json::Value, _>("/students/1?admin_key=adminusertoken").await;
+    let logged = state.get("emails").unwrap().get(0).unwrap();
+    assert_eq!(logged.get("template").unwrap().as_str().unwrap(), "emails/payment_link");
+    assert_eq!(logged.get("status").unwrap().as_str().unwrap(), "delivered");
+
+    sqlx::query(r#"UPDATE sent_emails SET context = (context::jsonb || '{"password": "hunter2"}')::text WHERE id = 1"#)
+      .execute(&site.db).await.unwrap();
+
+    let resent = client.post::<serde_json::Value, _>("/emails/1/resend?admin_key=adminusertoken", "").await;
+    assert_eq!(resent.get("status").unwrap().as_str().unwrap(), "sent");
+    assert_eq!(sent_emails().await.len(), 2);
+    assert!(!site.sent_email().find(&2).await.unwrap().attrs.context.contains("hunter2"));
+  }
+
+  test!{ sends_emails_in_the_student_language(client, site)
+    client.post::<serde_json::Value, _>("/students/",
+      serde_json::json![{
+        "email": "yo+testing@nubis.im",
+        "full_name": "Testing Testinger",
+        "payment_method": "BtcPay",
+        "locale": "pt",
+      }].to_string()
+    ).await;
+
+    let student = site.student().find(&1).await.unwrap();
+    assert_eq!(student.attrs.locale, Locale::Pt);
+
+    let emails = sent_emails().await;
+    assert_eq!(emails[0].subject, "Sobre o seu pagamento à DAO Education");
+    assert_that!(&emails[0].html, rematch("Olá"));
+
+    let state = client.get::<serde_json::Value, _>("/students/1?admin_key=adminusertoken").await;
+    let charge = state.get("billing").unwrap().get("unpaid_charges").unwrap().get(0).unwrap();
+    assert_that!(&charge.get("description").unwrap().as_str().unwrap().to_string(), rematch("^Assinatura"));
+  }
+
+  test!{ previews_email_templates(client, _site)
+    client.post::<serde_json::Value, _>("/students/",
+      serde_json::json![{
+        "email": "yo+testing@nubis.im",
+        "full_name": "Testing Testinger",
+        "payment_method": "BtcPay",
+      }].to_string()
+    ).await;
+
+    client.assert_unauthorized_get("/emails/preview?template=emails/welcome").await;
+
+    let sample = client.raw_get("/emails/preview?template=emails/welcome&locale=en&admin_key=adminusertoken").await;
+    assert_that!(&sample, rematch("Satoshi Nakamoto"));
+    assert_that!(&sample, rematch("Welcome|welcome"));
+
+    let real = client.raw_get("/emails/preview?template=emails/payment_link&student_id=1&admin_key=adminusertoken").await;
+    assert_that!(&real, rematch("Testing Testinger"));
+    assert_that!(&real, rematch("btcpay.constata.eu"));
+  }
 }
@@ -0,0 +1,11 @@
+CREATE TYPE program AS ENUM (
+  'zero_to_hero',
+  'academy'
+);
+
+ALTER TABLE subscriptions ADD COLUMN program program NOT NULL DEFAULT 'zero_to_hero';
+ALTER TABLE degrees ADD COLUMN program program NOT NULL DEFAULT 'zero_to_hero';
+
+CREATE INDEX subscription_program ON subscriptions (program);
+CREATE UNIQUE INDEX subscription_active_student_program ON subscriptions (student_id, program) WHERE active;
+CREATE INDEX degrees_program ON degrees (program);
@@ -0,0 +1,37 @@
+CREATE TABLE cohorts (
+  id SERIAL PRIMARY KEY NOT NULL,
+  program program NOT NULL,
+  name VARCHAR NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  starts_at TIMESTAMPTZ NOT NULL,
+  ends_at TIMESTAMPTZ NOT NULL,
+  capacity INTEGER NOT NULL,
+  enrollment_open BOOLEAN NOT NULL DEFAULT FALSE,
+  discord_role_id VARCHAR,
+  wordpress_group_id INTEGER
+);
+
+CREATE INDEX cohorts_program ON cohorts (program);
+CREATE INDEX cohorts_enrollment_open ON cohorts (enrollment_open);
+
+ALTER TABLE subscriptions ADD COLUMN cohort_id INTEGER;
+CREATE INDEX subscription_cohort_id ON subscriptions (cohort_id);
+
+CREATE TABLE waitlist_entries (
+  id SERIAL PRIMARY KEY NOT NULL,
+  cohort_id INTEGER,
+  program program NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  country VARCHAR NOT NULL,
+  email VARCHAR NOT NULL,
+  full_name VARCHAR NOT NULL,
+  phone VARCHAR,
+  tax_number VARCHAR,
+  tax_address VARCHAR,
+  referral_code VARCHAR,
+  payment_method payment_method NOT NULL
+);
+
+CREATE INDEX waitlist_entries_cohort_id ON waitlist_entries (cohort_id);
+CREATE INDEX waitlist_entries_program ON waitlist_entries (program);
+CREATE INDEX waitlist_entries_email ON waitlist_entries (email);
@@ -0,0 +1,14 @@
+CREATE TYPE waitlist_status AS ENUM (
+  'waiting',
+  'invited',
+  'expired',
+  'accepted'
+);
+
+ALTER TABLE waitlist_entries ADD COLUMN status waitlist_status NOT NULL DEFAULT 'waiting';
+ALTER TABLE waitlist_entries ADD COLUMN invitation_token VARCHAR;
+ALTER TABLE waitlist_entries ADD COLUMN invited_at TIMESTAMPTZ;
+ALTER TABLE waitlist_entries ADD COLUMN invitation_expires_at TIMESTAMPTZ;
+
+CREATE INDEX waitlist_entries_status ON waitlist_entries (status);
+CREATE UNIQUE INDEX waitlist_entries_invitation_token ON waitlist_entries (invitation_token);
@@ -0,0 +1,37 @@
+CREATE TYPE scholarship_kind AS ENUM (
+  'percentage',
+  'fixed'
+);
+
+CREATE TYPE scholarship_status AS ENUM (
+  'requested',
+  'approved',
+  'rejected'
+);
+
+CREATE TABLE scholarships (
+  id SERIAL PRIMARY KEY NOT NULL,
+  student_id INTEGER NOT NULL,
+  program program NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  kind scholarship_kind NOT NULL,
+  value DECIMAL NOT NULL,
+  sponsor VARCHAR,
+  reason TEXT NOT NULL,
+  status scholarship_status NOT NULL DEFAULT 'requested',
+  reviewed_at TIMESTAMPTZ,
+  expires_at TIMESTAMPTZ
+);
+
+CREATE INDEX scholarships_student_id ON scholarships (student_id);
+CREATE INDEX scholarships_status ON scholarships (status);
+
+ALTER TABLE subscriptions ADD COLUMN list_price DECIMAL;
+UPDATE subscriptions SET list_price = price;
+ALTER TABLE subscriptions ALTER COLUMN list_price SET NOT NULL;
+ALTER TABLE subscriptions ADD COLUMN scholarship_id INTEGER;
+
+ALTER TABLE degrees ADD COLUMN list_price DECIMAL;
+UPDATE degrees SET list_price = price;
+ALTER TABLE degrees ALTER COLUMN list_price SET NOT NULL;
+ALTER TABLE degrees ADD COLUMN scholarship_id INTEGER;
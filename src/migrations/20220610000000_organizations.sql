@@ -0,0 +1,46 @@
+CREATE TABLE organizations (
+  id SERIAL PRIMARY KEY NOT NULL,
+  name VARCHAR NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  billing_contact_name VARCHAR NOT NULL,
+  billing_email VARCHAR NOT NULL,
+  country VARCHAR NOT NULL,
+  tax_number VARCHAR,
+  tax_address VARCHAR,
+  payment_method payment_method NOT NULL DEFAULT 'stripe',
+  stripe_customer_id VARCHAR
+);
+
+ALTER TABLE students ADD COLUMN organization_id INTEGER;
+CREATE INDEX student_organization_id ON students (organization_id);
+
+CREATE TABLE organization_invoices (
+  id SERIAL PRIMARY KEY NOT NULL,
+  organization_id INTEGER NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  payment_method payment_method NOT NULL,
+  external_id VARCHAR NOT NULL,
+  amount DECIMAL NOT NULL,
+  description TEXT NOT NULL,
+  url TEXT NOT NULL,
+  paid BOOLEAN NOT NULL DEFAULT FALSE,
+  expired BOOLEAN NOT NULL DEFAULT FALSE,
+  paid_at TIMESTAMPTZ
+);
+
+CREATE INDEX organization_invoices_organization_id ON organization_invoices (organization_id);
+CREATE INDEX organization_invoices_external_id ON organization_invoices (external_id);
+
+CREATE TABLE organization_invoice_items (
+  id SERIAL PRIMARY KEY NOT NULL,
+  organization_invoice_id INTEGER NOT NULL,
+  student_id INTEGER NOT NULL,
+  description TEXT NOT NULL,
+  amount DECIMAL NOT NULL
+);
+
+CREATE INDEX organization_invoice_items_invoice_id ON organization_invoice_items (organization_invoice_id);
+CREATE INDEX organization_invoice_items_student_id ON organization_invoice_items (student_id);
+
+ALTER TABLE payments ADD COLUMN organization_invoice_id INTEGER;
+CREATE INDEX payments_organization_invoice_id ON payments (organization_invoice_id);
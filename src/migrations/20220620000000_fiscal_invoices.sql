@@ -0,0 +1,44 @@
+CREATE TABLE fiscal_invoice_series (
+  series VARCHAR PRIMARY KEY NOT NULL,
+  next_number INTEGER NOT NULL
+);
+
+CREATE TABLE fiscal_invoices (
+  id SERIAL PRIMARY KEY NOT NULL,
+  series VARCHAR NOT NULL,
+  number INTEGER NOT NULL,
+  issued_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  student_id INTEGER,
+  organization_id INTEGER,
+  payment_id INTEGER,
+  organization_invoice_id INTEGER,
+  issuer_name VARCHAR NOT NULL,
+  issuer_tax_number VARCHAR NOT NULL,
+  issuer_address VARCHAR NOT NULL,
+  customer_name VARCHAR NOT NULL,
+  customer_tax_number VARCHAR,
+  customer_tax_address VARCHAR,
+  customer_country VARCHAR NOT NULL,
+  subtotal DECIMAL NOT NULL,
+  tax_rate DECIMAL NOT NULL,
+  tax_amount DECIMAL NOT NULL,
+  total DECIMAL NOT NULL
+);
+
+CREATE UNIQUE INDEX fiscal_invoices_series_number ON fiscal_invoices (series, number);
+CREATE INDEX fiscal_invoices_student_id ON fiscal_invoices (student_id);
+CREATE INDEX fiscal_invoices_organization_id ON fiscal_invoices (organization_id);
+CREATE INDEX fiscal_invoices_payment_id ON fiscal_invoices (payment_id);
+
+CREATE TABLE fiscal_invoice_lines (
+  id SERIAL PRIMARY KEY NOT NULL,
+  fiscal_invoice_id INTEGER NOT NULL,
+  charge_kind VARCHAR NOT NULL,
+  charge_id INTEGER NOT NULL,
+  description TEXT NOT NULL,
+  subtotal DECIMAL NOT NULL,
+  tax_amount DECIMAL NOT NULL,
+  total DECIMAL NOT NULL
+);
+
+CREATE INDEX fiscal_invoice_lines_fiscal_invoice_id ON fiscal_invoice_lines (fiscal_invoice_id);
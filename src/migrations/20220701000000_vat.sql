@@ -0,0 +1,7 @@
+CREATE TYPE tax_treatment AS ENUM ('domestic', 'eu_consumer', 'reverse_charge', 'export');
+
+ALTER TABLE students ADD COLUMN vat_number VARCHAR;
+ALTER TABLE organizations ADD COLUMN vat_number VARCHAR;
+
+ALTER TABLE fiscal_invoices ADD COLUMN customer_vat_number VARCHAR;
+ALTER TABLE fiscal_invoices ADD COLUMN tax_treatment tax_treatment NOT NULL DEFAULT 'domestic';
@@ -0,0 +1,25 @@
+CREATE TYPE currency AS ENUM (
+  'eur',
+  'usd',
+  'ars',
+  'brl',
+  'mxn',
+  'btc'
+);
+
+ALTER TABLE subscriptions ADD COLUMN currency currency NOT NULL DEFAULT 'eur';
+ALTER TABLE degrees ADD COLUMN currency currency NOT NULL DEFAULT 'eur';
+ALTER TABLE invoices ADD COLUMN currency currency NOT NULL DEFAULT 'eur';
+ALTER TABLE payments ADD COLUMN currency currency NOT NULL DEFAULT 'eur';
+ALTER TABLE fiscal_invoices ADD COLUMN currency currency NOT NULL DEFAULT 'eur';
+ALTER TABLE organization_invoices ADD COLUMN currency currency NOT NULL DEFAULT 'eur';
+
+CREATE TABLE exchange_rates (
+  id SERIAL PRIMARY KEY NOT NULL,
+  currency currency NOT NULL,
+  eur_rate DECIMAL NOT NULL,
+  valid_from TIMESTAMPTZ NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX exchange_rates_currency_valid_from ON exchange_rates (currency, valid_from);
@@ -0,0 +1,20 @@
+CREATE TABLE refunds (
+  id SERIAL PRIMARY KEY NOT NULL,
+  payment_id INTEGER NOT NULL,
+  student_id INTEGER NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  amount DECIMAL NOT NULL,
+  currency currency NOT NULL,
+  fees DECIMAL NOT NULL DEFAULT 0,
+  reason TEXT NOT NULL,
+  clearing_data TEXT NOT NULL DEFAULT ''
+);
+
+CREATE INDEX refunds_payment_id ON refunds (payment_id);
+CREATE INDEX refunds_student_id ON refunds (student_id);
+
+CREATE TYPE fiscal_document_kind AS ENUM ('invoice', 'credit_note');
+
+ALTER TABLE fiscal_invoices ADD COLUMN kind fiscal_document_kind NOT NULL DEFAULT 'invoice';
+ALTER TABLE fiscal_invoices ADD COLUMN rectifies_id INTEGER;
+ALTER TABLE fiscal_invoices ADD COLUMN refund_id INTEGER;
@@ -0,0 +1,6 @@
+ALTER TABLE invoices ADD COLUMN reminder_count INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE invoices ADD COLUMN due_since TIMESTAMPTZ NOT NULL DEFAULT now();
+
+ALTER TABLE students ADD COLUMN delinquent_since TIMESTAMPTZ;
+
+CREATE INDEX invoices_open ON invoices (paid, expired);
@@ -0,0 +1,32 @@
+CREATE TYPE email_sender AS ENUM ('default', 'billing');
+
+CREATE TYPE email_status AS ENUM (
+  'queued',
+  'sent',
+  'failed',
+  'delivered',
+  'opened',
+  'bounced',
+  'complained'
+);
+
+CREATE TABLE sent_emails (
+  id SERIAL PRIMARY KEY NOT NULL,
+  student_id INTEGER,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  sender email_sender NOT NULL,
+  to_email VARCHAR NOT NULL,
+  to_name VARCHAR NOT NULL,
+  template VARCHAR NOT NULL,
+  subject VARCHAR NOT NULL,
+  context TEXT NOT NULL,
+  body_hash VARCHAR NOT NULL,
+  has_attachments BOOLEAN NOT NULL DEFAULT FALSE,
+  provider_message_id VARCHAR,
+  status email_status NOT NULL DEFAULT 'queued',
+  error TEXT
+);
+
+CREATE INDEX sent_emails_student_id ON sent_emails (student_id);
+CREATE UNIQUE INDEX sent_emails_provider_message_id ON sent_emails (provider_message_id);
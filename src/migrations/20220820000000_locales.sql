@@ -0,0 +1,23 @@
+CREATE TYPE locale AS ENUM ('es', 'en', 'pt');
+
+-- Same lists as Locale::from_country.
+CREATE FUNCTION locale_for_country(country VARCHAR) RETURNS locale AS $$
+  SELECT CASE
+    WHEN upper(country) IN ('BR', 'PT', 'AO', 'MZ', 'CV', 'GW', 'ST', 'TL') THEN 'pt'::locale
+    WHEN upper(country) IN (
+      'AR', 'BO', 'CL', 'CO', 'CR', 'CU', 'DO', 'EC', 'ES', 'GQ', 'GT', 'HN',
+      'MX', 'NI', 'PA', 'PE', 'PR', 'PY', 'SV', 'UY', 'VE', 'XX'
+    ) THEN 'es'::locale
+    ELSE 'en'::locale
+  END
+$$ LANGUAGE SQL IMMUTABLE;
+
+ALTER TABLE students ADD COLUMN locale locale NOT NULL DEFAULT 'es';
+UPDATE students SET locale = locale_for_country(country);
+
+ALTER TABLE waitlist_entries ADD COLUMN locale locale NOT NULL DEFAULT 'es';
+UPDATE waitlist_entries SET locale = locale_for_country(country);
+
+DROP FUNCTION locale_for_country(VARCHAR);
+
+ALTER TABLE sent_emails ADD COLUMN locale locale NOT NULL DEFAULT 'es';
@@ -0,0 +1,4 @@
+-- Students now choose their WordPress password through a reset link, the ones we generated are not kept.
+UPDATE students SET wordpress_initial_password = NULL;
+ALTER TABLE students DROP COLUMN wordpress_initial_password;
+UPDATE sent_emails SET context = (context::jsonb - 'password')::text WHERE template = 'emails/welcome';
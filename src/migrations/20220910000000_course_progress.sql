@@ -0,0 +1,18 @@
+CREATE TYPE course_status AS ENUM ('not_started', 'in_progress', 'completed');
+
+CREATE TABLE course_progress (
+  id SERIAL PRIMARY KEY NOT NULL,
+  student_id INTEGER NOT NULL,
+  course_id INTEGER NOT NULL,
+  status course_status NOT NULL DEFAULT 'not_started',
+  steps_completed INTEGER NOT NULL DEFAULT 0,
+  steps_total INTEGER NOT NULL DEFAULT 0,
+  quizzes_taken INTEGER NOT NULL DEFAULT 0,
+  quizzes_passed INTEGER NOT NULL DEFAULT 0,
+  started_at TIMESTAMPTZ,
+  completed_at TIMESTAMPTZ,
+  synced_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX course_progress_student_id ON course_progress (student_id);
+CREATE UNIQUE INDEX course_progress_student_course ON course_progress (student_id, course_id);
@@ -0,0 +1,12 @@
+CREATE TABLE discord_grants (
+  id SERIAL PRIMARY KEY NOT NULL,
+  student_id INTEGER NOT NULL,
+  discord_user_id VARCHAR NOT NULL,
+  sealed_access_token VARCHAR NOT NULL,
+  sealed_refresh_token VARCHAR NOT NULL,
+  scope VARCHAR NOT NULL,
+  expires_at TIMESTAMPTZ NOT NULL,
+  updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE UNIQUE INDEX discord_grants_student_id ON discord_grants (student_id);
@@ -0,0 +1,280 @@
+use crate::error::Result;
+use super::*;
+use std::collections::BTreeSet;
+
+/* LearnDash groups and Discord roles, either the ones a student should have,
+ * or every one we manage and are allowed to take away. */
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct Access {
+  pub wordpress_groups: BTreeSet<i32>,
+  pub discord_roles: BTreeSet<String>,
+}
+
+impl Access {
+  /* Everything configured for programs and cohorts. Groups or roles not listed here
+   * were granted by hand, and reconciliation never touches them. */
+  pub async fn managed(site: &Site) -> Result<Access> {
+    let wp = &site.settings.wordpress;
+    let discord = &site.settings.discord;
+    let mut managed = Access::default();
+
+    managed.wordpress_groups.insert(wp.student_group_id);
+    managed.discord_roles.insert(discord.student_role_id.clone());
+
+    for program in Program::all() {
+      managed.wordpress_groups.insert(*wp.program_group_ids.get(program));
+      managed.discord_roles.insert(discord.program_role_ids.get(program).clone());
+    }
+
+    for cohort in site.cohort().select().all().await? {
+      managed.grant_cohort(&cohort);
+    }
+
+    Ok(managed)
+  }
+
+  pub fn grant_program(&mut self, site: &Site, program: Program) {
+    self.wordpress_groups.insert(site.settings.wordpress.student_group_id);
+    self.wordpress_groups.insert(*site.settings.wordpress.program_group_ids.get(program));
+    self.discord_roles.insert(site.settings.discord.student_role_id.clone());
+    self.discord_roles.insert(site.settings.discord.program_role_ids.get(program).clone());
+  }
+
+  pub fn grant_cohort(&mut self, cohort: &Cohort) {
+    if let Some(group_id) = cohort.attrs.wordpress_group_id {
+      self.wordpress_groups.insert(group_id);
+    }
+    if let Some(ref role_id) = cohort.attrs.discord_role_id {
+      self.discord_roles.insert(role_id.clone());
+    }
+  }
+
+  /* What it takes to go from the current groups and roles to these ones, leaving unmanaged ones alone.
+   * None means we don't know the current state, because the student has no account there yet. */
+  pub fn changes_from(&self, managed: &Access, groups: Option<&[i32]>, roles: Option<&[String]>) -> Vec<AccessChange> {
+    let mut changes = vec![];
+
+    if let Some(groups) = groups {
+      for group_id in self.wordpress_groups.iter().filter(|g| !groups.contains(g)) {
+        changes.push(AccessChange::JoinWordpressGroup{ group_id: *group_id });
+      }
+      for group_id in groups.iter().filter(|g| managed.wordpress_groups.contains(g) && !self.wordpress_groups.contains(g)) {
+        changes.push(AccessChange::LeaveWordpressGroup{ group_id: *group_id });
+      }
+    }
+
+    if let Some(roles) = roles {
+      for role_id in self.discord_roles.iter().filter(|r| !roles.contains(r)) {
+        changes.push(AccessChange::GrantDiscordRole{ role_id: role_id.clone() });
+      }
+      for role_id in roles.iter().filter(|r| managed.discord_roles.contains(*r) && !self.discord_roles.contains(*r)) {
+        changes.push(AccessChange::RevokeDiscordRole{ role_id: role_id.clone() });
+      }
+    }
+
+    changes
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum AccessChange {
+  JoinWordpressGroup{ group_id: i32 },
+  LeaveWordpressGroup{ group_id: i32 },
+  GrantDiscordRole{ role_id: String },
+  RevokeDiscordRole{ role_id: String },
+}
+
+impl AccessChange {
+  pub async fn apply(&self, student: &Student) -> Result<()> {
+    match self {
+      AccessChange::JoinWordpressGroup{ group_id } => student.join_wordpress_group(*group_id).await,
+      AccessChange::LeaveWordpressGroup{ group_id } => student.leave_wordpress_group(*group_id).await,
+      AccessChange::GrantDiscordRole{ role_id } => student.grant_discord_role(role_id).await,
+      AccessChange::RevokeDiscordRole{ role_id } => student.revoke_discord_role(role_id).await,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AccessReport {
+  pub student_id: i32,
+  pub email: String,
+  pub delinquent: bool,
+  pub entitled_programs: Vec<Program>,
+  pub changes: Vec<AccessChange>,
+  pub applied: bool,
+  pub error: Option<String>,
+}
+
+impl Student {
+  /* A program grants access while its subscription is paid and the student's payments still cover it.
+   * A refund leaves charges marked as paid with nothing backing them, and delinquent students get nothing. */
+  pub async fn entitlement(&self) -> Result<(Vec<Program>, Access)> {
+    let mut programs = vec![];
+    let mut access = Access::default();
+
+    if self.attrs.delinquent_since.is_some() {
+      return Ok((programs, access));
+    }
+
+    let billing = BillingSummary::new(self.clone()).await?;
+    let covered = |currency: Currency| {
+      let unpaid: Decimal = billing.unpaid_charges.iter()
+        .filter(|c| c.currency() == currency)
+        .map(|c| c.amount())
+        .sum();
+      billing.balance_in(currency) + unpaid >= Decimal::ZERO
+    };
+
+    for subscription in billing.subscriptions.iter() {
+      if !subscription.attrs.paid || !covered(subscription.attrs.currency) {
+        continue;
+      }
+
+      programs.push(subscription.attrs.program);
+      access.grant_program(&self.state, subscription.attrs.program);
+      if let Some(cohort) = subscription.cohort().await? {
+        access.grant_cohort(&cohort);
+      }
+    }
+
+    Ok((programs, access))
+  }
+
+  pub async fn current_wordpress_groups(&self) -> Result<Option<Vec<i32>>> {
+    #[derive(Deserialize)]
+    struct Group {
+      id: i32,
+    }
+
+    if self.attrs.wordpress_user.is_none() {
+      return Ok(None);
+    }
+
+    let groups: Vec<Group> = self.state.wordpress_api()
+      .get(&format!("{}?per_page=100", self.wordpress_groups_path()?))
+      .await?
+      .json()?;
+
+    Ok(Some(groups.into_iter().map(|g| g.id).collect()))
+  }
+
+  pub async fn current_discord_roles(&self) -> Result<Option<Vec<String>>> {
+    #[derive(Deserialize)]
+    struct Member {
+      roles: Vec<String>,
+    }
+
+    if self.attrs.discord_user_id.is_none() {
+      return Ok(None);
+    }
+
+    let member: Member = self.state.discord_api()
+      .get(&self.discord_member_path()?)
+      .await?
+      .json()?;
+
+    Ok(Some(member.roles))
+  }
+
+  /* Joins every configured LearnDash group the student is entitled to and leaves the others.
+   * Running it again changes nothing, so it's safe to call whenever a subscription changes. */
+  pub async fn sync_wordpress_groups(&self) -> Result<Vec<AccessChange>> {
+    let managed = Access::managed(&self.state).await?;
+    let (_, entitled) = self.entitlement().await?;
+    let groups = self.current_wordpress_groups().await?;
+    let changes = entitled.changes_from(&managed, groups.as_deref(), None);
+
+    for change in changes.iter() {
+      change.apply(self).await?;
+    }
+
+    Ok(changes)
+  }
+
+  pub async fn reconcile_access(&self, managed: &Access, dry_run: bool) -> Result<AccessReport> {
+    let (entitled_programs, entitled) = self.entitlement().await?;
+    let groups = self.current_wordpress_groups().await?;
+    let roles = self.current_discord_roles().await?;
+    let changes = entitled.changes_from(managed, groups.as_deref(), roles.as_deref());
+
+    if !dry_run {
+      for change in changes.iter() {
+        change.apply(self).await?;
+      }
+    }
+
+    Ok(AccessReport{
+      student_id: self.attrs.id,
+      email: self.attrs.email.clone(),
+      delinquent: self.attrs.delinquent_since.is_some(),
+      entitled_programs,
+      changes,
+      applied: !dry_run,
+      error: None,
+    })
+  }
+}
+
+impl StudentHub {
+  /* Only students with an account on either side can have anything to reconcile.
+   * A failure with one student is reported along the others instead of stopping the run. */
+  pub async fn reconcile_access(&self, student_id: Option<i32>, dry_run: bool) -> Result<Vec<AccessReport>> {
+    let managed = Access::managed(&self.state).await?;
+
+    let students = match student_id {
+      Some(id) => vec![self.find(&id).await?],
+      None => self.select().order_by(StudentOrderBy::Id).all().await?,
+    };
+
+    let mut reports = vec![];
+    for student in students.iter().filter(|s| s.attrs.wordpress_user.is_some() || s.attrs.discord_user_id.is_some()) {
+      let report = match student.reconcile_access(&managed, dry_run).await {
+        Ok(report) => report,
+        Err(e) => AccessReport{
+          student_id: student.attrs.id,
+          email: student.attrs.email.clone(),
+          delinquent: student.attrs.delinquent_since.is_some(),
+          entitled_programs: vec![],
+          changes: vec![],
+          applied: false,
+          error: Some(format!("{:?}", e)),
+        },
+      };
+
+      if report.error.is_some() || !report.changes.is_empty() {
+        reports.push(report);
+      }
+    }
+
+    Ok(reports)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn computes_access_changes_leaving_unmanaged_alone() {
+    let managed = Access{
+      wordpress_groups: vec![1, 2, 3].into_iter().collect(),
+      discord_roles: vec!["student".to_string(), "academy".to_string()].into_iter().collect(),
+    };
+    let entitled = Access{
+      wordpress_groups: vec![1, 2].into_iter().collect(),
+      discord_roles: vec!["student".to_string()].into_iter().collect(),
+    };
+
+    let changes = entitled.changes_from(&managed, Some(&[2, 3, 99]), Some(&["academy".to_string(), "moderator".to_string()]));
+    assert_eq!(changes, vec![
+      AccessChange::JoinWordpressGroup{ group_id: 1 },
+      AccessChange::LeaveWordpressGroup{ group_id: 3 },
+      AccessChange::GrantDiscordRole{ role_id: "student".to_string() },
+      AccessChange::RevokeDiscordRole{ role_id: "academy".to_string() },
+    ]);
+
+    assert!(entitled.changes_from(&managed, None, None).is_empty());
+  }
+}
@@ -0,0 +1,365 @@
+use crate::error::Result;
+use super::*;
+
+/* Ledger account codes for the journal export, as our accountant names them. */
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+pub struct AccountingSettings {
+  pub receivables_account: String,
+  pub revenue_account: String,
+  pub tax_payable_account: String,
+  pub fees_account: String,
+  pub stripe_account: String,
+  pub btcpay_account: String,
+}
+
+impl AccountingSettings {
+  pub fn cash_account(&self, payment_method: PaymentMethod) -> &str {
+    match payment_method {
+      PaymentMethod::Stripe => &self.stripe_account,
+      PaymentMethod::BtcPay => &self.btcpay_account,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LedgerEntryKind {
+  Invoice,
+  CreditNote,
+  Payment,
+  PaymentFee,
+  Refund,
+  RefundFee,
+}
+
+impl LedgerEntryKind {
+  pub fn name(&self) -> &'static str {
+    match self {
+      LedgerEntryKind::Invoice => "invoice",
+      LedgerEntryKind::CreditNote => "credit_note",
+      LedgerEntryKind::Payment => "payment",
+      LedgerEntryKind::PaymentFee => "payment_fee",
+      LedgerEntryKind::Refund => "refund",
+      LedgerEntryKind::RefundFee => "refund_fee",
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct JournalLine {
+  pub account: String,
+  pub debit: Decimal,
+  pub credit: Decimal,
+  pub debit_eur: Option<Decimal>,
+  pub credit_eur: Option<Decimal>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LedgerEntry {
+  pub date: UtcDateTime,
+  pub kind: LedgerEntryKind,
+  pub reference: String,
+  pub student_id: Option<i32>,
+  pub organization_id: Option<i32>,
+  pub payment_method: Option<PaymentMethod>,
+  pub description: String,
+  pub currency: Currency,
+  pub amount: Decimal,
+  pub eur_rate: Option<Decimal>,
+  pub lines: Vec<JournalLine>,
+}
+
+impl LedgerEntry {
+  /* Postings are signed, debits positive and credits negative, and must add up to zero.
+   * Rounding to euros can leave a cent off, the last line absorbs it so entries stay balanced.
+   * Without an exchange rate the euro amounts are left out, for the accountant to fill in. */
+  pub fn journal_lines(postings: &[(&str, Decimal)], eur_rate: Option<Decimal>) -> Vec<JournalLine> {
+    let mut lines: Vec<JournalLine> = postings.iter()
+      .filter(|(_, amount)| !amount.is_zero())
+      .map(|(account, amount)| {
+        let eur = eur_rate.map(|rate| (amount.abs() * rate).round_dp(2));
+        if amount.is_sign_positive() {
+          JournalLine{ account: account.to_string(), debit: *amount, credit: Decimal::ZERO, debit_eur: eur, credit_eur: eur.map(|_| Decimal::ZERO) }
+        } else {
+          JournalLine{ account: account.to_string(), debit: Decimal::ZERO, credit: amount.abs(), debit_eur: eur.map(|_| Decimal::ZERO), credit_eur: eur }
+        }
+      }).collect();
+
+    if eur_rate.is_none() {
+      return lines;
+    }
+
+    let unbalanced: Decimal = lines.iter().map(|l| l.debit_eur.unwrap_or_default() - l.credit_eur.unwrap_or_default()).sum();
+    if let Some(last) = lines.last_mut() {
+      match (last.debit_eur.as_mut(), last.credit_eur.as_mut()) {
+        (Some(debit), Some(credit)) if credit.is_zero() => *debit -= unbalanced,
+        (_, Some(credit)) => *credit += unbalanced,
+        _ => {},
+      }
+    }
+
+    lines
+  }
+}
+
+pub struct AccountingExport {
+  pub from: UtcDateTime,
+  pub until: UtcDateTime,
+  pub entries: Vec<LedgerEntry>,
+}
+
+impl AccountingExport {
+  pub async fn build(site: &Site, from: UtcDateTime, until: UtcDateTime) -> Result<AccountingExport> {
+    let accounts = &site.settings.accounting;
+    let mut entries = vec![];
+
+    let fiscal_invoice_ids = sqlx::query_scalar!(
+      "SELECT id FROM fiscal_invoices WHERE issued_at >= $1 AND issued_at < $2 ORDER BY id",
+      from,
+      until,
+    ).fetch_all(&site.db).await?;
+
+    for id in fiscal_invoice_ids {
+      let invoice = site.fiscal_invoice().find(&id).await?;
+      let a = &invoice.attrs;
+      let eur_rate = rate_at(site, a.currency, a.issued_at).await?;
+      let kind = match a.kind {
+        FiscalDocumentKind::Invoice => LedgerEntryKind::Invoice,
+        FiscalDocumentKind::CreditNote => LedgerEntryKind::CreditNote,
+      };
+
+      entries.push(LedgerEntry{
+        date: a.issued_at,
+        kind,
+        reference: invoice.full_number(),
+        student_id: a.student_id,
+        organization_id: a.organization_id,
+        payment_method: None,
+        description: a.customer_name.clone(),
+        currency: a.currency,
+        amount: a.total,
+        eur_rate,
+        lines: LedgerEntry::journal_lines(&[
+          (&accounts.receivables_account, a.total),
+          (&accounts.revenue_account, a.subtotal * Decimal::NEGATIVE_ONE),
+          (&accounts.tax_payable_account, a.tax_amount * Decimal::NEGATIVE_ONE),
+        ], eur_rate),
+      });
+    }
+
+    let payment_ids = sqlx::query_scalar!(
+      "SELECT id FROM payments WHERE created_at >= $1 AND created_at < $2 ORDER BY id",
+      from,
+      until,
+    ).fetch_all(&site.db).await?;
+
+    for id in payment_ids {
+      let payment = site.payment().find(&id).await?;
+      let a = &payment.attrs;
+      let eur_rate = rate_at(site, a.currency, a.created_at).await?;
+      let cash = accounts.cash_account(a.payment_method);
+      let reference = format!("P{}", a.id);
+
+      entries.push(LedgerEntry{
+        date: a.created_at,
+        kind: LedgerEntryKind::Payment,
+        reference: reference.clone(),
+        student_id: Some(a.student_id),
+        organization_id: None,
+        payment_method: Some(a.payment_method),
+        description: format!("Cobro #{}", a.id),
+        currency: a.currency,
+        amount: a.amount,
+        eur_rate,
+        lines: LedgerEntry::journal_lines(&[
+          (cash, a.amount),
+          (&accounts.receivables_account, a.amount * Decimal::NEGATIVE_ONE),
+        ], eur_rate),
+      });
+
+      if !a.fees.is_zero() {
+        entries.push(LedgerEntry{
+          date: a.created_at,
+          kind: LedgerEntryKind::PaymentFee,
+          reference,
+          student_id: Some(a.student_id),
+          organization_id: None,
+          payment_method: Some(a.payment_method),
+          description: format!("Comisión del cobro #{}", a.id),
+          currency: a.currency,
+          amount: a.fees,
+          eur_rate,
+          lines: LedgerEntry::journal_lines(&[
+            (&accounts.fees_account, a.fees),
+            (cash, a.fees * Decimal::NEGATIVE_ONE),
+          ], eur_rate),
+        });
+      }
+    }
+
+    let refund_ids = sqlx::query_scalar!(
+      "SELECT id FROM refunds WHERE created_at >= $1 AND created_at < $2 ORDER BY id",
+      from,
+      until,
+    ).fetch_all(&site.db).await?;
+
+    for id in refund_ids {
+      let refund = site.refund().find(&id).await?;
+      let a = &refund.attrs;
+      let payment = site.payment().find(&a.payment_id).await?;
+      let eur_rate = rate_at(site, a.currency, a.created_at).await?;
+      let cash = accounts.cash_account(payment.attrs.payment_method);
+      let reference = format!("R{}", a.id);
+
+      entries.push(LedgerEntry{
+        date: a.created_at,
+        kind: LedgerEntryKind::Refund,
+        reference: reference.clone(),
+        student_id: Some(a.student_id),
+        organization_id: None,
+        payment_method: Some(payment.attrs.payment_method),
+        description: format!("Devolución del cobro #{}: {}", a.payment_id, a.reason),
+        currency: a.currency,
+        amount: a.amount,
+        eur_rate,
+        lines: LedgerEntry::journal_lines(&[
+          (&accounts.receivables_account, a.amount),
+          (cash, a.amount * Decimal::NEGATIVE_ONE),
+        ], eur_rate),
+      });
+
+      if !a.fees.is_zero() {
+        entries.push(LedgerEntry{
+          date: a.created_at,
+          kind: LedgerEntryKind::RefundFee,
+          reference,
+          student_id: Some(a.student_id),
+          organization_id: None,
+          payment_method: Some(payment.attrs.payment_method),
+          description: format!("Comisión de la devolución #{}", a.id),
+          currency: a.currency,
+          amount: a.fees,
+          eur_rate,
+          lines: LedgerEntry::journal_lines(&[
+            (&accounts.fees_account, a.fees),
+            (cash, a.fees * Decimal::NEGATIVE_ONE),
+          ], eur_rate),
+        });
+      }
+    }
+
+    entries.sort_by_key(|e| e.date);
+
+    Ok(AccountingExport{ from, until, entries })
+  }
+
+  pub fn to_csv(&self) -> String {
+    let mut out = csv_row(&[
+      "date", "kind", "reference", "student_id", "organization_id", "payment_method",
+      "description", "currency", "amount", "eur_rate", "amount_eur",
+    ]);
+
+    for e in self.entries.iter() {
+      let amount_eur: Option<Decimal> = e.lines.iter().map(|l| l.debit_eur).sum();
+      out.push_str(&csv_row(&[
+        &e.date.to_rfc3339(),
+        e.kind.name(),
+        &e.reference,
+        &e.student_id.map(|i| i.to_string()).unwrap_or_default(),
+        &e.organization_id.map(|i| i.to_string()).unwrap_or_default(),
+        &e.payment_method.map(|m| format!("{:?}", m).to_lowercase()).unwrap_or_default(),
+        &e.description,
+        e.currency.code(),
+        &e.amount.to_string(),
+        &e.eur_rate.map(|r| r.to_string()).unwrap_or_default(),
+        &amount_eur.map(|a| a.to_string()).unwrap_or_default(),
+      ]));
+    }
+
+    out
+  }
+
+  /* One row per journal line, grouped by entry number. Amounts in euros, with the original alongside. */
+  pub fn to_journal(&self) -> String {
+    let mut out = csv_row(&[
+      "entry", "date", "account", "description", "debit", "credit", "currency", "original_debit", "original_credit",
+    ]);
+
+    for (i, e) in self.entries.iter().enumerate() {
+      for line in e.lines.iter() {
+        out.push_str(&csv_row(&[
+          &(i + 1).to_string(),
+          &e.date.format("%Y-%m-%d").to_string(),
+          &line.account,
+          &format!("{} {}", e.reference, e.description),
+          &line.debit_eur.map(|d| d.to_string()).unwrap_or_default(),
+          &line.credit_eur.map(|c| c.to_string()).unwrap_or_default(),
+          e.currency.code(),
+          &line.debit.to_string(),
+          &line.credit.to_string(),
+        ]));
+      }
+    }
+
+    out
+  }
+}
+
+/* A missing rate leaves the euro columns empty instead of failing the whole export. */
+async fn rate_at(site: &Site, currency: Currency, at: UtcDateTime) -> Result<Option<Decimal>> {
+  match site.exchange_rate().rate_at(currency, at).await {
+    Ok(rate) => Ok(Some(rate)),
+    Err(Error::Validation{ .. }) => Ok(None),
+    Err(e) => Err(e),
+  }
+}
+
+fn csv_row(fields: &[&str]) -> String {
+  let escaped: Vec<String> = fields.iter().map(|f| {
+    if f.contains(',') || f.contains('"') || f.contains('\n') {
+      format!("\"{}\"", f.replace('"', "\"\""))
+    } else {
+      f.to_string()
+    }
+  }).collect();
+  format!("{}\n", escaped.join(","))
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn journal_lines_stay_balanced_in_euros() {
+    let lines = LedgerEntry::journal_lines(&[
+      ("430", Decimal::new(150, 0)),
+      ("705", Decimal::new(-12397, 2)),
+      ("477", Decimal::new(-2603, 2)),
+    ], Some(Decimal::new(3333, 4)));
+
+    let debit: Decimal = lines.iter().map(|l| l.debit_eur.unwrap()).sum();
+    let credit: Decimal = lines.iter().map(|l| l.credit_eur.unwrap()).sum();
+    assert_eq!(debit, Decimal::new(5000, 2));
+    assert_eq!(debit, credit);
+    assert_eq!(lines[1].credit, Decimal::new(12397, 2));
+
+    let no_tax = LedgerEntry::journal_lines(&[
+      ("430", Decimal::ONE),
+      ("705", Decimal::NEGATIVE_ONE),
+      ("477", Decimal::ZERO),
+    ], Some(Decimal::ONE));
+    assert_eq!(no_tax.len(), 2);
+
+    let no_rate = LedgerEntry::journal_lines(&[
+      ("430", Decimal::new(500, 0)),
+      ("705", Decimal::new(-500, 0)),
+    ], None);
+    assert_eq!(no_rate[0].debit, Decimal::new(500, 0));
+    assert!(no_rate.iter().all(|l| l.debit_eur.is_none() && l.credit_eur.is_none()));
+  }
+
+  #[test]
+  fn escapes_csv_fields() {
+    assert_eq!(csv_row(&["a", "b,c", "say \"hi\""]), "a,\"b,c\",\"say \"\"hi\"\"\"\n");
+  }
+}
@@ -0,0 +1,216 @@
+use crate::error::Result;
+use super::*;
+
+make_sqlx_model!{
+  state: Site,
+  table: cohorts,
+  struct Cohort {
+    #[sqlx_search_as(int4)]
+    id: i32,
+    #[sqlx_search_as(program)]
+    program: Program,
+    name: String,
+    created_at: UtcDateTime,
+    starts_at: UtcDateTime,
+    ends_at: UtcDateTime,
+    capacity: i32,
+    #[sqlx_search_as(bool)]
+    enrollment_open: bool,
+    discord_role_id: Option<String>,
+    wordpress_group_id: Option<i32>,
+  }
+}
+
+impl CohortHub {
+  /* Programs that run cohorts always enroll into one. Without a cohort_id that's the earliest one with
+   * seats left, or the earliest one open for enrollment when they're all full, so the signup is waitlisted.
+   * Either way seats are counted again under Cohort::lock_seats before taking one. */
+  pub async fn for_enrollment(&self, program: Program, cohort_id: Option<i32>) -> Result<Option<Cohort>> {
+    let cohort = match cohort_id {
+      Some(id) => self.find(&id).await?,
+      None => return self.next_for_enrollment(program).await,
+    };
+
+    cohort.validate_program(program)?;
+
+    if !cohort.attrs.enrollment_open {
+      return Err(Error::invalid("cohort_id", ValidationMessage::CohortEnrollmentClosed));
+    }
+
+    Ok(Some(cohort))
+  }
+
+  pub async fn open(&self, program: Program) -> Result<Vec<Cohort>> {
+    let mut open = vec![];
+    let cohorts = self.select()
+      .program_eq(&program)
+      .enrollment_open_eq(&true)
+      .order_by(CohortOrderBy::StartsAt)
+      .all().await?;
+
+    for cohort in cohorts.into_iter() {
+      if cohort.seats_left().await? > 0 {
+        open.push(cohort);
+      }
+    }
+
+    Ok(open)
+  }
+
+  async fn next_for_enrollment(&self, program: Program) -> Result<Option<Cohort>> {
+    if let Some(cohort) = self.open(program).await?.into_iter().next() {
+      return Ok(Some(cohort));
+    }
+
+    Ok(self.select()
+      .program_eq(&program)
+      .enrollment_open_eq(&true)
+      .order_by(CohortOrderBy::StartsAt)
+      .optional().await?)
+  }
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize, Validate)]
+pub struct CohortForm {
+  pub program: Program,
+  pub name: String,
+  pub starts_at: UtcDateTime,
+  pub ends_at: UtcDateTime,
+  #[validate(range(min = 1))]
+  pub capacity: i32,
+  pub enrollment_open: bool,
+  pub discord_role_id: Option<String>,
+  pub wordpress_group_id: Option<i32>,
+}
+
+impl CohortForm {
+  pub fn into_insert_cohort(self) -> Result<InsertCohort> {
+    self.validate()?;
+    if self.ends_at <= self.starts_at {
+      return Err(Error::validation("ends_at", "must be after starts_at"));
+    }
+
+    Ok(InsertCohort{
+      program: self.program,
+      name: self.name,
+      created_at: Utc::now(),
+      starts_at: self.starts_at,
+      ends_at: self.ends_at,
+      capacity: self.capacity,
+      enrollment_open: self.enrollment_open,
+      discord_role_id: self.discord_role_id,
+      wordpress_group_id: self.wordpress_group_id,
+    })
+  }
+}
+
+impl Cohort {
+  pub async fn enrolled_count(&self) -> Result<i64> {
+    Ok(sqlx::query_scalar!(
+      r#"SELECT count(*) as "count!" FROM subscriptions WHERE cohort_id = $1 AND active"#,
+      self.attrs.id,
+    ).fetch_one(&self.state.db).await?)
+  }
+
+  /* Seats offered to someone in the waitlist stay reserved until the invitation expires. */
+  pub async fn pending_invitations_count(&self) -> Result<i64> {
+    Ok(sqlx::query_scalar!(
+      r#"SELECT count(*) as "count!" FROM waitlist_entries
+        WHERE cohort_id = $1 AND status = 'invited' AND invitation_expires_at > now()"#,
+      self.attrs.id,
+    ).fetch_one(&self.state.db).await?)
+  }
+
+  pub async fn seats_left(&self) -> Result<i64> {
+    let taken = self.enrolled_count().await? + self.pending_invitations_count().await?;
+    Ok((self.attrs.capacity as i64 - taken).max(0))
+  }
+
+  /* Held while counting seats and taking one, so two signups can't both get the last seat.
+   * The queries in between run on other connections, they only need to wait for this lock.
+   * It's released when the transaction is committed or dropped. */
+  pub async fn lock_seats(&self) -> Result<sqlx::Transaction<'static, sqlx::Postgres>> {
+    let mut tx = self.state.db.begin().await?;
+    sqlx::query!("SELECT id FROM cohorts WHERE id = $1 FOR UPDATE", self.attrs.id)
+      .fetch_one(&mut tx).await?;
+    Ok(tx)
+  }
+
+  /* A cohort takes new students only while enrollment is open and there are seats left.
+   * Students that would not fit go to the waitlist instead. */
+  pub async fn accepts_enrollment(&self) -> Result<bool> {
+    Ok(self.attrs.enrollment_open && self.seats_left().await? > 0)
+  }
+
+  pub fn validate_program(&self, program: Program) -> Result<()> {
+    if self.attrs.program != program {
+      return Err(Error::invalid("cohort_id", ValidationMessage::CohortOfAnotherProgram));
+    }
+    Ok(())
+  }
+
+  pub async fn set_enrollment_open(&mut self, open: bool) -> Result<()> {
+    sqlx::query!(
+      "UPDATE cohorts SET enrollment_open = $2 WHERE id = $1",
+      self.attrs.id,
+      open,
+    ).execute(&self.state.db).await?;
+    self.attrs.enrollment_open = open;
+    Ok(())
+  }
+
+  pub async fn subscriptions(&self) -> sqlx::Result<Vec<Subscription>> {
+    self.state.subscription().select()
+      .cohort_id_eq(&Some(self.attrs.id))
+      .active_eq(&true)
+      .all().await
+  }
+
+  pub async fn waitlist(&self) -> sqlx::Result<Vec<WaitlistEntry>> {
+    self.state.waitlist_entry().select()
+      .cohort_id_eq(&Some(self.attrs.id))
+      .order_by(WaitlistEntryOrderBy::CreatedAt)
+      .all().await
+  }
+
+  /* Grants the cohort's LearnDash group and Discord role, if configured.
+   * Discord is only granted when the student already linked their account. */
+  pub async fn grant_access(&self, student: &Student) -> Result<()> {
+    if let Some(group_id) = self.attrs.wordpress_group_id {
+      student.join_wordpress_group(group_id).await?;
+    }
+    if let (Some(role_id), Some(_)) = (&self.attrs.discord_role_id, &student.attrs.discord_user_id) {
+      student.grant_discord_role(role_id).await?;
+    }
+    Ok(())
+  }
+
+  pub async fn revoke_access(&self, student: &Student) -> Result<()> {
+    if let (Some(group_id), Some(_)) = (self.attrs.wordpress_group_id, &student.attrs.wordpress_user) {
+      student.leave_wordpress_group(group_id).await?;
+    }
+    if let (Some(role_id), Some(_)) = (&self.attrs.discord_role_id, &student.attrs.discord_user_id) {
+      student.revoke_discord_role(role_id).await?;
+    }
+    Ok(())
+  }
+}
+
+#[derive(Serialize)]
+pub struct CohortState {
+  pub cohort: Cohort,
+  pub seats_left: i64,
+  pub subscriptions: Vec<Subscription>,
+  pub waitlist: Vec<WaitlistEntry>,
+}
+
+impl CohortState {
+  pub async fn new(cohort: Cohort) -> Result<CohortState> {
+    Ok(Self{
+      seats_left: cohort.seats_left().await?,
+      subscriptions: cohort.subscriptions().await?,
+      waitlist: cohort.waitlist().await?,
+      cohort,
+    })
+  }
+}
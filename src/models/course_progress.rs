@@ -0,0 +1,233 @@
+use crate::error::Result;
+use super::*;
+use std::collections::BTreeSet;
+
+make_sqlx_model!{
+  state: Site,
+  table: course_progress,
+  struct CourseProgress {
+    #[sqlx_search_as(int4)]
+    id: i32,
+    #[sqlx_search_as(int4)]
+    student_id: i32,
+    #[sqlx_search_as(int4)]
+    course_id: i32,
+    #[sqlx_search_as(course_status)]
+    status: CourseStatus,
+    steps_completed: i32,
+    steps_total: i32,
+    quizzes_taken: i32,
+    quizzes_passed: i32,
+    started_at: Option<UtcDateTime>,
+    completed_at: Option<UtcDateTime>,
+    synced_at: UtcDateTime,
+  }
+}
+
+#[derive(sqlx::Type, Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[sqlx(type_name = "course_status", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum CourseStatus {
+  NotStarted,
+  InProgress,
+  Completed,
+}
+
+impl CourseStatus {
+  /* LearnDash spells them "not-started", "in-progress" and "completed". */
+  pub fn from_learndash(status: &str) -> CourseStatus {
+    match status {
+      "completed" => CourseStatus::Completed,
+      "in-progress" | "in_progress" => CourseStatus::InProgress,
+      _ => CourseStatus::NotStarted,
+    }
+  }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LearndashCourseProgress {
+  pub course: i32,
+  #[serde(default)]
+  pub progress_status: String,
+  #[serde(default)]
+  pub steps_completed: i32,
+  #[serde(default)]
+  pub steps_total: i32,
+  #[serde(default)]
+  pub date_started: Option<String>,
+  #[serde(default)]
+  pub date_completed: Option<String>,
+}
+
+/* Every attempt is listed, a quiz counts as passed if any attempt passed. */
+#[derive(Debug, Deserialize)]
+pub struct LearndashQuizProgress {
+  pub quiz: i32,
+  #[serde(default)]
+  pub course: i32,
+  #[serde(default)]
+  pub pass: serde_json::Value,
+}
+
+impl LearndashQuizProgress {
+  pub fn passed(&self) -> bool {
+    match self.pass {
+      serde_json::Value::Bool(b) => b,
+      serde_json::Value::Number(ref n) => n.as_i64() == Some(1),
+      serde_json::Value::String(ref s) => s == "1",
+      _ => false,
+    }
+  }
+}
+
+/* Dates come in the site's timezone, which is UTC for us, and empty when there's none. */
+pub fn parse_learndash_date(date: Option<&str>) -> Option<UtcDateTime> {
+  let date = date?.trim();
+  DateTime::parse_from_rfc3339(date).map(|d| d.with_timezone(&Utc)).ok()
+    .or_else(|| chrono::NaiveDateTime::parse_from_str(date, "%Y-%m-%dT%H:%M:%S").ok().map(|d| DateTime::from_utc(d, Utc)))
+    .or_else(|| chrono::NaiveDateTime::parse_from_str(date, "%Y-%m-%d %H:%M:%S").ok().map(|d| DateTime::from_utc(d, Utc)))
+}
+
+impl Student {
+  pub async fn course_progress(&self) -> sqlx::Result<Vec<CourseProgress>> {
+    self.state.course_progress().select()
+      .student_id_eq(self.id())
+      .order_by(CourseProgressOrderBy::CourseId)
+      .all().await
+  }
+
+  /* LearnDash only tracks WordPress users, students without one have nothing to sync. */
+  pub async fn sync_course_progress(&self) -> Result<Vec<CourseProgress>> {
+    let user_id = match self.attrs.wordpress_user {
+      Some(ref id) => id,
+      None => return Ok(vec![]),
+    };
+
+    let api = self.state.wordpress_api();
+    let courses: Vec<LearndashCourseProgress> = api
+      .get_all_pages(&format!("/ldlms/v2/users/{}/course-progress", user_id), &[])
+      .await?;
+    let quizzes: Vec<LearndashQuizProgress> = api
+      .get_all_pages(&format!("/ldlms/v2/users/{}/quiz-progress", user_id), &[])
+      .await?;
+
+    for course in courses.iter() {
+      let in_course = || quizzes.iter().filter(|q| q.course == course.course);
+      let taken: BTreeSet<i32> = in_course().map(|q| q.quiz).collect();
+      let passed: BTreeSet<i32> = in_course().filter(|q| q.passed()).map(|q| q.quiz).collect();
+
+      sqlx::query!(
+        "INSERT INTO course_progress
+          (student_id, course_id, status, steps_completed, steps_total, quizzes_taken, quizzes_passed, started_at, completed_at, synced_at)
+          VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, now())
+          ON CONFLICT (student_id, course_id) DO UPDATE SET
+            status = EXCLUDED.status,
+            steps_completed = EXCLUDED.steps_completed,
+            steps_total = EXCLUDED.steps_total,
+            quizzes_taken = EXCLUDED.quizzes_taken,
+            quizzes_passed = EXCLUDED.quizzes_passed,
+            started_at = EXCLUDED.started_at,
+            completed_at = EXCLUDED.completed_at,
+            synced_at = EXCLUDED.synced_at",
+        self.attrs.id,
+        course.course,
+        CourseStatus::from_learndash(&course.progress_status) as CourseStatus,
+        course.steps_completed,
+        course.steps_total,
+        taken.len() as i32,
+        passed.len() as i32,
+        parse_learndash_date(course.date_started.as_deref()),
+        parse_learndash_date(course.date_completed.as_deref()),
+      ).execute(&self.state.db).await?;
+    }
+
+    self.award_degrees().await?;
+    Ok(self.course_progress().await?)
+  }
+
+  /* Only when degree_courses is configured, otherwise degrees are still awarded by hand.
+   * A paid program with all its courses completed gets one degree, that is invoiced right away. */
+  pub async fn award_degrees(&self) -> Result<Vec<Degree>> {
+    let required = match self.state.settings.wordpress.degree_courses {
+      Some(ref required) => required,
+      None => return Ok(vec![]),
+    };
+
+    let completed: Vec<i32> = self.course_progress().await?.into_iter()
+      .filter(|p| p.attrs.status == CourseStatus::Completed)
+      .map(|p| p.attrs.course_id)
+      .collect();
+
+    let mut awarded = vec![];
+    for subscription in self.subscriptions().await? {
+      let program = subscription.attrs.program;
+      let courses = required.get(program);
+      if !subscription.attrs.paid || courses.is_empty() || !courses.iter().all(|c| completed.contains(c)) {
+        continue;
+      }
+
+      let existing = self.state.degree().select()
+        .student_id_eq(self.id())
+        .program_eq(&program)
+        .all().await?;
+      if !existing.is_empty() {
+        continue;
+      }
+
+      awarded.push(subscription.award_degree().await?);
+    }
+
+    if !awarded.is_empty() {
+      BillingSummary::new(self.clone()).await?.invoice_all_not_invoiced_yet().await?;
+      self.send_payment_reminder().await?;
+    }
+
+    Ok(awarded)
+  }
+}
+
+impl StudentHub {
+  /* Run periodically. A failure with one student doesn't stop the others. */
+  pub async fn sync_course_progress(&self, student_id: Option<i32>) -> Result<()> {
+    let students = match student_id {
+      Some(id) => vec![self.find(&id).await?],
+      None => self.select().order_by(StudentOrderBy::Id).all().await?,
+    };
+
+    for student in students.iter().filter(|s| s.attrs.wordpress_user.is_some()) {
+      if let Err(e) = student.sync_course_progress().await {
+        rocket::warn!("Course progress sync failed for student {}: {:?}", student.attrs.id, e);
+      }
+    }
+
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn reads_learndash_progress() {
+    let course: LearndashCourseProgress = serde_json::from_str(r#"{
+      "course": 101, "progress_status": "in-progress", "steps_completed": 3, "steps_total": 8,
+      "date_started": "2022-08-01T12:30:00", "date_completed": ""
+    }"#).unwrap();
+    assert_eq!(CourseStatus::from_learndash(&course.progress_status), CourseStatus::InProgress);
+    assert_eq!(
+      parse_learndash_date(course.date_started.as_deref()).unwrap().to_rfc3339(),
+      "2022-08-01T12:30:00+00:00"
+    );
+    assert_eq!(parse_learndash_date(course.date_completed.as_deref()), None);
+    assert_eq!(CourseStatus::from_learndash("not-started"), CourseStatus::NotStarted);
+    assert_eq!(CourseStatus::from_learndash("completed"), CourseStatus::Completed);
+
+    let attempts: Vec<LearndashQuizProgress> = serde_json::from_str(r#"[
+      { "quiz": 1, "course": 101, "pass": 1 },
+      { "quiz": 2, "course": 101, "pass": false },
+      { "quiz": 3, "course": 101 }
+    ]"#).unwrap();
+    assert_eq!(attempts.iter().filter(|a| a.passed()).count(), 1);
+  }
+}
@@ -1,3 +1,4 @@
+use crate::error::Result;
 use super::*;
 
 make_sqlx_model!{
@@ -15,8 +16,72 @@ make_sqlx_model!{
     poap_link: Option<String>,
     constata_certificate_id: Option<String>,
     price: Decimal,
+    list_price: Decimal,
+    #[sqlx_search_as(currency)]
+    currency: Currency,
+    #[sqlx_search_as(int4)]
+    scholarship_id: Option<i32>,
+    #[sqlx_search_as(program)]
+    program: Program,
     #[sqlx_search_as(bool)]
     paid: bool,
     paid_at: Option<UtcDateTime>,
   }
 }
+
+impl Degree {
+  /* The POAP and the certificate may be issued later, the email says so when they're not there yet. */
+  pub async fn on_paid(&self) -> Result<()> {
+    let student = self.state.student().find(&self.attrs.student_id).await?;
+
+    let mut context = tera::Context::new();
+    context.insert("full_name", &student.attrs.full_name);
+    context.insert("program", self.attrs.program.name());
+    context.insert("poap_link", &self.attrs.poap_link);
+    context.insert("certificate_id", &self.attrs.constata_certificate_id);
+
+    /* Runs while charges are being settled, a failing email must not leave the rest unpaid. */
+    if let Err(e) = self.state.send_email(
+      Sender::Default,
+      student.recipient(),
+      student.attrs.locale.text(Text::DegreePaidSubject),
+      "emails/degree_paid",
+      &context,
+    ).await {
+      rocket::warn!("Could not send degree paid email for degree {}: {:?}", self.attrs.id, e);
+    }
+    Ok(())
+  }
+}
+
+impl Subscription {
+  /* Priced like the plan the student enrolled with, and discounted by their scholarship if they have one. */
+  pub async fn award_degree(&self) -> Result<Degree> {
+    let program = self.attrs.program;
+    let plan = self.state.settings.pricing.get(program)
+      .by_code(self.attrs.plan_code)
+      .in_currency(self.attrs.currency);
+
+    let mut degree = self.state.degree().insert().use_struct(InsertDegree{
+      subscription_id: self.attrs.id,
+      student_id: self.attrs.student_id,
+      created_at: Utc::now(),
+      description: format!("{} {}", Locale::Es.text(Text::DegreeCharge), program.name()),
+      poap_link: None,
+      constata_certificate_id: None,
+      price: plan.degree,
+      list_price: plan.degree,
+      currency: self.attrs.currency,
+      scholarship_id: None,
+      program,
+      paid: false,
+      paid_at: None,
+    }).save().await?;
+
+    if let Some(scholarship) = self.state.scholarship().applicable_for(self.attrs.student_id, program).await? {
+      degree.apply_scholarship(&scholarship).await?;
+    }
+
+    Ok(degree)
+  }
+}
@@ -1,9 +1,15 @@
-use super::Deserialize;
+use crate::error::{Result, Error};
+use super::*;
+use chrono::Duration;
+use reqwest::StatusCode;
+use crate::http::HttpError;
 
+/* What Discord sends back to our redirect uri, the frontend forwards it as is.
+ * The state is the student's discord_verification token, so a code can only link the student it was issued for. */
 #[derive(Debug, FromForm)]
-pub struct DiscordToken {
+pub struct DiscordAuthorization {
   pub state: String,
-  pub access_token: String,
+  pub code: String,
 }
 
 #[derive(Deserialize)]
@@ -14,3 +20,157 @@ pub struct DiscordProfile {
   pub verified: bool,
   pub email: String,
 }
+
+#[derive(Debug, Deserialize)]
+pub struct DiscordTokenResponse {
+  pub access_token: String,
+  pub refresh_token: String,
+  pub expires_in: i64,
+  pub scope: String,
+}
+
+impl DiscordTokenResponse {
+  /* Students may untick permissions on Discord's consent screen. */
+  pub fn has_scope(&self, scope: &str) -> bool {
+    self.scope.split(' ').any(|s| s == scope)
+  }
+}
+
+make_sqlx_model!{
+  state: Site,
+  table: discord_grants,
+  struct DiscordGrant {
+    #[sqlx_search_as(int4)]
+    id: i32,
+    #[sqlx_search_as(int4)]
+    student_id: i32,
+    discord_user_id: String,
+    sealed_access_token: String,
+    sealed_refresh_token: String,
+    scope: String,
+    expires_at: UtcDateTime,
+    updated_at: UtcDateTime,
+  }
+}
+
+/* Other 400s, like invalid_client, are our own misconfiguration and are reported as Discord errors. */
+fn is_invalid_grant(e: &HttpError) -> bool {
+  e.status == Some(400) && e.message.contains("invalid_grant")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GuildMembership {
+  Joined,
+  AlreadyMember,
+}
+
+impl DiscordGrantHub {
+  /* Codes are single use and expire in minutes, Discord rejects them with invalid_grant. */
+  pub async fn exchange_code(&self, code: &str) -> Result<DiscordTokenResponse> {
+    let response = self.state.discord_oauth_api().post_form("/oauth2/token", &serde_json::json!({
+      "grant_type": "authorization_code",
+      "code": code,
+      "redirect_uri": self.state.settings.discord.redirect_uri(&self.state.settings.checkout_domain),
+    })).await;
+
+    let token: DiscordTokenResponse = match response {
+      Ok(r) => r.json()?,
+      Err(Error::Discord(ref e)) if is_invalid_grant(e) =>
+        return Err(Error::validation("code", "discord authorization expired or was already used")),
+      Err(e) => return Err(e),
+    };
+
+    if !token.has_scope("guilds.join") {
+      return Err(Error::validation("code", "discord authorization is missing the guilds.join permission"));
+    }
+
+    Ok(token)
+  }
+
+  /* One grant per student, linking a different Discord account replaces it. Tokens are stored sealed. */
+  pub async fn store(&self, student_id: i32, discord_user_id: &str, token: &DiscordTokenResponse) -> Result<DiscordGrant> {
+    sqlx::query!(
+      "INSERT INTO discord_grants (student_id, discord_user_id, sealed_access_token, sealed_refresh_token, scope, expires_at, updated_at)
+        VALUES ($1, $2, $3, $4, $5, $6, now())
+        ON CONFLICT (student_id) DO UPDATE SET
+          discord_user_id = EXCLUDED.discord_user_id,
+          sealed_access_token = EXCLUDED.sealed_access_token,
+          sealed_refresh_token = EXCLUDED.sealed_refresh_token,
+          scope = EXCLUDED.scope,
+          expires_at = EXCLUDED.expires_at,
+          updated_at = EXCLUDED.updated_at",
+      student_id,
+      discord_user_id,
+      seal(&self.state, &format!("discord_access_token:{}", student_id), &token.access_token)?,
+      seal(&self.state, &format!("discord_refresh_token:{}", student_id), &token.refresh_token)?,
+      token.scope,
+      Utc::now() + Duration::seconds(token.expires_in),
+    ).execute(&self.state.db).await?;
+
+    Ok(self.select().student_id_eq(&student_id).one().await?)
+  }
+}
+
+impl DiscordGrant {
+  pub fn refresh_token(&self) -> Result<String> {
+    unseal(&self.state, &format!("discord_refresh_token:{}", self.attrs.student_id), &self.attrs.sealed_refresh_token)
+  }
+
+  /* Access tokens last a week. Refreshing also rotates the refresh token, so both are stored again. */
+  pub async fn access_token(&mut self) -> Result<String> {
+    if self.attrs.expires_at > Utc::now() + Duration::minutes(5) {
+      return unseal(&self.state, &format!("discord_access_token:{}", self.attrs.student_id), &self.attrs.sealed_access_token);
+    }
+
+    let response = self.state.discord_oauth_api().post_form("/oauth2/token", &serde_json::json!({
+      "grant_type": "refresh_token",
+      "refresh_token": self.refresh_token()?,
+    })).await;
+
+    let token: DiscordTokenResponse = match response {
+      Ok(r) => r.json()?,
+      Err(Error::Discord(ref e)) if is_invalid_grant(e) =>
+        return Err(Error::validation("discord", "student revoked our access, they need to link discord again")),
+      Err(e) => return Err(e),
+    };
+
+    *self = self.state.discord_grant().store(self.attrs.student_id, &self.attrs.discord_user_id, &token).await?;
+    Ok(token.access_token)
+  }
+
+  /* Discord answers 201 when it added the member and 204 when they were already in the server.
+   * A 403 means our bot lost the permission to add members, not something the student can fix. */
+  pub async fn join_guild(&mut self) -> Result<GuildMembership> {
+    let access_token = self.access_token().await?;
+    let path = format!("/guilds/{}/members/{}", self.state.settings.discord.guild_id, self.attrs.discord_user_id);
+
+    match self.state.discord_api().put(&path, &serde_json::json![{"access_token": access_token}]).await {
+      Ok(r) if r.status == StatusCode::NO_CONTENT => Ok(GuildMembership::AlreadyMember),
+      Ok(_) => Ok(GuildMembership::Joined),
+      Err(Error::Discord(ref e)) if e.status == Some(403) =>
+        Err(Error::validation("discord", "our bot is not allowed to add members to the server")),
+      Err(e) => Err(e),
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn reads_token_responses_and_their_scopes() {
+    let token: DiscordTokenResponse = serde_json::from_str(r#"{
+      "access_token": "6qrZcUqja7812RVdnEKjpzOL4CvHBFG",
+      "token_type": "Bearer",
+      "expires_in": 604800,
+      "refresh_token": "D43f5y0ahjqew82jZ4NViEr2YafMKhue",
+      "scope": "identify email guilds.join"
+    }"#).unwrap();
+
+    assert!(token.has_scope("guilds.join"));
+    assert!(token.has_scope("identify"));
+    assert!(!token.has_scope("guilds"));
+  }
+}
@@ -0,0 +1,63 @@
+use crate::error::Result;
+use super::*;
+
+/* How many euros one unit of a currency was worth from a given date on.
+ * Only used to report in euros, charges and payments keep their own currency. */
+make_sqlx_model!{
+  state: Site,
+  table: exchange_rates,
+  struct ExchangeRate {
+    #[sqlx_search_as(int4)]
+    id: i32,
+    #[sqlx_search_as(currency)]
+    currency: Currency,
+    eur_rate: Decimal,
+    valid_from: UtcDateTime,
+    created_at: UtcDateTime,
+  }
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+pub struct ExchangeRateForm {
+  pub currency: Currency,
+  pub eur_rate: Decimal,
+  pub valid_from: Option<UtcDateTime>,
+}
+
+impl ExchangeRateHub {
+  pub async fn create(&self, form: ExchangeRateForm) -> Result<ExchangeRate> {
+    if form.currency == Currency::Eur {
+      return Err(Error::validation("currency", "euros don't need an exchange rate"));
+    }
+
+    if !form.eur_rate.is_sign_positive() || form.eur_rate.is_zero() {
+      return Err(Error::validation("eur_rate", "must be positive"));
+    }
+
+    Ok(self.insert().use_struct(InsertExchangeRate{
+      currency: form.currency,
+      eur_rate: form.eur_rate,
+      valid_from: form.valid_from.unwrap_or_else(Utc::now),
+      created_at: Utc::now(),
+    }).save().await?)
+  }
+
+  pub async fn rate_at(&self, currency: Currency, at: UtcDateTime) -> Result<Decimal> {
+    if currency == Currency::Eur {
+      return Ok(Decimal::ONE);
+    }
+
+    sqlx::query_scalar!(
+      r#"SELECT eur_rate as "eur_rate!" FROM exchange_rates
+        WHERE currency = $1 AND valid_from <= $2
+        ORDER BY valid_from DESC LIMIT 1"#,
+      currency as Currency,
+      at,
+    ).fetch_optional(&self.state.db).await?
+      .ok_or_else(|| Error::validation("currency", &format!("no exchange rate for {} at {}", currency.code(), at)))
+  }
+
+  pub async fn to_eur(&self, amount: Decimal, currency: Currency, at: UtcDateTime) -> Result<Decimal> {
+    Ok((amount * self.rate_at(currency, at).await?).round_dp(2))
+  }
+}
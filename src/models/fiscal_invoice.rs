@@ -0,0 +1,342 @@
+use crate::error::Result;
+use super::*;
+
+make_sqlx_model!{
+  state: Site,
+  table: fiscal_invoices,
+  struct FiscalInvoice {
+    #[sqlx_search_as(int4)]
+    id: i32,
+    #[sqlx_search_as(varchar)]
+    series: String,
+    number: i32,
+    issued_at: UtcDateTime,
+    #[sqlx_search_as(fiscal_document_kind)]
+    kind: FiscalDocumentKind,
+    #[sqlx_search_as(int4)]
+    rectifies_id: Option<i32>,
+    #[sqlx_search_as(int4)]
+    refund_id: Option<i32>,
+    #[sqlx_search_as(int4)]
+    student_id: Option<i32>,
+    #[sqlx_search_as(int4)]
+    organization_id: Option<i32>,
+    #[sqlx_search_as(int4)]
+    payment_id: Option<i32>,
+    #[sqlx_search_as(int4)]
+    organization_invoice_id: Option<i32>,
+    issuer_name: String,
+    issuer_tax_number: String,
+    issuer_address: String,
+    customer_name: String,
+    customer_tax_number: Option<String>,
+    customer_tax_address: Option<String>,
+    customer_country: String,
+    customer_vat_number: Option<String>,
+    tax_treatment: TaxTreatment,
+    subtotal: Decimal,
+    tax_rate: Decimal,
+    tax_amount: Decimal,
+    total: Decimal,
+    currency: Currency,
+  }
+}
+
+make_sqlx_model!{
+  state: Site,
+  table: fiscal_invoice_lines,
+  struct FiscalInvoiceLine {
+    #[sqlx_search_as(int4)]
+    id: i32,
+    #[sqlx_search_as(int4)]
+    fiscal_invoice_id: i32,
+    charge_kind: String,
+    charge_id: i32,
+    description: String,
+    subtotal: Decimal,
+    tax_amount: Decimal,
+    total: Decimal,
+  }
+}
+
+#[derive(sqlx::Type, Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[sqlx(type_name = "fiscal_document_kind", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum FiscalDocumentKind {
+  Invoice,
+  CreditNote,
+}
+
+/* Who the invoice is addressed to. Snapshotted on the invoice, later changes
+ * to the student or organization must not alter an issued document. */
+#[derive(Debug, Clone)]
+pub struct FiscalCustomer {
+  pub student_id: Option<i32>,
+  pub organization_id: Option<i32>,
+  pub name: String,
+  pub tax_number: Option<String>,
+  pub tax_address: Option<String>,
+  pub country: String,
+  pub vat_number: Option<String>,
+}
+
+impl FiscalCustomer {
+  pub fn from_student(student: &Student) -> Self {
+    Self{
+      student_id: Some(student.attrs.id),
+      organization_id: None,
+      name: student.attrs.full_name.clone(),
+      tax_number: student.attrs.tax_number.clone(),
+      tax_address: student.attrs.tax_address.clone(),
+      country: student.attrs.country.clone(),
+      vat_number: student.attrs.vat_number.clone(),
+    }
+  }
+
+  pub fn from_organization(organization: &Organization) -> Self {
+    Self{
+      student_id: None,
+      organization_id: Some(organization.attrs.id),
+      name: organization.attrs.name.clone(),
+      tax_number: organization.attrs.tax_number.clone(),
+      tax_address: organization.attrs.tax_address.clone(),
+      country: organization.attrs.country.clone(),
+      vat_number: organization.attrs.vat_number.clone(),
+    }
+  }
+}
+
+/* Our prices are final prices, tax included. The taxable base is derived from them. */
+pub fn split_tax(total: Decimal, rate: Decimal) -> (Decimal, Decimal) {
+  let subtotal = (total / (Decimal::ONE + rate)).round_dp(2);
+  (subtotal, total - subtotal)
+}
+
+/* Numbers are taken from fiscal_invoice_series in the same transaction that
+ * stores the document, so a failed issue never burns a number. */
+async fn take_number(tx: &mut sqlx::Transaction<'_, sqlx::Postgres>, series: &str) -> Result<i32> {
+  sqlx::query!(
+    "INSERT INTO fiscal_invoice_series (series, next_number) VALUES ($1, 1) ON CONFLICT (series) DO NOTHING",
+    series,
+  ).execute(&mut *tx).await?;
+
+  Ok(sqlx::query_scalar!(
+    r#"UPDATE fiscal_invoice_series SET next_number = next_number + 1 WHERE series = $1 RETURNING next_number - 1 as "number!""#,
+    series,
+  ).fetch_one(&mut *tx).await?)
+}
+
+impl FiscalInvoiceHub {
+  pub async fn issue(
+    &self,
+    customer: FiscalCustomer,
+    currency: Currency,
+    payment_id: Option<i32>,
+    organization_invoice_id: Option<i32>,
+    charges: &[Box<dyn BillingCharge>],
+  ) -> Result<Option<FiscalInvoice>> {
+    /* Charges in other currencies that got settled along are invoiced with their own payment. */
+    let charges: Vec<&Box<dyn BillingCharge>> = charges.iter().filter(|c| c.currency() == currency).collect();
+    if charges.is_empty() {
+      return Ok(None);
+    }
+
+    let conf = &self.state.settings.invoicing;
+    let tax = conf.assess(&customer.country, customer.vat_number.as_deref());
+    let issued_at = Utc::now();
+    let series = format!("{}{}", conf.series_prefix, issued_at.format("%Y"));
+
+    let lines: Vec<(&Box<dyn BillingCharge>, Decimal, Decimal)> = charges.iter().map(|c| {
+      let (subtotal, tax_amount) = tax.split(c.amount());
+      (*c, subtotal, tax_amount)
+    }).collect();
+
+    let subtotal: Decimal = lines.iter().map(|(_, s, _)| *s).sum();
+    let tax_amount: Decimal = lines.iter().map(|(_, _, t)| *t).sum();
+    let total: Decimal = charges.iter().map(|c| c.amount()).sum();
+
+    let mut tx = self.state.db.begin().await?;
+    let number = take_number(&mut tx, &series).await?;
+
+    let id = sqlx::query_scalar!(
+      "INSERT INTO fiscal_invoices (
+        series, number, issued_at, student_id, organization_id, payment_id, organization_invoice_id,
+        issuer_name, issuer_tax_number, issuer_address,
+        customer_name, customer_tax_number, customer_tax_address, customer_country,
+        customer_vat_number, tax_treatment,
+        subtotal, tax_rate, tax_amount, total, currency
+      ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, $19, $20, $21)
+      RETURNING id",
+      series,
+      number,
+      issued_at,
+      customer.student_id,
+      customer.organization_id,
+      payment_id,
+      organization_invoice_id,
+      conf.issuer_name,
+      conf.issuer_tax_number,
+      conf.issuer_address,
+      customer.name,
+      customer.tax_number,
+      customer.tax_address,
+      customer.country,
+      customer.vat_number,
+      tax.treatment as TaxTreatment,
+      subtotal,
+      tax.rate,
+      tax_amount,
+      total,
+      currency as Currency,
+    ).fetch_one(&mut tx).await?;
+
+    /* Fiscal documents are issued in Spanish whatever the customer's language. */
+    for (charge, line_subtotal, line_tax) in lines.into_iter() {
+      sqlx::query!(
+        "INSERT INTO fiscal_invoice_lines (fiscal_invoice_id, charge_kind, charge_id, description, subtotal, tax_amount, total)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)",
+        id,
+        charge.kind(),
+        charge.charge_id(),
+        charge.description(Locale::Es),
+        line_subtotal,
+        line_tax,
+        charge.amount(),
+      ).execute(&mut tx).await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(Some(self.find(&id).await?))
+  }
+}
+
+impl FiscalInvoice {
+  pub fn full_number(&self) -> String {
+    format!("{}-{:06}", self.attrs.series, self.attrs.number)
+  }
+
+  pub async fn lines(&self) -> sqlx::Result<Vec<FiscalInvoiceLine>> {
+    self.state.fiscal_invoice_line().select()
+      .fiscal_invoice_id_eq(self.id())
+      .order_by(FiscalInvoiceLineOrderBy::Id)
+      .all().await
+  }
+
+  pub fn pdf_filename(&self) -> String {
+    match self.attrs.kind {
+      FiscalDocumentKind::Invoice => format!("factura-{}.pdf", self.full_number()),
+      FiscalDocumentKind::CreditNote => format!("factura-rectificativa-{}.pdf", self.full_number()),
+    }
+  }
+
+  /* Credit notes have their own series, and copy the customer and tax snapshot of the invoice they rectify. */
+  pub async fn issue_credit_note(&self, refund: &Refund) -> Result<FiscalInvoice> {
+    let conf = &self.state.settings.invoicing;
+    let issued_at = Utc::now();
+    let series = format!("{}R{}", conf.series_prefix, issued_at.format("%Y"));
+    let total = refund.attrs.amount * Decimal::NEGATIVE_ONE;
+    let (subtotal, tax_amount) = split_tax(total, self.attrs.tax_rate);
+
+    let mut tx = self.state.db.begin().await?;
+    let number = take_number(&mut tx, &series).await?;
+
+    let id = sqlx::query_scalar!(
+      "INSERT INTO fiscal_invoices (
+        series, number, issued_at, kind, rectifies_id, refund_id,
+        student_id, organization_id, payment_id, organization_invoice_id,
+        issuer_name, issuer_tax_number, issuer_address,
+        customer_name, customer_tax_number, customer_tax_address, customer_country,
+        customer_vat_number, tax_treatment,
+        subtotal, tax_rate, tax_amount, total, currency
+      )
+      SELECT $1, $2, $3, 'credit_note', id, $4,
+        student_id, organization_id, payment_id, organization_invoice_id,
+        issuer_name, issuer_tax_number, issuer_address,
+        customer_name, customer_tax_number, customer_tax_address, customer_country,
+        customer_vat_number, tax_treatment,
+        $5, tax_rate, $6, $7, currency
+      FROM fiscal_invoices WHERE id = $8
+      RETURNING id",
+      series,
+      number,
+      issued_at,
+      refund.attrs.id,
+      subtotal,
+      tax_amount,
+      total,
+      self.attrs.id,
+    ).fetch_one(&mut tx).await?;
+
+    sqlx::query!(
+      "INSERT INTO fiscal_invoice_lines (fiscal_invoice_id, charge_kind, charge_id, description, subtotal, tax_amount, total)
+      VALUES ($1, 'refund', $2, $3, $4, $5, $6)",
+      id,
+      refund.attrs.id,
+      format!("Devolución sobre factura {}: {}", self.full_number(), refund.attrs.reason),
+      subtotal,
+      tax_amount,
+      total,
+    ).execute(&mut tx).await?;
+
+    tx.commit().await?;
+
+    Ok(self.state.fiscal_invoice().find(&id).await?)
+  }
+
+  pub async fn pdf(&self) -> Result<Vec<u8>> {
+    let mut context = tera::Context::new();
+    context.insert("full_number", &self.full_number());
+    context.insert("invoice", &self);
+    context.insert("currency", self.attrs.currency.symbol());
+    context.insert("lines", &self.lines().await?);
+    context.insert("tax_rate_percent", &(self.attrs.tax_rate * Decimal::ONE_HUNDRED).normalize().to_string());
+    context.insert("reverse_charge", &(self.attrs.tax_treatment == TaxTreatment::ReverseCharge));
+    context.insert("credit_note", &(self.attrs.kind == FiscalDocumentKind::CreditNote));
+    let rectified = match self.attrs.rectifies_id {
+      Some(id) => Some(self.state.fiscal_invoice().find(&id).await?.full_number()),
+      None => None,
+    };
+    context.insert("rectifies_number", &rectified);
+    crate::pdf::render("pdfs/fiscal_invoice", &context)
+  }
+
+  /* Derived like receipt tokens, so there's no secret stored next to the invoice. */
+  pub fn access_token(&self) -> String {
+    access_token_for(&self.state, &format!("fiscal_invoice:{}", self.attrs.id))
+  }
+
+  pub fn check_access_token(&self, token: &str) -> Result<()> {
+    check_access_token_for(&self.state, &format!("fiscal_invoice:{}", self.attrs.id), token)
+  }
+}
+
+#[derive(Serialize)]
+pub struct FiscalInvoiceState {
+  pub full_number: String,
+  pub invoice: FiscalInvoice,
+  pub lines: Vec<FiscalInvoiceLine>,
+}
+
+impl FiscalInvoiceState {
+  pub async fn new(invoice: FiscalInvoice) -> Result<FiscalInvoiceState> {
+    Ok(Self{
+      full_number: invoice.full_number(),
+      lines: invoice.lines().await?,
+      invoice,
+    })
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn splits_tax_from_final_prices() {
+    let rate = Decimal::new(21, 2);
+    assert_eq!(split_tax(Decimal::new(121, 0), rate), (Decimal::new(100, 0), Decimal::new(21, 0)));
+    assert_eq!(split_tax(Decimal::new(150, 0), rate), (Decimal::new(12397, 2), Decimal::new(2603, 2)));
+    assert_eq!(split_tax(Decimal::new(100, 0), Decimal::ZERO), (Decimal::new(100, 0), Decimal::ZERO));
+  }
+}
@@ -1,5 +1,25 @@
 use crate::error::Result;
 use super::*;
+use chrono::Duration;
+
+/* Unpaid invoices get a reminder on each of these days after they became due.
+ * Students that haven't paid after suspend_after_days lose access until they do. */
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+pub struct DunningSettings {
+  pub reminder_days: Vec<i64>,
+  pub suspend_after_days: i64,
+  pub payment_link_hours: i64,
+}
+
+impl DunningSettings {
+  pub fn reminders_due(&self, days_overdue: i64) -> i32 {
+    self.reminder_days.iter().filter(|d| **d <= days_overdue).count() as i32
+  }
+
+  pub fn is_final_reminder(&self, reminder_count: i32) -> bool {
+    reminder_count > 0 && reminder_count as usize >= self.reminder_days.len()
+  }
+}
 
 make_sqlx_model!{
   state: Site,
@@ -16,6 +36,8 @@ make_sqlx_model!{
     external_id: String,
     #[sqlx_search_as(decimal)]
     amount: Decimal,
+    #[sqlx_search_as(currency)]
+    currency: Currency,
     description: String,
     url: String,
     #[sqlx_search_as(boolean)]
@@ -25,19 +47,234 @@ make_sqlx_model!{
     #[sqlx_search_as(int4)]
     payment_id: Option<i32>,
     notified_on: Option<UtcDateTime>,
+    reminder_count: i32,
+    due_since: UtcDateTime,
+  }
+}
+
+impl InvoiceHub {
+  /* Run periodically. A failure with one student's invoice doesn't stop the others. */
+  pub async fn process_dunning(&self) -> Result<()> {
+    let open = self.select()
+      .paid_eq(&false)
+      .expired_eq(&false)
+      .order_by(InvoiceOrderBy::Id)
+      .all().await?;
+
+    for invoice in open.into_iter() {
+      let id = invoice.attrs.id;
+      if let Err(e) = invoice.dun().await {
+        rocket::warn!("Dunning failed for invoice {}: {:?}", id, e);
+      }
+    }
+
+    Ok(())
   }
 }
 
 impl Invoice {
-  pub async fn make_payment(&self, clearing_data: Option<&str>) -> Result<Payment> {
-    self.state.payment().insert().use_struct(InsertPayment{
-      student_id: self.attrs.student_id,
-      created_at: Utc::now(),
-      amount: self.attrs.amount,
-      fees: Decimal::ZERO,
-      payment_method: self.attrs.payment_method,
-      clearing_data: clearing_data.unwrap_or("").to_string(),
-      invoice_id: Some(self.attrs.id),
-    }).create_and_pay_invoice().await
+  pub async fn dun(self) -> Result<()> {
+    let settings = &self.state.settings.dunning;
+    let now = Utc::now();
+    let days_overdue = (now - self.attrs.due_since).num_days();
+    let mut student = self.state.student().find(self.student_id()).await?;
+
+    if days_overdue >= settings.suspend_after_days {
+      if student.attrs.delinquent_since.is_none() {
+        student.mark_delinquent().await?;
+      }
+      return Ok(());
+    }
+
+    let due = settings.reminders_due(days_overdue);
+    if due <= self.attrs.reminder_count {
+      return Ok(());
+    }
+
+    let invoice = if now - self.attrs.created_at > Duration::hours(settings.payment_link_hours) {
+      match self.regenerate().await? {
+        Some(fresh) => fresh,
+        None => return Ok(()),
+      }
+    } else {
+      self
+    };
+
+    invoice.send_link(&student, due).await?;
+    Ok(())
+  }
+
+  /* Checkout links stop working after a while. The debt is invoiced again with a fresh link,
+   * and the new invoice keeps the dunning history of the old one.
+   * Nothing is regenerated when the old link turns out to be paid already. */
+  pub async fn regenerate(self) -> Result<Option<Invoice>> {
+    match self.void().await {
+      Err(Error::Validation{ .. }) => return Ok(None),
+      other => other?,
+    }
+
+    let student = self.state.student().find(self.student_id()).await?;
+    let fresh = BillingSummary::new(student).await?
+      .invoice_all_not_invoiced_yet().await?
+      .into_iter()
+      .find(|i| i.attrs.currency == self.attrs.currency);
+
+    let mut invoice = match fresh {
+      Some(i) => i,
+      None => return Ok(None),
+    };
+
+    invoice.keep_dunning_history(&self).await?;
+    Ok(Some(invoice))
+  }
+
+  /* Replacing an invoice doesn't restart the reminders schedule for the debt it was for. */
+  pub async fn keep_dunning_history(&mut self, previous: &Invoice) -> Result<()> {
+    sqlx::query!(
+      "UPDATE invoices SET due_since = $2, reminder_count = $3, notified_on = $4 WHERE id = $1",
+      self.attrs.id,
+      previous.attrs.due_since,
+      previous.attrs.reminder_count,
+      previous.attrs.notified_on,
+    ).execute(&self.state.db).await?;
+
+    self.attrs.due_since = previous.attrs.due_since;
+    self.attrs.reminder_count = previous.attrs.reminder_count;
+    self.attrs.notified_on = previous.attrs.notified_on;
+    Ok(())
+  }
+
+  /* Makes sure the link can't be paid anymore, on the processor first and then here.
+   * A link that was already paid can't be voided, the payment is on its way. */
+  pub async fn void(&self) -> Result<()> {
+    void_on_processor(&self.state, self.attrs.payment_method, &self.attrs.external_id).await?;
+
+    sqlx::query!("UPDATE invoices SET expired = true WHERE id = $1", self.attrs.id)
+      .execute(&self.state.db).await?;
+    Ok(())
+  }
+
+  /* The first email goes out with reminder_count zero, when the invoice is created. */
+  pub async fn send_link(&self, student: &Student, reminder_count: i32) -> Result<()> {
+    let settings = &self.state.settings.dunning;
+    let final_notice = settings.is_final_reminder(reminder_count);
+
+    let mut context = tera::Context::new();
+    context.insert("full_name", &student.attrs.full_name);
+    context.insert("checkout_link", &self.attrs.url);
+    context.insert("billing_link", &student.billing_link());
+    context.insert("amount", &self.attrs.amount);
+    context.insert("currency", self.attrs.currency.symbol());
+    context.insert("reminder_count", &reminder_count);
+    context.insert("final_notice", &final_notice);
+    context.insert("suspend_after_days", &settings.suspend_after_days);
+
+    let subject = student.attrs.locale.text(if final_notice {
+      Text::PaymentFinalNoticeSubject
+    } else if reminder_count > 0 {
+      Text::PaymentReminderSubject
+    } else {
+      Text::PaymentLinkSubject
+    });
+
+    self.state.send_email(Sender::Billing, student.recipient(), subject, "emails/payment_link", &context).await?;
+
+    sqlx::query!(
+      "UPDATE invoices SET notified_on = now(), reminder_count = $2 WHERE id = $1",
+      self.attrs.id,
+      reminder_count,
+    ).execute(&self.state.db).await?;
+
+    Ok(())
+  }
+
+  /* Webhooks and the reconciliation job may both find this invoice unpaid. The payment is stored
+   * and linked with the invoice row locked, so only one of them records it, the other gets None. */
+  pub async fn make_payment(&self, clearing_data: Option<&str>) -> Result<Option<Payment>> {
+    let mut tx = self.state.db.begin().await?;
+    let already_paid = sqlx::query_scalar!(
+      "SELECT payment_id FROM invoices WHERE id = $1 FOR UPDATE",
+      self.attrs.id,
+    ).fetch_one(&mut tx).await?.is_some();
+
+    if already_paid {
+      return Ok(None);
+    }
+
+    let payment_id = sqlx::query_scalar!(
+      "INSERT INTO payments (student_id, created_at, amount, currency, fees, payment_method, clearing_data, invoice_id)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8) RETURNING id",
+      self.attrs.student_id,
+      Utc::now(),
+      self.attrs.amount,
+      self.attrs.currency as Currency,
+      Decimal::ZERO,
+      self.attrs.payment_method as PaymentMethod,
+      clearing_data.unwrap_or(""),
+      self.attrs.id,
+    ).fetch_one(&mut tx).await?;
+
+    sqlx::query!(
+      "UPDATE invoices SET paid = true, payment_id = $2 WHERE id = $1",
+      self.attrs.id,
+      payment_id,
+    ).execute(&mut tx).await?;
+    tx.commit().await?;
+
+    let payment = self.state.payment().find(&payment_id).await?;
+    let settled = payment.settle().await?;
+    payment.issue_and_send_receipt(&settled).await?;
+    Ok(Some(payment))
+  }
+}
+
+/* Shared with organization invoices, which are paid through the same checkout links. */
+pub async fn void_on_processor(site: &Site, payment_method: PaymentMethod, external_id: &str) -> Result<()> {
+  match payment_method {
+    PaymentMethod::Stripe => {
+      #[derive(Deserialize)]
+      struct Session {
+        status: Option<String>,
+      }
+
+      let client = &site.stripe;
+      let session: Session = client.get(&format!("/checkout/sessions/{}", external_id)).await?;
+      match session.status.as_deref() {
+        Some("complete") => return Err(Error::validation("invoice", "was already paid, its payment is being processed")),
+        Some("expired") => {},
+        _ => {
+          let _expired: serde_json::Value = client
+            .post_form(&format!("/checkout/sessions/{}/expire", external_id), serde_json::json!({}))
+            .await?;
+        }
+      }
+    },
+    PaymentMethod::BtcPay => {
+      let remote = btcpay::get_invoice(site, external_id).await?;
+      match remote.status.as_str() {
+        "Settled" | "Processing" => return Err(Error::validation("invoice", "was already paid, its payment is being processed")),
+        "Expired" | "Invalid" => {},
+        _ => btcpay::invalidate_invoice(site, external_id).await?,
+      }
+    },
+  }
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn counts_reminders_due() {
+    let settings = DunningSettings{ reminder_days: vec![1, 3, 7, 14], suspend_after_days: 21, payment_link_hours: 24 };
+    assert_eq!(settings.reminders_due(0), 0);
+    assert_eq!(settings.reminders_due(1), 1);
+    assert_eq!(settings.reminders_due(6), 2);
+    assert_eq!(settings.reminders_due(20), 4);
+    assert!(!settings.is_final_reminder(0));
+    assert!(!settings.is_final_reminder(3));
+    assert!(settings.is_final_reminder(4));
   }
 }
@@ -0,0 +1,309 @@
+use super::*;
+use crate::templating;
+
+/* Spanish is our main language. Anything without a translation falls back to it. */
+#[derive(sqlx::Type, Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, FromFormField)]
+#[sqlx(type_name = "locale", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum Locale {
+  Es,
+  En,
+  Pt,
+}
+
+impl Default for Locale {
+  fn default() -> Locale {
+    Locale::Es
+  }
+}
+
+const PORTUGUESE_SPEAKING: &[&str] = &["BR", "PT", "AO", "MZ", "CV", "GW", "ST", "TL"];
+const SPANISH_SPEAKING: &[&str] = &[
+  "AR", "BO", "CL", "CO", "CR", "CU", "DO", "EC", "ES", "GQ", "GT", "HN",
+  "MX", "NI", "PA", "PE", "PR", "PY", "SV", "UY", "VE", "XX",
+];
+
+impl Locale {
+  pub fn code(self) -> &'static str {
+    match self {
+      Locale::Es => "es",
+      Locale::En => "en",
+      Locale::Pt => "pt",
+    }
+  }
+
+  /* Unknown countries, like the "XX" Cloudflare sends for Tor, get Spanish. */
+  pub fn from_country(country: &str) -> Locale {
+    let country = country.to_uppercase();
+    if PORTUGUESE_SPEAKING.contains(&country.as_str()) {
+      Locale::Pt
+    } else if SPANISH_SPEAKING.contains(&country.as_str()) {
+      Locale::Es
+    } else {
+      Locale::En
+    }
+  }
+
+  /* The first language in an Accept-Language header we have a translation for. */
+  pub fn from_accept_language(header: &str) -> Option<Locale> {
+    header.split(',')
+      .map(|tag| tag.split(';').next().unwrap_or("").trim().to_lowercase())
+      .find_map(|tag| match tag.split('-').next().unwrap_or("") {
+        "es" => Some(Locale::Es),
+        "en" => Some(Locale::En),
+        "pt" => Some(Locale::Pt),
+        _ => None,
+      })
+  }
+
+  pub fn stripe_code(self) -> &'static str {
+    match self {
+      Locale::Es => "es",
+      Locale::En => "en",
+      Locale::Pt => "pt-BR",
+    }
+  }
+
+  /* Translations live under a directory named after the locale, "emails/welcome" becomes "en/emails/welcome". */
+  pub fn template(self, name: &str) -> String {
+    let localized = format!("{}/{}", self.code(), name);
+    if self != Locale::Es && templating::exists(&localized) {
+      localized
+    } else {
+      name.to_string()
+    }
+  }
+
+  pub fn text(self, text: Text) -> &'static str {
+    let (es, en, pt) = match text {
+      Text::DegreeCharge => ("Titulación", "Degree", "Diploma"),
+      Text::SubscriptionCharge => ("Subscripción", "Subscription", "Assinatura"),
+      Text::PaymentEntry => ("Pago", "Payment", "Pagamento"),
+      Text::RefundEntry => ("Devolución", "Refund", "Reembolso"),
+      Text::WelcomeSubject => (
+        "Te damos la bienvenida a DAO Education",
+        "Welcome to DAO Education",
+        "Boas-vindas à DAO Education",
+      ),
+      Text::PaymentLinkSubject => (
+        "Acerca de tu pago a DAO Education",
+        "About your payment to DAO Education",
+        "Sobre o seu pagamento à DAO Education",
+      ),
+      Text::PaymentReminderSubject => (
+        "Recordatorio: tu pago a DAO Education está pendiente",
+        "Reminder: your payment to DAO Education is pending",
+        "Lembrete: o seu pagamento à DAO Education está pendente",
+      ),
+      Text::PaymentFinalNoticeSubject => (
+        "Último aviso: tu pago a DAO Education está pendiente",
+        "Final notice: your payment to DAO Education is pending",
+        "Último aviso: o seu pagamento à DAO Education está pendente",
+      ),
+      Text::PaymentReceiptSubject => (
+        "Recibimos tu pago a DAO Education",
+        "We received your payment to DAO Education",
+        "Recebemos o seu pagamento à DAO Education",
+      ),
+      Text::DegreePaidSubject => (
+        "Tu titulación de DAO Education está paga",
+        "Your DAO Education degree is paid",
+        "O seu diploma da DAO Education está pago",
+      ),
+      Text::WaitlistInvitationSubject => (
+        "Se liberó un lugar para ti en DAO Education",
+        "A seat just opened up for you at DAO Education",
+        "Uma vaga foi liberada para você na DAO Education",
+      ),
+      Text::AccessSuspendedSubject => (
+        "Suspendimos tu acceso a DAO Education",
+        "We suspended your access to DAO Education",
+        "Suspendemos o seu acesso à DAO Education",
+      ),
+      Text::PendingChargesInvoice => ("Cargos pendientes", "Pending charges", "Cobranças pendentes"),
+    };
+
+    match self {
+      Locale::Es => es,
+      Locale::En => en,
+      Locale::Pt => pt,
+    }
+  }
+
+  pub fn invalid_field(self, field: &str, message: &str) -> String {
+    match self {
+      Locale::Es => format!("Dato inválido en {}: {}", field, message),
+      Locale::En => format!("Invalid {}: {}", field, message),
+      Locale::Pt => format!("Dado inválido em {}: {}", field, message),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Text {
+  DegreeCharge,
+  SubscriptionCharge,
+  PaymentEntry,
+  RefundEntry,
+  WelcomeSubject,
+  PaymentLinkSubject,
+  PaymentReminderSubject,
+  PaymentFinalNoticeSubject,
+  PaymentReceiptSubject,
+  DegreePaidSubject,
+  WaitlistInvitationSubject,
+  AccessSuspendedSubject,
+  PendingChargesInvoice,
+}
+
+/* Why a field was rejected. Only the ones students may run into are translated,
+ * anything else stays in english as Untranslated. */
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValidationMessage {
+  InvalidVatNumber,
+  VatNumberNotInVies,
+  CohortIsFull,
+  CohortNotAcceptingEnrollments,
+  CohortEnrollmentClosed,
+  CohortOfAnotherProgram,
+  AlreadyEnrolled,
+  InvitationNoLongerValid,
+  InvalidAccessToken,
+  RefundOutOfRange(Decimal),
+  InvalidEmail,
+  InvalidLength,
+  OutOfRange,
+  Untranslated(String),
+}
+
+impl ValidationMessage {
+  /* Maps the rule names the validator crate reports, like "email" or "range". */
+  pub fn from_rule(code: &str) -> ValidationMessage {
+    match code {
+      "email" => ValidationMessage::InvalidEmail,
+      "length" => ValidationMessage::InvalidLength,
+      "range" => ValidationMessage::OutOfRange,
+      other => ValidationMessage::Untranslated(other.to_string()),
+    }
+  }
+
+  pub fn in_locale(&self, locale: Locale) -> String {
+    let (es, en, pt) = match self {
+      ValidationMessage::InvalidVatNumber => (
+        "el número de IVA europeo no es válido",
+        "invalid EU VAT number",
+        "o número de IVA europeu não é válido",
+      ),
+      ValidationMessage::VatNumberNotInVies => (
+        "el número de IVA no está registrado en VIES",
+        "VAT number is not registered in VIES",
+        "o número de IVA não está registrado no VIES",
+      ),
+      ValidationMessage::CohortIsFull => (
+        "no quedan lugares en esta cohorte",
+        "cohort is full",
+        "não há mais vagas nesta turma",
+      ),
+      ValidationMessage::CohortNotAcceptingEnrollments => (
+        "esta cohorte no acepta inscripciones",
+        "cohort is not accepting enrollments",
+        "esta turma não aceita inscrições",
+      ),
+      ValidationMessage::CohortEnrollmentClosed => (
+        "la inscripción a esta cohorte está cerrada",
+        "enrollment for this cohort is closed",
+        "as inscrições para esta turma estão encerradas",
+      ),
+      ValidationMessage::CohortOfAnotherProgram => (
+        "la cohorte pertenece a otro programa",
+        "cohort belongs to another program",
+        "a turma pertence a outro programa",
+      ),
+      ValidationMessage::AlreadyEnrolled => (
+        "ya estás inscripto en este programa",
+        "student is already enrolled in this program",
+        "você já está inscrito neste programa",
+      ),
+      ValidationMessage::InvitationNoLongerValid => (
+        "esta invitación ya no es válida",
+        "this invitation is no longer valid",
+        "este convite não é mais válido",
+      ),
+      ValidationMessage::InvalidAccessToken => (
+        "el enlace que usaste no es válido",
+        "invalid access token",
+        "o link que você usou não é válido",
+      ),
+      ValidationMessage::InvalidEmail => (
+        "el email no es válido",
+        "invalid email address",
+        "o email não é válido",
+      ),
+      ValidationMessage::InvalidLength => (
+        "el largo no es válido",
+        "invalid length",
+        "o comprimento não é válido",
+      ),
+      ValidationMessage::OutOfRange => (
+        "el valor está fuera de rango",
+        "value is out of range",
+        "o valor está fora do intervalo",
+      ),
+      ValidationMessage::RefundOutOfRange(max) => return match locale {
+        Locale::Es => format!("debe ser mayor a cero y como máximo {}", max),
+        Locale::En => format!("must be more than zero and at most {}", max),
+        Locale::Pt => format!("deve ser maior que zero e no máximo {}", max),
+      },
+      ValidationMessage::Untranslated(message) => return message.clone(),
+    };
+
+    match locale {
+      Locale::Es => es,
+      Locale::En => en,
+      Locale::Pt => pt,
+    }.to_string()
+  }
+}
+
+impl std::fmt::Display for ValidationMessage {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.write_str(&self.in_locale(Locale::En))
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn picks_locale_from_country_and_headers() {
+    assert_eq!(Locale::from_country("br"), Locale::Pt);
+    assert_eq!(Locale::from_country("AR"), Locale::Es);
+    assert_eq!(Locale::from_country("XX"), Locale::Es);
+    assert_eq!(Locale::from_country("US"), Locale::En);
+
+    assert_eq!(Locale::from_accept_language("pt-BR,pt;q=0.9,en;q=0.8"), Some(Locale::Pt));
+    assert_eq!(Locale::from_accept_language("fr-FR, en;q=0.5"), Some(Locale::En));
+    assert_eq!(Locale::from_accept_language("fr"), None);
+  }
+
+  #[test]
+  fn falls_back_to_spanish_templates() {
+    assert_eq!(Locale::En.template("emails/welcome"), "en/emails/welcome");
+    assert_eq!(Locale::Pt.template("emails/access_suspended"), "pt/emails/access_suspended");
+    assert_eq!(Locale::Pt.template("pdfs/receipt"), "pdfs/receipt");
+    assert_eq!(Locale::Es.template("emails/welcome"), "emails/welcome");
+  }
+
+  #[test]
+  fn translates_validation_messages() {
+    assert_eq!(ValidationMessage::CohortIsFull.in_locale(Locale::Pt), "não há mais vagas nesta turma");
+    assert_eq!(ValidationMessage::CohortIsFull.to_string(), "cohort is full");
+    assert_eq!(
+      ValidationMessage::RefundOutOfRange(Decimal::new(70, 0)).in_locale(Locale::Es),
+      "debe ser mayor a cero y como máximo 70"
+    );
+    assert_eq!(ValidationMessage::from_rule("email").in_locale(Locale::Es), "el email no es válido");
+    assert_eq!(ValidationMessage::from_rule("custom").in_locale(Locale::Pt), "custom");
+  }
+}
@@ -1,11 +1,11 @@
 use sqlx_models_derive::make_sqlx_model;
-use crate::TEMPLATES;
 use crate::error::{Result, Error};
 pub use chrono::{DateTime, Date, Utc};
 pub use serde::{Deserialize, Serialize, ser::{Serializer, SerializeStruct}};
 pub use sqlx::types::Decimal;
 use validator::Validate;
 pub use stripe::{PriceId, Price, Customer, CustomerId};
+pub use crate::email::Sender;
 pub use rocket::{
   http::{uri::Path, Status},
   request::{FromRequest, Outcome, Request},
@@ -28,9 +28,60 @@ pub use discord::*;
 pub mod plan;
 pub use plan::*;
 
+pub mod program;
+pub use program::*;
+
+pub mod cohort;
+pub use cohort::*;
+
+pub mod waitlist;
+pub use waitlist::*;
+
+pub mod scholarship;
+pub use scholarship::*;
+
+pub mod organization;
+pub use organization::*;
+
+pub mod organization_invoice;
+pub use organization_invoice::*;
+
+pub mod fiscal_invoice;
+pub use fiscal_invoice::*;
+
+pub mod vat;
+pub use vat::*;
+
+pub mod exchange_rate;
+pub use exchange_rate::*;
+
 pub mod payment;
 pub use payment::*;
 
+pub mod refund;
+pub use refund::*;
+
+pub mod accounting;
+pub use accounting::*;
+
+pub mod report;
+pub use report::*;
+
+pub mod access;
+pub use access::*;
+
+pub mod sent_email;
+pub use sent_email::*;
+
+pub mod locale;
+pub use locale::*;
+
+pub mod reconciliation;
+pub use reconciliation::*;
+
+pub mod course_progress;
+pub use course_progress::*;
+
 pub mod invoice;
 pub use invoice::*;
 
@@ -47,11 +98,20 @@ pub struct PublicStudentForm {
   pub tax_address: Option<String>,
   pub referral_code: Option<String>,
   pub payment_method: PaymentMethod,
+  #[serde(default)]
+  pub program: Program,
+  pub cohort_id: Option<i32>,
+  #[serde(default)]
+  pub scholarship: Option<ScholarshipRequest>,
+  #[serde(default)]
+  pub locale: Option<Locale>,
 }
 
 impl PublicStudentForm {
-  pub fn into_insert_student(self, country: &Country) -> InsertStudent {
-    InsertStudent{
+  pub async fn into_insert_student(self, country: &Country, site: &Site) -> Result<InsertStudent> {
+    let vat_number = VatNumber::validate(site, &country.0, self.tax_number.as_deref()).await?;
+
+    Ok(InsertStudent{
       email: self.email,
       full_name: self.full_name,
       country: country.0.clone(),
@@ -62,61 +122,116 @@ impl PublicStudentForm {
       referral_code: self.referral_code,
       current_subscription_id: None,
       wordpress_user: None,
-      wordpress_initial_password: None,
       discord_user_id: None,
       discord_handle: None,
       discord_verification: None,
       stripe_customer_id: None,
       payment_method: self.payment_method,
-    }
+      organization_id: None,
+      vat_number,
+      delinquent_since: None,
+      locale: self.locale.unwrap_or_else(|| Locale::from_country(&country.0)),
+    })
   }
 }
 
 #[derive(Serialize)]
 pub struct StudentState {
+  pub access_token: String,
   pub discord_verification_link: Option<String>,
   pub discord_handle: Option<String>,
+  pub scholarships: Vec<Scholarship>,
+  pub fiscal_invoices: Vec<FiscalInvoice>,
   pub billing: BillingSummary,
+  pub course_progress: Vec<CourseProgress>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub emails: Option<Vec<SentEmail>>,
 }
 
 impl StudentState {
   pub async fn new(student: Student) -> Result<StudentState> {
     Ok(Self{
+      access_token: student.access_token(),
       discord_verification_link: student.discord_verification_link(),
       discord_handle: student.attrs.discord_handle.clone(),
+      scholarships: student.scholarships().await?,
+      fiscal_invoices: student.fiscal_invoices().await?,
+      course_progress: student.course_progress().await?,
       billing: BillingSummary::new(student).await?,
+      emails: None,
     })
   }
+
+  /* Only admins get to see what we emailed the student. */
+  pub async fn with_email_log(mut self) -> Result<StudentState> {
+    self.emails = Some(self.billing.student.sent_emails().await?);
+    Ok(self)
+  }
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum SignupOutcome {
+  Enrolled(StudentState),
+  Waitlisted { waitlist_entry: WaitlistEntry },
 }
 
 #[rocket::async_trait]
 pub trait BillingCharge: Send + Sync + std::fmt::Debug {
-  fn description(&self) -> String;
+  fn kind(&self) -> &'static str;
+  fn charge_id(&self) -> i32;
+  fn description(&self, locale: Locale) -> String;
   fn created_at(&self) -> UtcDateTime;
   fn amount(&self) -> Decimal;
   fn paid_at(&self) -> Option<UtcDateTime>;
+  fn currency(&self) -> Currency;
+  fn program(&self) -> Program;
+  fn scholarship_id(&self) -> Option<i32>;
   async fn set_paid(&mut self) -> Result<()>;
+  async fn apply_scholarship(&mut self, scholarship: &Scholarship) -> Result<()>;
   fn stripe_price<'a>(&self, prices: &'a StripePlanPrices) -> &'a PriceId;
 }
 
-impl Serialize for dyn BillingCharge {
+/* Charges and history items shown to a student are described in their language, see BillingSummary. */
+pub struct Localized<'a, T: ?Sized>(pub &'a T, pub Locale);
+
+impl<'a> Serialize for Localized<'a, dyn BillingCharge> {
     fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
-        let mut state = serializer.serialize_struct("BillingHistoryItem", 4)?;
-        state.serialize_field("created_at", &self.created_at())?;
-        state.serialize_field("description", &self.description())?;
-        state.serialize_field("amount", &self.amount())?;
-        state.serialize_field("paid_at", &self.paid_at())?;
+        let Localized(charge, locale) = self;
+        let mut state = serializer.serialize_struct("BillingHistoryItem", 5)?;
+        state.serialize_field("created_at", &charge.created_at())?;
+        state.serialize_field("description", &charge.description(*locale))?;
+        state.serialize_field("amount", &charge.amount())?;
+        state.serialize_field("currency", &charge.currency())?;
+        state.serialize_field("paid_at", &charge.paid_at())?;
         state.end()
     }
 }
 
+impl Serialize for dyn BillingCharge {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        Localized(self, Locale::default()).serialize(serializer)
+    }
+}
+
 #[rocket::async_trait]
 impl BillingCharge for Degree {
-  fn description(&self) -> String {
-    "Titulación".to_string()
+  fn kind(&self) -> &'static str {
+    "degree"
+  }
+
+  fn charge_id(&self) -> i32 {
+    self.attrs.id
+  }
+
+  fn description(&self, locale: Locale) -> String {
+    format!("{} {}", locale.text(Text::DegreeCharge), self.attrs.program.name())
   }
 
   fn created_at(&self) -> UtcDateTime {
@@ -127,14 +242,42 @@ impl BillingCharge for Degree {
     self.attrs.price.clone()
   }
 
+  fn currency(&self) -> Currency {
+    self.attrs.currency
+  }
+
   fn paid_at(&self) -> Option<UtcDateTime> {
     self.attrs.paid_at.clone()
   }
 
+  fn program(&self) -> Program {
+    self.attrs.program
+  }
+
+  fn scholarship_id(&self) -> Option<i32> {
+    self.attrs.scholarship_id
+  }
+
   fn stripe_price<'a>(&self, prices: &'a StripePlanPrices) -> &'a PriceId {
     prices.degree
   }
 
+  async fn apply_scholarship(&mut self, scholarship: &Scholarship) -> Result<()> {
+    self.attrs.price = scholarship.discounted_for(self.attrs.subscription_id, self.kind(), self.attrs.id, self.attrs.list_price).await?;
+    self.attrs.scholarship_id = Some(scholarship.attrs.id);
+    sqlx::query!(
+      "UPDATE degrees SET price = $2, scholarship_id = $3 WHERE id = $1",
+      self.attrs.id,
+      self.attrs.price,
+      self.attrs.scholarship_id,
+    ).execute(&self.state.db).await?;
+
+    if self.attrs.price.is_zero() {
+      self.set_paid().await?;
+    }
+    Ok(())
+  }
+
   async fn set_paid(&mut self) -> Result<()> {
     self.attrs.paid_at = Some(Utc::now());
     self.attrs.paid = true;
@@ -143,14 +286,23 @@ impl BillingCharge for Degree {
       self.attrs.id,
       self.attrs.paid_at,
     ).execute(&self.state.db).await?;
+    self.on_paid().await?;
     Ok(())
   }
 }
 
 #[rocket::async_trait]
 impl BillingCharge for Subscription {
-  fn description(&self) -> String {
-    "Subscripción".to_string()
+  fn kind(&self) -> &'static str {
+    "subscription"
+  }
+
+  fn charge_id(&self) -> i32 {
+    self.attrs.id
+  }
+
+  fn description(&self, locale: Locale) -> String {
+    format!("{} {}", locale.text(Text::SubscriptionCharge), self.attrs.program.name())
   }
 
   fn created_at(&self) -> UtcDateTime {
@@ -161,14 +313,42 @@ impl BillingCharge for Subscription {
     self.attrs.price.clone()
   }
 
+  fn currency(&self) -> Currency {
+    self.attrs.currency
+  }
+
   fn paid_at(&self) -> Option<UtcDateTime> {
     self.attrs.paid_at.clone()
   }
 
+  fn program(&self) -> Program {
+    self.attrs.program
+  }
+
+  fn scholarship_id(&self) -> Option<i32> {
+    self.attrs.scholarship_id
+  }
+
   fn stripe_price<'a>(&self, prices: &'a StripePlanPrices) -> &'a PriceId {
     prices.signup
   }
 
+  async fn apply_scholarship(&mut self, scholarship: &Scholarship) -> Result<()> {
+    self.attrs.price = scholarship.discounted_for(self.attrs.id, self.kind(), self.attrs.id, self.attrs.list_price).await?;
+    self.attrs.scholarship_id = Some(scholarship.attrs.id);
+    sqlx::query!(
+      "UPDATE subscriptions SET price = $2, scholarship_id = $3 WHERE id = $1",
+      self.attrs.id,
+      self.attrs.price,
+      self.attrs.scholarship_id,
+    ).execute(&self.state.db).await?;
+
+    if self.attrs.price.is_zero() {
+      self.set_paid().await?;
+    }
+    Ok(())
+  }
+
   async fn set_paid(&mut self) -> Result<()> {
     self.attrs.paid_at = Some(Utc::now());
     self.attrs.paid = true;
@@ -184,33 +364,48 @@ impl BillingCharge for Subscription {
 
 pub trait BillingHistoryItem: Send + Sync + std::fmt::Debug {
   fn date(&self) -> UtcDateTime;
-  fn description(&self) -> String;
+  fn description(&self, locale: Locale) -> String;
   fn amount(&self) -> Decimal;
+  fn currency(&self) -> Currency;
 }
 
-impl Serialize for dyn BillingHistoryItem {
+impl<'a> Serialize for Localized<'a, dyn BillingHistoryItem> {
   fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
   where
       S: Serializer,
   {
-    let mut state = serializer.serialize_struct("BillingHistoryItem", 3)?;
-    state.serialize_field("date", &self.date())?;
-    state.serialize_field("description", &self.description())?;
-    state.serialize_field("amount", &self.amount())?;
+    let Localized(item, locale) = self;
+    let mut state = serializer.serialize_struct("BillingHistoryItem", 4)?;
+    state.serialize_field("date", &item.date())?;
+    state.serialize_field("description", &item.description(*locale))?;
+    state.serialize_field("amount", &item.amount())?;
+    state.serialize_field("currency", &item.currency())?;
     state.end()
   }
 }
 
+impl Serialize for dyn BillingHistoryItem {
+  fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+  where
+      S: Serializer,
+  {
+    Localized(self, Locale::default()).serialize(serializer)
+  }
+}
+
 impl<T: BillingCharge> BillingHistoryItem for T {
   fn date(&self) -> UtcDateTime {
     self.created_at()
   }
-  fn description(&self) -> String {
-    self.description()
+  fn description(&self, locale: Locale) -> String {
+    BillingCharge::description(self, locale)
   }
   fn amount(&self) -> Decimal {
     self.amount() * Decimal::NEGATIVE_ONE
   }
+  fn currency(&self) -> Currency {
+    BillingCharge::currency(self)
+  }
 }
 
 impl BillingHistoryItem for Payment {
@@ -218,28 +413,92 @@ impl BillingHistoryItem for Payment {
     self.attrs.created_at.clone()
   }
 
-  fn description(&self) -> String {
-    format!("Payment #{} via #{:?}", self.attrs.id, self.attrs.payment_method)
+  fn description(&self, locale: Locale) -> String {
+    format!("{} #{} ({})", locale.text(Text::PaymentEntry), self.attrs.id, self.attrs.payment_method.name())
   }
 
   fn amount(&self) -> Decimal {
     self.attrs.amount
   }
+
+  fn currency(&self) -> Currency {
+    self.attrs.currency
+  }
 }
 
-#[derive(Serialize)]
+impl BillingHistoryItem for Refund {
+  fn date(&self) -> UtcDateTime {
+    self.attrs.created_at.clone()
+  }
+
+  fn description(&self, locale: Locale) -> String {
+    format!(
+      "{} #{} ({} #{})",
+      locale.text(Text::RefundEntry),
+      self.attrs.id,
+      locale.text(Text::PaymentEntry),
+      self.attrs.payment_id,
+    )
+  }
+
+  fn amount(&self) -> Decimal {
+    self.attrs.amount * Decimal::NEGATIVE_ONE
+  }
+
+  fn currency(&self) -> Currency {
+    self.attrs.currency
+  }
+}
+
+/* Amounts in different currencies never add up, each one has its own balance and gets its own invoices. */
+#[derive(Debug, Clone, Serialize)]
+pub struct CurrencyBalance {
+  pub currency: Currency,
+  pub balance: Decimal,
+  pub total_charges_not_invoiced_yet: Option<Decimal>,
+}
+
+/* Serialized by hand so descriptions come out in the student's language. */
 pub struct BillingSummary {
-  #[serde(skip_serializing)]
   pub student: student::Student,
-  #[serde(skip_serializing)]
   pub state: Site,
 
-  pub subscription: Subscription,
+  pub subscriptions: Vec<Subscription>,
   pub history: Vec<Box<dyn BillingHistoryItem>>,
   pub unpaid_charges: Vec<Box<dyn BillingCharge>>,
   pub invoices: Vec<Invoice>,
+  pub balances: Vec<CurrencyBalance>,
+  pub currency: Currency,
   pub total_charges_not_invoiced_yet: Option<Decimal>,
   pub balance: Decimal,
+  pub tax: TaxAssessment,
+  pub unpaid_subtotal: Decimal,
+  pub unpaid_tax_amount: Decimal,
+}
+
+impl Serialize for BillingSummary {
+  fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+  where
+      S: Serializer,
+  {
+    let locale = self.student.attrs.locale;
+    let history = self.history.iter().map(|i| Localized(&**i, locale)).collect::<Vec<_>>();
+    let unpaid_charges = self.unpaid_charges.iter().map(|c| Localized(&**c, locale)).collect::<Vec<_>>();
+
+    let mut state = serializer.serialize_struct("BillingSummary", 11)?;
+    state.serialize_field("subscriptions", &self.subscriptions)?;
+    state.serialize_field("history", &history)?;
+    state.serialize_field("unpaid_charges", &unpaid_charges)?;
+    state.serialize_field("invoices", &self.invoices)?;
+    state.serialize_field("balances", &self.balances)?;
+    state.serialize_field("currency", &self.currency)?;
+    state.serialize_field("total_charges_not_invoiced_yet", &self.total_charges_not_invoiced_yet)?;
+    state.serialize_field("balance", &self.balance)?;
+    state.serialize_field("tax", &self.tax)?;
+    state.serialize_field("unpaid_subtotal", &self.unpaid_subtotal)?;
+    state.serialize_field("unpaid_tax_amount", &self.unpaid_tax_amount)?;
+    state.end()
+  }
 }
 
 impl BillingSummary {
@@ -249,12 +508,14 @@ impl BillingSummary {
 
     let site = &student.state;
 
-    let subscription = student.subscription().await?;
+    let subscriptions = student.subscriptions().await?;
 
-    history.push(Box::new(subscription.clone()));
+    for subscription in subscriptions.iter() {
+      history.push(Box::new(subscription.clone()));
 
-    if !subscription.attrs.paid {
-      unpaid_charges.push(Box::new(subscription.clone()));
+      if !subscription.attrs.paid {
+        unpaid_charges.push(Box::new(subscription.clone()));
+      }
     }
 
     let degrees = site.degree().select().student_id_eq(student.id()).all().await?;
@@ -272,94 +533,176 @@ impl BillingSummary {
       history.push(Box::new(payment))
     }
 
-    let balance: Decimal = history.iter().map(|i| i.amount() ).sum();
+    let refunds = site.refund().select().student_id_eq(student.id()).all().await?;
+
+    for refund in refunds.into_iter() {
+      history.push(Box::new(refund))
+    }
+
     let invoices = site.invoice().select()
       .student_id_eq(student.id())
       .paid_eq(&false)
       .expired_eq(&false)
       .all().await?;
-    let invoiced: Decimal = invoices.iter().map(|i| i.attrs.amount ).sum();
-    let invoiceable = (balance * Decimal::NEGATIVE_ONE) - invoiced;
 
-    let total_charges_not_invoiced_yet = if invoiceable.is_sign_positive() {
-      Some( invoiceable )
-    } else {
-      None
+    /* The main currency is the one of the latest enrollment, it always has a balance even if empty. */
+    let currency = subscriptions.iter()
+      .max_by_key(|s| s.attrs.created_at)
+      .map(|s| s.attrs.currency)
+      .unwrap_or_default();
+
+    let mut currencies = vec![currency];
+    for item in history.iter() {
+      if !currencies.contains(&item.currency()) {
+        currencies.push(item.currency());
+      }
+    }
+
+    let balances: Vec<CurrencyBalance> = currencies.into_iter().map(|c| {
+      let balance: Decimal = history.iter().filter(|i| i.currency() == c).map(|i| i.amount() ).sum();
+      let invoiced: Decimal = invoices.iter().filter(|i| i.attrs.currency == c).map(|i| i.attrs.amount ).sum();
+      let invoiceable = (balance * Decimal::NEGATIVE_ONE) - invoiced;
+
+      CurrencyBalance{
+        currency: c,
+        balance,
+        total_charges_not_invoiced_yet: if invoiceable.is_sign_positive() { Some(invoiceable) } else { None },
+      }
+    }).collect();
+
+    /* Sponsored students are invoiced to their organization, so they're taxed as it is. */
+    let tax = match student.attrs.organization_id {
+      Some(id) => site.organization().find(&id).await?.tax_assessment(),
+      None => student.tax_assessment(),
     };
+    let (unpaid_subtotal, unpaid_tax_amount) = unpaid_charges.iter()
+      .filter(|c| c.currency() == currency )
+      .map(|c| tax.split(c.amount()) )
+      .fold((Decimal::ZERO, Decimal::ZERO), |(s, t), (cs, ct)| (s + cs, t + ct));
 
     Ok(BillingSummary {
       state: student.state.clone(),
-      subscription,
+      subscriptions,
       student,
       history,
       unpaid_charges,
       invoices,
-      total_charges_not_invoiced_yet,
-      balance,
+      currency,
+      total_charges_not_invoiced_yet: balances[0].total_charges_not_invoiced_yet,
+      balance: balances[0].balance,
+      balances,
+      tax,
+      unpaid_subtotal,
+      unpaid_tax_amount,
     })
   }
 
-  pub async fn invoice_all_not_invoiced_yet(&self) -> Result<Option<Invoice>> {
-    if self.subscription.attrs.plan_code == PlanCode::Guest {
-      return Ok(None)
-    }
+  pub fn balance_in(&self, currency: Currency) -> Decimal {
+    self.balances.iter()
+      .find(|b| b.currency == currency)
+      .map(|b| b.balance)
+      .unwrap_or(Decimal::ZERO)
+  }
 
-    let amount = match self.total_charges_not_invoiced_yet {
-      Some(a) => a,
-      None => return Ok(None),
-    };
+  pub fn plan_code_for(&self, program: Program) -> PlanCode {
+    self.subscriptions.iter()
+      .find(|s| s.attrs.program == program)
+      .map(|s| s.attrs.plan_code)
+      .unwrap_or(PlanCode::Global)
+  }
 
-    let maybe_url_and_external_id = match self.student.attrs.payment_method {
-      PaymentMethod::Stripe => self.request_on_stripe().await?,
-      PaymentMethod::BtcPay => self.request_on_btcpay().await?,
-    };
+  pub async fn invoice_all_not_invoiced_yet(&self) -> Result<Vec<Invoice>> {
+    let mut invoices = vec![];
 
-    match maybe_url_and_external_id {
-      Some((url, external_id)) => Ok(Some(self.state.invoice().insert().use_struct(InsertInvoice{
-        student_id: self.student.attrs.id,
-        created_at: Utc::now(),
-        payment_method: self.student.attrs.payment_method,
-        external_id: external_id,
-        amount: amount,
-        description: "Cargos pendientes".to_string(),
-        url: url,
-        paid: false,
-        expired: false,
-        payment_id: None,
-        notified_on: None,
-      }).save().await?)),
-      _ => Ok(None),
+    if self.student.attrs.organization_id.is_some() {
+      return Ok(invoices)
     }
+
+    for currency_balance in self.balances.iter() {
+      let currency = currency_balance.currency;
+      let amount = match currency_balance.total_charges_not_invoiced_yet {
+        Some(a) if !a.is_zero() => a,
+        _ => continue,
+      };
+
+      let maybe_url_and_external_id = match self.student.attrs.payment_method {
+        PaymentMethod::Stripe => self.request_on_stripe(currency).await?,
+        PaymentMethod::BtcPay => self.request_on_btcpay(currency, amount).await?,
+      };
+
+      if let Some((url, external_id)) = maybe_url_and_external_id {
+        invoices.push(self.state.invoice().insert().use_struct(InsertInvoice{
+          student_id: self.student.attrs.id,
+          created_at: Utc::now(),
+          payment_method: self.student.attrs.payment_method,
+          external_id: external_id,
+          amount: amount,
+          currency: currency,
+          description: self.student.attrs.locale.text(Text::PendingChargesInvoice).to_string(),
+          url: url,
+          paid: false,
+          expired: false,
+          payment_id: None,
+          notified_on: None,
+          reminder_count: 0,
+          due_since: Utc::now(),
+        }).save().await?);
+      }
+    }
+
+    Ok(invoices)
   }
 
   /* Apply payments denormalizes the payment status from all outstanding charges
    * so that we know what to invoice. It may be the case that a customer payment
-   * cannot cover the full of their debt so they need to top up again */
-  pub async fn sync_paid_status(mut self) -> Result<()> {
+   * cannot cover the full of their debt so they need to top up again.
+   * Payments only cover charges in their same currency. */
+  pub async fn sync_paid_status(self) -> Result<Vec<Box<dyn BillingCharge>>> {
+    let mut settled = vec![];
+
     if self.unpaid_charges.is_empty() {
-      return Ok(())
+      return Ok(settled)
+    }
+
+    let balances = self.balances;
+    let balance_in = |currency: Currency| balances.iter()
+      .find(|b| b.currency == currency)
+      .map(|b| b.balance)
+      .unwrap_or(Decimal::ZERO);
+
+    let mut unsynced: std::collections::HashMap<Currency, Decimal> = std::collections::HashMap::new();
+    for charge in self.unpaid_charges.iter() {
+      *unsynced.entry(charge.currency()).or_insert(Decimal::ZERO) += charge.amount();
     }
 
-    let mut unsynced: Decimal = self.unpaid_charges.iter().map(|c| c.amount() ).sum(); // - 120
+    let mut stalled: Vec<Currency> = vec![];
 
-    for charge in self.unpaid_charges.iter_mut() {
-      if (unsynced - charge.amount()) * Decimal::NEGATIVE_ONE > self.balance {
-        break;
+    for mut charge in self.unpaid_charges.into_iter() {
+      let currency = charge.currency();
+      let pending = unsynced[&currency];
+
+      if stalled.contains(&currency) {
+        continue;
+      }
+
+      if (pending - charge.amount()) * Decimal::NEGATIVE_ONE > balance_in(currency) {
+        stalled.push(currency);
+        continue;
       }
 
       charge.set_paid().await?;
-      unsynced -= charge.amount();
+      unsynced.insert(currency, pending - charge.amount());
+      settled.push(charge);
     }
 
-    Ok(())
+    Ok(settled)
   }
 
-  async fn request_on_stripe(&self) -> Result<Option<(String, String)>> {
+  async fn request_on_stripe(&self, currency: Currency) -> Result<Option<(String, String)>> {
     use serde_json::json;
     pub use stripe::{CheckoutSession, Subscription, ListSubscriptions, SubscriptionStatusFilter};
 
     let client = &self.state.stripe;
-    let prices = self.state.settings.stripe_prices.by_plan_code(self.subscription.attrs.plan_code);
     let customer_id: CustomerId = self.student.get_or_create_stripe_customer_id(&client).await?;
 
     let _subscribed = Subscription::list(client, ListSubscriptions{
@@ -368,8 +711,32 @@ impl BillingSummary {
       ..ListSubscriptions::new()
     }).await?.total_count.unwrap_or(0) > 0;
 
-    let line_items: Vec<&PriceId> = self.unpaid_charges.iter()
-      .map(|i| i.stripe_price(&prices) ).collect();
+    let charges: Vec<&Box<dyn BillingCharge>> = self.unpaid_charges.iter()
+      .filter(|i| i.currency() == currency && !i.amount().is_zero() )
+      .collect();
+
+    let tax_amount: Decimal = charges.iter().map(|i| self.tax.split(i.amount()).1 ).sum();
+
+    /* Configured prices are in euros and at list price, so discounted or local currency
+     * charges are priced inline. */
+    let line_items: Vec<serde_json::Value> = charges.iter()
+      .map(|i| {
+        if i.scholarship_id().is_some() || currency != Currency::Eur {
+          json![{
+            "quantity": 1,
+            "price_data": {
+              "currency": currency,
+              "unit_amount": (i.amount() * Decimal::ONE_HUNDRED).round().to_string(),
+              "tax_behavior": "inclusive",
+              "product_data": { "name": i.description(self.student.attrs.locale), "description": self.tax.note(i.amount(), currency) },
+            }
+          }]
+        } else {
+          let prices = self.state.settings.stripe_prices.get(i.program())
+            .by_plan_code(self.plan_code_for(i.program()));
+          json![{"quantity": 1, "price": i.stripe_price(&prices).clone()}]
+        }
+      }).collect();
 
     let stripe_session : CheckoutSession = client.post_form("/checkout/sessions", json![{
       "success_url": self.state.settings.payment_success_redirect.clone(),
@@ -377,52 +744,101 @@ impl BillingSummary {
       "customer": customer_id,
       "payment_method_types": ["card"],
       "mode": "payment",
-      "line_items": line_items.into_iter().map(|i| json![{"quantity": 1, "price": i.clone()}]).collect::<Vec<serde_json::Value>>(),
+      "locale": self.student.attrs.locale.stripe_code(),
+      "line_items": line_items,
+      "metadata": {
+        "tax_treatment": self.tax.treatment,
+        "tax_rate": self.tax.rate.to_string(),
+        "tax_amount": tax_amount.to_string(),
+      },
     }])
     .await?;
 
     Ok(Some((stripe_session.url, stripe_session.id.to_string())))
   }
 
-  async fn request_on_btcpay(&self) -> Result<Option<(String, String)>> {
-    let total = self.total_charges_not_invoiced_yet
-      .ok_or(Error::validation("amount","Cannot request empty amount"))?;
-
-    let invoice: btcpay::Invoice = ureq::post(&format!(
-        "{}/api/v1/stores/{}/invoices",
-        self.state.settings.btcpay.base_url,
-        self.state.settings.btcpay.store_id,
-      ))
-      .set("Authorization", &format!("token {}", self.state.settings.btcpay.api_key))
-      .send_json(serde_json::to_value(btcpay::InvoiceForm{
-        amount: total,
-        currency: Currency::Eur,
-        checkout: btcpay::InvoiceFormCheckout{ redirectURL: self.state.settings.payment_success_redirect.clone() }
-      })?)?
-      .into_json()?;
+  async fn request_on_btcpay(&self, currency: Currency, amount: Decimal) -> Result<Option<(String, String)>> {
+    let invoice = btcpay::request_invoice(&self.state, amount, currency).await?;
 
     Ok(Some((invoice.checkout_link, invoice.id)))
   }
 }
 
-#[derive(sqlx::Type, Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[derive(sqlx::Type, Debug, Clone, Copy, PartialEq, Deserialize, Serialize, FromFormField)]
 #[sqlx(type_name = "payment_method", rename_all = "lowercase")]
 pub enum PaymentMethod {
   Stripe,
   BtcPay,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+impl PaymentMethod {
+  pub fn name(&self) -> &'static str {
+    match self {
+      PaymentMethod::Stripe => "Stripe",
+      PaymentMethod::BtcPay => "BTCPay",
+    }
+  }
+}
+
+#[derive(sqlx::Type, Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize, FromFormField)]
+#[sqlx(type_name = "currency", rename_all = "lowercase")]
 #[serde(rename_all = "lowercase")]
 pub enum Currency {
   Eur,
+  Usd,
+  Ars,
+  Brl,
+  Mxn,
   Btc,
 }
 
+impl Default for Currency {
+  fn default() -> Self {
+    Currency::Eur
+  }
+}
+
+impl Currency {
+  pub fn code(&self) -> &'static str {
+    match self {
+      Currency::Eur => "EUR",
+      Currency::Usd => "USD",
+      Currency::Ars => "ARS",
+      Currency::Brl => "BRL",
+      Currency::Mxn => "MXN",
+      Currency::Btc => "BTC",
+    }
+  }
+
+  pub fn symbol(&self) -> &'static str {
+    match self {
+      Currency::Eur => "€",
+      Currency::Usd => "US$",
+      Currency::Brl => "R$",
+      other => other.code(),
+    }
+  }
+}
+
 pub struct Country(pub String);
 
 impl Country {
-  pub fn plan(&self) -> Plan {
+  pub fn plan(&self, program: Program) -> Plan {
+    SiteSettings::default().pricing.get(program).by_code(self.plan_code()).in_currency(self.currency())
+  }
+
+  /* The currency we'd like to charge in. Plans without a local price fall back to euros. */
+  pub fn currency(&self) -> Currency {
+    match self.0.as_str() {
+      "AR" => Currency::Ars,
+      "BR" => Currency::Brl,
+      "MX" => Currency::Mxn,
+      "US" => Currency::Usd,
+      _ => Currency::Eur,
+    }
+  }
+
+  pub fn plan_code(&self) -> PlanCode {
     let latam = vec![
       "AR", "BH", "BO", "BR", "BZ", "CL", "CO", "CR", "EC", "FK", "GF", "GY",
       "GT", "HN", "MX", "NI", "PA", "PY", "PE", "SR", "SV", "UY", "VE",
@@ -434,14 +850,12 @@ impl Country {
       "SI", "ES", "SE", "GB", 
     ];
 
-    let plans = SiteSettings::default().pricing;
-
     if latam.contains(&self.0.as_str()) {
-      plans.latam
+      PlanCode::Latam
     } else if europe.contains(&self.0.as_str()) {
-      plans.europe
+      PlanCode::Europe
     } else {
-      plans.global
+      PlanCode::Global
     }
   }
 }
@@ -451,12 +865,54 @@ pub struct DiscordSettings {
   pub guild_id: String,
   pub bot_secret_token: String,
   pub client_id: String,
+  pub client_secret: String,
   pub student_role_id: String,
+  pub program_role_ids: Programs<String>,
+  #[serde(default = "DiscordSettings::default_api_url")]
+  pub api_url: String,
+}
+
+impl DiscordSettings {
+  fn default_api_url() -> String {
+    "https://discord.com/api/v9".to_string()
+  }
+
+  /* Has to be registered in the Discord application, and match exactly when exchanging codes. */
+  pub fn redirect_uri(&self, checkout_domain: &str) -> String {
+    format!("{}/discord-success", checkout_domain)
+  }
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+pub struct InvoicingSettings {
+  pub issuer_name: String,
+  pub issuer_tax_number: String,
+  pub issuer_address: String,
+  pub issuer_country: String,
+  pub series_prefix: String,
+  pub default_tax_rate: Decimal,
+  pub tax_rates: std::collections::HashMap<String, Decimal>,
+  pub vat_check_url: Option<String>,
+}
+
+impl InvoicingSettings {
+  pub fn tax_rate_for(&self, country: &str) -> Decimal {
+    self.tax_rates.get(country).cloned().unwrap_or(self.default_tax_rate)
+  }
 }
 
 #[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
 pub struct SendinblueSettings {
   pub api_key: String,
+  pub webhooks_secret: String,
+  #[serde(default = "SendinblueSettings::default_api_url")]
+  pub api_url: String,
+}
+
+impl SendinblueSettings {
+  fn default_api_url() -> String {
+    "https://api.sendinblue.com/v3".to_string()
+  }
 }
 
 #[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
@@ -465,6 +921,20 @@ pub struct WordpressSettings {
   pub user: String,
   pub pass: String,
   pub student_group_id: i32,
+  pub program_group_ids: Programs<i32>,
+  #[serde(default)]
+  pub degree_courses: Option<Programs<Vec<i32>>>,
+}
+
+impl WordpressSettings {
+  /* The REST API lives at /wp-json/ under the site root. */
+  pub fn site_url(&self) -> &str {
+    self.api_url.trim_end_matches('/').trim_end_matches("/wp-json")
+  }
+
+  pub fn lost_password_url(&self) -> String {
+    format!("{}/wp-login.php?action=lostpassword", self.site_url())
+  }
 }
 
 #[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
@@ -476,13 +946,21 @@ pub struct BtcpaySettings {
 }
 
 #[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+/* Before there were several programs these were a single table with "fzth" in every key.
+ * Old configs keep working by moving that table under [global.stripe_prices.zero_to_hero]. */
 pub struct StripePrices {
-  pub global_fzth_signup: PriceId,
-  pub global_fzth_degree: PriceId,
-  pub latam_fzth_signup: PriceId,
-  pub latam_fzth_degree: PriceId,
-  pub europe_fzth_signup: PriceId,
-  pub europe_fzth_degree: PriceId,
+  #[serde(alias = "global_fzth_signup")]
+  pub global_signup: PriceId,
+  #[serde(alias = "global_fzth_degree")]
+  pub global_degree: PriceId,
+  #[serde(alias = "latam_fzth_signup")]
+  pub latam_signup: PriceId,
+  #[serde(alias = "latam_fzth_degree")]
+  pub latam_degree: PriceId,
+  #[serde(alias = "europe_fzth_signup")]
+  pub europe_signup: PriceId,
+  #[serde(alias = "europe_fzth_degree")]
+  pub europe_degree: PriceId,
 }
 
 #[derive(Debug, PartialEq)]
@@ -494,12 +972,12 @@ pub struct StripePlanPrices<'a> {
 impl StripePrices {
   pub async fn validate_all(&self, client: &stripe::Client) -> Result<()> {
     let prices = vec![
-      &self.global_fzth_signup,
-      &self.global_fzth_degree,
-      &self.latam_fzth_signup,
-      &self.latam_fzth_degree,
-      &self.europe_fzth_signup,
-      &self.europe_fzth_degree,
+      &self.global_signup,
+      &self.global_degree,
+      &self.latam_signup,
+      &self.latam_degree,
+      &self.europe_signup,
+      &self.europe_degree,
     ];
     for price in prices {
       Price::retrieve(client, price, &[]).await
@@ -511,21 +989,84 @@ impl StripePrices {
   fn by_plan_code<'a>(&'a self, code: PlanCode) -> StripePlanPrices<'a> {
     match code {
       PlanCode::Europe => StripePlanPrices{
-        signup: &self.europe_fzth_signup,
-        degree: &self.europe_fzth_degree,
+        signup: &self.europe_signup,
+        degree: &self.europe_degree,
       },
       PlanCode::Latam => StripePlanPrices{
-        signup: &self.latam_fzth_signup,
-        degree: &self.latam_fzth_degree,
+        signup: &self.latam_signup,
+        degree: &self.latam_degree,
       },
       _ => StripePlanPrices {
-        signup: &self.global_fzth_signup,
-        degree: &self.global_fzth_degree,
+        signup: &self.global_signup,
+        degree: &self.global_degree,
       }
     }
   }
 }
 
+/* Links we email out carry an HMAC of what they give access to, so there's no token to store.
+ * Tokens are compared in constant time. */
+fn access_mac(site: &Site, subject: &str) -> hmac::Hmac<sha2::Sha256> {
+  use hmac::{Hmac, Mac, NewMac};
+  let mut mac = Hmac::<sha2::Sha256>::new_from_slice(site.settings.secret_key.as_bytes())
+    .expect("HMAC can take a key of any size");
+  mac.update(subject.as_bytes());
+  mac
+}
+
+pub fn access_token_for(site: &Site, subject: &str) -> String {
+  use hmac::Mac;
+  hex::encode(access_mac(site, subject).finalize().into_bytes())
+}
+
+pub fn check_access_token_for(site: &Site, subject: &str, token: &str) -> Result<()> {
+  use hmac::Mac;
+  hex::decode(token).ok()
+    .and_then(|tag| access_mac(site, subject).verify(&tag).ok())
+    .ok_or_else(|| Error::invalid("token", ValidationMessage::InvalidAccessToken))
+}
+
+/* Third party credentials we must keep, like Discord tokens, are stored encrypted with AES-256-GCM
+ * under a key derived from secret_key. The subject goes in as associated data, so a sealed value
+ * can't be moved to another row. Stored as hex, nonce first. */
+fn sealing_key(site: &Site) -> ring::aead::LessSafeKey {
+  use hmac::Mac;
+  use ring::aead::{LessSafeKey, UnboundKey, AES_256_GCM};
+  let derived = access_mac(site, "sealing_key").finalize().into_bytes();
+  LessSafeKey::new(UnboundKey::new(&AES_256_GCM, &derived).expect("HMAC-SHA256 output is an AES-256 key"))
+}
+
+pub fn seal(site: &Site, subject: &str, secret: &str) -> Result<String> {
+  use ring::aead::{Aad, Nonce, NONCE_LEN};
+  use ring::rand::{SecureRandom, SystemRandom};
+
+  let mut nonce = [0u8; NONCE_LEN];
+  SystemRandom::new().fill(&mut nonce).map_err(|_| Error::Sealing)?;
+  let mut sealed = secret.as_bytes().to_vec();
+  sealing_key(site)
+    .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(subject.as_bytes()), &mut sealed)
+    .map_err(|_| Error::Sealing)?;
+
+  Ok(hex::encode([&nonce[..], &sealed[..]].concat()))
+}
+
+pub fn unseal(site: &Site, subject: &str, sealed: &str) -> Result<String> {
+  use ring::aead::{Aad, Nonce, NONCE_LEN};
+
+  let bytes = hex::decode(sealed).map_err(|_| Error::Sealing)?;
+  if bytes.len() < NONCE_LEN {
+    return Err(Error::Sealing);
+  }
+  let (nonce, ciphertext) = bytes.split_at(NONCE_LEN);
+  let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(|_| Error::Sealing)?;
+  let mut opened = ciphertext.to_vec();
+  let secret = sealing_key(site)
+    .open_in_place(nonce, Aad::from(subject.as_bytes()), &mut opened)
+    .map_err(|_| Error::Sealing)?;
+
+  String::from_utf8(secret.to_vec()).map_err(|_| Error::Sealing)
+}
+
 pub fn gen_passphrase() -> String {
   use chbs::{config::BasicConfig, prelude::*};
   let mut config = BasicConfig::default();
@@ -607,4 +1148,44 @@ pub mod btcpay {
     pub currency: Currency,
     pub checkout: InvoiceFormCheckout
   }
+
+  pub async fn request_invoice(site: &Site, amount: Decimal, currency: Currency) -> Result<Invoice> {
+    site.btcpay_api().post("/invoices", &InvoiceForm{
+      amount: amount,
+      currency: currency,
+      checkout: InvoiceFormCheckout{ redirectURL: site.settings.payment_success_redirect.clone() }
+    }).await?.json()
+  }
+
+  /* As listed by the Greenfield API, we only care about settled ones. */
+  #[derive(Debug, Clone, Deserialize, Serialize)]
+  #[serde(rename_all = "camelCase")]
+  pub struct InvoiceData {
+    pub id: String,
+    pub status: String,
+    pub amount: Decimal,
+    pub currency: String,
+    pub created_time: i64,
+  }
+
+  impl InvoiceData {
+    pub fn is_settled(&self) -> bool {
+      self.status == "Settled"
+    }
+  }
+
+  pub async fn get_invoice(site: &Site, invoice_id: &str) -> Result<InvoiceData> {
+    site.btcpay_api().get(&format!("/invoices/{}", invoice_id)).await?.json()
+  }
+
+  pub async fn invalidate_invoice(site: &Site, invoice_id: &str) -> Result<()> {
+    site.btcpay_api()
+      .post(&format!("/invoices/{}/status", invoice_id), &serde_json::json!({"status": "Invalid"}))
+      .await?;
+    Ok(())
+  }
+
+  pub async fn list_invoices(site: &Site, since: UtcDateTime) -> Result<Vec<InvoiceData>> {
+    site.btcpay_api().get(&format!("/invoices?startDate={}", since.timestamp())).await?.json()
+  }
 }
@@ -0,0 +1,288 @@
+use crate::error::Result;
+use super::*;
+
+make_sqlx_model!{
+  state: Site,
+  table: organizations,
+  struct Organization {
+    #[sqlx_search_as(int4)]
+    id: i32,
+    name: String,
+    created_at: UtcDateTime,
+    billing_contact_name: String,
+    billing_email: String,
+    country: String,
+    tax_number: Option<String>,
+    tax_address: Option<String>,
+    payment_method: PaymentMethod,
+    #[sqlx_search_as(varchar)]
+    stripe_customer_id: Option<String>,
+    vat_number: Option<String>,
+  }
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize, Validate)]
+pub struct OrganizationForm {
+  pub name: String,
+  pub billing_contact_name: String,
+  #[validate(email)]
+  pub billing_email: String,
+  pub country: String,
+  pub tax_number: Option<String>,
+  pub tax_address: Option<String>,
+  pub payment_method: PaymentMethod,
+}
+
+impl OrganizationForm {
+  pub async fn into_insert_organization(self, site: &Site) -> Result<InsertOrganization> {
+    self.validate()?;
+    let vat_number = VatNumber::validate(site, &self.country, self.tax_number.as_deref()).await?;
+
+    Ok(InsertOrganization{
+      name: self.name,
+      created_at: Utc::now(),
+      billing_contact_name: self.billing_contact_name,
+      billing_email: self.billing_email,
+      country: self.country,
+      tax_number: self.tax_number,
+      tax_address: self.tax_address,
+      payment_method: self.payment_method,
+      stripe_customer_id: None,
+      vat_number,
+    })
+  }
+}
+
+impl Organization {
+  pub fn recipient(&self) -> Recipient {
+    Recipient::new(&self.attrs.billing_email, &self.attrs.billing_contact_name, Locale::from_country(&self.attrs.country))
+  }
+
+  pub fn tax_assessment(&self) -> TaxAssessment {
+    self.state.settings.invoicing.assess(&self.attrs.country, self.attrs.vat_number.as_deref())
+  }
+
+  pub async fn students(&self) -> sqlx::Result<Vec<Student>> {
+    self.state.student().select()
+      .organization_id_eq(&Some(self.attrs.id))
+      .order_by(StudentOrderBy::Id)
+      .all().await
+  }
+
+  pub async fn invoices(&self) -> sqlx::Result<Vec<OrganizationInvoice>> {
+    self.state.organization_invoice().select()
+      .organization_id_eq(self.id())
+      .order_by(OrganizationInvoiceOrderBy::Id)
+      .all().await
+  }
+
+  /* Sponsored students stop getting their own payment links, the organization pays for them.
+   * Links the student is paying right now can't be voided, so they can't be sponsored until that settles.
+   * A student sponsored by another organization has to be released by it first. */
+  pub async fn sponsor(&self, student: &mut Student) -> Result<()> {
+    match student.attrs.organization_id {
+      Some(id) if id == self.attrs.id => return Ok(()),
+      Some(_) => return Err(Error::validation("student_id", "student is sponsored by another organization")),
+      None => {},
+    }
+
+    student.void_open_invoices().await?;
+
+    sqlx::query!(
+      "UPDATE students SET organization_id = $2 WHERE id = $1",
+      student.attrs.id,
+      self.attrs.id,
+    ).execute(&self.state.db).await?;
+
+    student.attrs.organization_id = Some(self.attrs.id);
+    Ok(())
+  }
+
+  /* Open organization invoices with a line for the student are voided and issued again without it,
+   * so the organization and the student can't both pay the same charges. */
+  pub async fn stop_sponsoring(&self, student: &mut Student) -> Result<()> {
+    if student.attrs.organization_id != Some(self.attrs.id) {
+      return Err(Error::validation("student_id", "student is not sponsored by this organization"));
+    }
+
+    let covering = self.state.organization_invoice().open_covering(self.attrs.id, student.attrs.id).await?;
+    for invoice in covering.iter() {
+      invoice.void().await?;
+    }
+
+    sqlx::query!("UPDATE students SET organization_id = NULL WHERE id = $1", student.attrs.id)
+      .execute(&self.state.db).await?;
+    student.attrs.organization_id = None;
+
+    if !covering.is_empty() {
+      self.invoice_all_not_invoiced_yet().await?;
+    }
+
+    BillingSummary::new(student.clone()).await?.invoice_all_not_invoiced_yet().await?;
+    Ok(())
+  }
+
+  /* Issues one invoice per currency covering what every sponsored student owes and is not
+   * covered by an open organization invoice yet. Each student gets its own line. */
+  pub async fn invoice_all_not_invoiced_yet(&self) -> Result<Vec<OrganizationInvoice>> {
+    let mut lines_by_currency: Vec<(Currency, Vec<(Student, Decimal)>)> = vec![];
+
+    for student in self.students().await? {
+      let billing = BillingSummary::new(student).await?;
+      for currency_balance in billing.balances.iter() {
+        let currency = currency_balance.currency;
+        let owed = currency_balance.balance * Decimal::NEGATIVE_ONE;
+        let invoiced = self.state.organization_invoice().open_amount_for(billing.student.attrs.id, currency).await?;
+        let invoiceable = owed - invoiced;
+
+        if invoiceable <= Decimal::ZERO {
+          continue;
+        }
+
+        match lines_by_currency.iter_mut().find(|(c, _)| *c == currency) {
+          Some((_, lines)) => lines.push((billing.student.clone(), invoiceable)),
+          None => lines_by_currency.push((currency, vec![(billing.student.clone(), invoiceable)])),
+        }
+      }
+    }
+
+    let mut invoices = vec![];
+    for (currency, lines) in lines_by_currency.iter() {
+      invoices.push(self.invoice_lines(*currency, lines).await?);
+    }
+
+    Ok(invoices)
+  }
+
+  async fn invoice_lines(&self, currency: Currency, lines: &[(Student, Decimal)]) -> Result<OrganizationInvoice> {
+    let amount: Decimal = lines.iter().map(|(_, a)| *a).sum();
+
+    let (url, external_id) = match self.attrs.payment_method {
+      PaymentMethod::Stripe => self.request_on_stripe(currency, lines).await?,
+      PaymentMethod::BtcPay => {
+        let invoice = btcpay::request_invoice(&self.state, amount, currency).await?;
+        (invoice.checkout_link, invoice.id)
+      }
+    };
+
+    let invoice = self.state.organization_invoice().insert().use_struct(InsertOrganizationInvoice{
+      organization_id: self.attrs.id,
+      created_at: Utc::now(),
+      payment_method: self.attrs.payment_method,
+      external_id,
+      amount,
+      currency,
+      description: format!("Cargos pendientes de {} estudiantes", lines.len()),
+      url,
+      paid: false,
+      expired: false,
+      paid_at: None,
+    }).save().await?;
+
+    for (student, line_amount) in lines.iter() {
+      self.state.organization_invoice_item().insert().use_struct(InsertOrganizationInvoiceItem{
+        organization_invoice_id: invoice.attrs.id,
+        student_id: student.attrs.id,
+        description: format!("Cargos pendientes de {}", student.attrs.full_name),
+        amount: *line_amount,
+      }).save().await?;
+    }
+
+    let mut context = tera::Context::new();
+    context.insert("full_name", &self.attrs.billing_contact_name);
+    context.insert("checkout_link", &invoice.attrs.url);
+    let to = self.recipient();
+    let subject = to.locale.text(Text::PaymentLinkSubject);
+    self.state.send_email(
+      Sender::Billing,
+      to,
+      subject,
+      "emails/payment_link",
+      &context,
+    ).await?;
+
+    Ok(invoice)
+  }
+
+  async fn request_on_stripe(&self, currency: Currency, lines: &[(Student, Decimal)]) -> Result<(String, String)> {
+    use serde_json::json;
+    use stripe::CheckoutSession;
+
+    let client = &self.state.stripe;
+    let customer_id = self.get_or_create_stripe_customer_id(client).await?;
+    let tax = self.tax_assessment();
+    let tax_amount: Decimal = lines.iter().map(|(_, amount)| tax.split(*amount).1).sum();
+
+    let line_items: Vec<serde_json::Value> = lines.iter().map(|(student, amount)| json![{
+      "quantity": 1,
+      "price_data": {
+        "currency": currency,
+        "unit_amount": (*amount * Decimal::ONE_HUNDRED).round().to_string(),
+        "tax_behavior": "inclusive",
+        "product_data": {
+          "name": format!("DAO Education - {}", student.attrs.full_name),
+          "description": tax.note(*amount, currency),
+        },
+      }
+    }]).collect();
+
+    let stripe_session : CheckoutSession = client.post_form("/checkout/sessions", json![{
+      "success_url": self.state.settings.payment_success_redirect.clone(),
+      "cancel_url": self.state.settings.payment_error_redirect.clone(),
+      "customer": customer_id,
+      "payment_method_types": ["card"],
+      "mode": "payment",
+      "line_items": line_items,
+      "metadata": {
+        "tax_treatment": tax.treatment,
+        "tax_rate": tax.rate.to_string(),
+        "tax_amount": tax_amount.to_string(),
+      },
+    }])
+    .await?;
+
+    Ok((stripe_session.url, stripe_session.id.to_string()))
+  }
+
+  pub async fn get_or_create_stripe_customer_id(&self, client: &stripe::Client) -> Result<CustomerId> {
+    use std::collections::HashMap;
+    use stripe::CreateCustomer;
+
+    if let Some(ref id) = self.attrs.stripe_customer_id {
+      return Ok(id.parse::<CustomerId>()?);
+    }
+
+    let mut metadata = HashMap::new();
+
+    metadata.insert("organization_id".to_string(), self.attrs.id.to_string());
+    let customer_id = Customer::create(client, CreateCustomer{
+      email: Some(&self.attrs.billing_email),
+      name: Some(&self.attrs.name),
+      metadata: Some(metadata),
+      ..Default::default()
+    }).await?.id;
+
+    sqlx::query!("UPDATE organizations SET stripe_customer_id = $1 WHERE id = $2",
+      Some(customer_id.to_string()),
+      self.attrs.id
+    ).execute(&self.state.db).await?;
+    Ok(customer_id)
+  }
+}
+
+#[derive(Serialize)]
+pub struct OrganizationState {
+  pub organization: Organization,
+  pub students: Vec<Student>,
+  pub invoices: Vec<OrganizationInvoice>,
+}
+
+impl OrganizationState {
+  pub async fn new(organization: Organization) -> Result<OrganizationState> {
+    Ok(Self{
+      students: organization.students().await?,
+      invoices: organization.invoices().await?,
+      organization,
+    })
+  }
+}
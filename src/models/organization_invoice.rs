@@ -0,0 +1,185 @@
+use crate::error::Result;
+use super::*;
+
+make_sqlx_model!{
+  state: Site,
+  table: organization_invoices,
+  struct OrganizationInvoice {
+    #[sqlx_search_as(int4)]
+    id: i32,
+    #[sqlx_search_as(int4)]
+    organization_id: i32,
+    created_at: UtcDateTime,
+    #[sqlx_search_as(payment_method)]
+    payment_method: PaymentMethod,
+    #[sqlx_search_as(varchar)]
+    external_id: String,
+    #[sqlx_search_as(decimal)]
+    amount: Decimal,
+    #[sqlx_search_as(currency)]
+    currency: Currency,
+    description: String,
+    url: String,
+    #[sqlx_search_as(boolean)]
+    paid: bool,
+    #[sqlx_search_as(boolean)]
+    expired: bool,
+    paid_at: Option<UtcDateTime>,
+  }
+}
+
+make_sqlx_model!{
+  state: Site,
+  table: organization_invoice_items,
+  struct OrganizationInvoiceItem {
+    #[sqlx_search_as(int4)]
+    id: i32,
+    #[sqlx_search_as(int4)]
+    organization_invoice_id: i32,
+    #[sqlx_search_as(int4)]
+    student_id: i32,
+    description: String,
+    amount: Decimal,
+  }
+}
+
+impl OrganizationInvoiceHub {
+  /* Open invoices of an organization with a line for this student. */
+  pub async fn open_covering(&self, organization_id: i32, student_id: i32) -> Result<Vec<OrganizationInvoice>> {
+    let ids = sqlx::query_scalar!(
+      "SELECT DISTINCT i.id FROM organization_invoices i
+        INNER JOIN organization_invoice_items items ON items.organization_invoice_id = i.id
+        WHERE i.organization_id = $1 AND items.student_id = $2 AND NOT i.paid AND NOT i.expired",
+      organization_id,
+      student_id,
+    ).fetch_all(&self.state.db).await?;
+
+    let mut invoices = vec![];
+    for id in ids.iter() {
+      invoices.push(self.find(id).await?);
+    }
+    Ok(invoices)
+  }
+
+  pub async fn open_amount_for(&self, student_id: i32, currency: Currency) -> Result<Decimal> {
+    Ok(sqlx::query_scalar!(
+      r#"SELECT COALESCE(SUM(items.amount), 0) as "amount!"
+        FROM organization_invoice_items items
+        INNER JOIN organization_invoices i ON i.id = items.organization_invoice_id
+        WHERE items.student_id = $1 AND i.currency = $2 AND NOT i.paid AND NOT i.expired"#,
+      student_id,
+      currency as Currency,
+    ).fetch_one(&self.state.db).await?)
+  }
+}
+
+impl OrganizationInvoice {
+  /* Same as Invoice::void, a link the organization already paid can't be voided. */
+  pub async fn void(&self) -> Result<()> {
+    void_on_processor(&self.state, self.attrs.payment_method, &self.attrs.external_id).await?;
+
+    sqlx::query!("UPDATE organization_invoices SET expired = true WHERE id = $1", self.attrs.id)
+      .execute(&self.state.db).await?;
+    Ok(())
+  }
+
+  pub async fn items(&self) -> sqlx::Result<Vec<OrganizationInvoiceItem>> {
+    self.state.organization_invoice_item().select()
+      .organization_invoice_id_eq(self.id())
+      .all().await
+  }
+
+  /* An organization payment is split into one payment per covered student, so each
+   * student's balance and charges are settled through the usual path.
+   * The payments and the paid flag are stored together, with the invoice row locked so
+   * a webhook delivered twice can't pay it twice. Settling charges afterwards can be retried. */
+  pub async fn make_payment(&self, clearing_data: Option<&str>) -> Result<Vec<Payment>> {
+    let mut tx = self.state.db.begin().await?;
+    let already_paid = sqlx::query_scalar!(
+      "SELECT paid FROM organization_invoices WHERE id = $1 FOR UPDATE",
+      self.attrs.id,
+    ).fetch_one(&mut tx).await?;
+
+    if already_paid {
+      return Ok(vec![]);
+    }
+
+    let now = Utc::now();
+    let mut payment_ids = vec![];
+    for item in self.items().await? {
+      payment_ids.push(sqlx::query_scalar!(
+        "INSERT INTO payments (student_id, created_at, amount, currency, fees, payment_method, clearing_data, organization_invoice_id)
+          VALUES ($1, $2, $3, $4, $5, $6, $7, $8) RETURNING id",
+        item.attrs.student_id,
+        now,
+        item.attrs.amount,
+        self.attrs.currency as Currency,
+        Decimal::ZERO,
+        self.attrs.payment_method as PaymentMethod,
+        clearing_data.unwrap_or(""),
+        self.attrs.id,
+      ).fetch_one(&mut tx).await?);
+    }
+
+    sqlx::query!(
+      "UPDATE organization_invoices SET paid = true, paid_at = $2 WHERE id = $1",
+      self.attrs.id,
+      now,
+    ).execute(&mut tx).await?;
+    tx.commit().await?;
+
+    let mut payments = vec![];
+    let mut settled = vec![];
+    for id in payment_ids {
+      let payment = self.state.payment().find(&id).await?;
+      settled.extend(payment.settle().await?);
+      payments.push(payment);
+    }
+
+    let organization = self.state.organization().find(&self.attrs.organization_id).await?;
+    let maybe_fiscal_invoice = self.state.fiscal_invoice()
+      .issue(FiscalCustomer::from_organization(&organization), self.attrs.currency, None, Some(self.attrs.id), &settled)
+      .await?;
+
+    if let Err(e) = self.send_receipt(&organization, maybe_fiscal_invoice.as_ref()).await {
+      rocket::warn!("Could not send receipt for organization invoice {}: {:?}", self.attrs.id, e);
+    }
+
+    Ok(payments)
+  }
+
+  /* The same receipt students get for their own payments, listing each sponsored student's line.
+   * There's no receipt page for organizations, the fiscal invoice is attached instead. */
+  pub async fn send_receipt(&self, organization: &Organization, fiscal_invoice: Option<&FiscalInvoice>) -> Result<()> {
+    let charges: Vec<serde_json::Value> = self.items().await?.iter()
+      .map(|i| serde_json::json!({ "description": i.attrs.description, "amount": i.attrs.amount }))
+      .collect();
+
+    let mut context = tera::Context::new();
+    context.insert("full_name", &organization.attrs.billing_contact_name);
+    context.insert("amount", &self.attrs.amount);
+    context.insert("currency", self.attrs.currency.symbol());
+    context.insert("payment_method", self.attrs.payment_method.name());
+    context.insert("charges", &charges);
+    context.insert("outstanding", &Decimal::ZERO);
+    context.insert("receipt_link", &None::<String>);
+    context.insert("fiscal_invoice_number", &fiscal_invoice.map(|i| i.full_number()));
+
+    let attachments = match fiscal_invoice {
+      Some(invoice) => vec![(invoice.pdf_filename(), invoice.pdf().await?)],
+      None => vec![],
+    };
+
+    let to = organization.recipient();
+    let subject = to.locale.text(Text::PaymentReceiptSubject);
+    self.state.send_email_with_attachments(
+      Sender::Billing,
+      to,
+      subject,
+      "emails/payment_receipt",
+      &context,
+      &attachments,
+    ).await?;
+    Ok(())
+  }
+}
@@ -12,16 +12,31 @@ make_sqlx_model!{
     created_at: UtcDateTime,
     #[sqlx_search_as(decimal)]
     amount: Decimal,
+    #[sqlx_search_as(currency)]
+    currency: Currency,
     fees: Decimal,
     payment_method: PaymentMethod,
     clearing_data: String,
     #[sqlx_search_as(int4)]
     invoice_id: Option<i32>,
+    #[sqlx_search_as(int4)]
+    organization_invoice_id: Option<i32>,
   }
 }
 
 impl InsertPaymentHub {
   pub async fn create_and_pay_invoice(self) -> Result<Payment> {
+    let (payment, settled) = self.create_and_settle().await?;
+
+    if payment.attrs.organization_invoice_id.is_none() {
+      payment.issue_and_send_receipt(&settled).await?;
+    }
+
+    Ok(payment)
+  }
+
+  /* Stores the payment and marks as paid every charge it covers, returning those charges. */
+  pub async fn create_and_settle(self) -> Result<(Payment, Vec<Box<dyn BillingCharge>>)> {
     let payment = self.save().await?;
 
     if let Some(id) = payment.attrs.invoice_id {
@@ -32,10 +47,189 @@ impl InsertPaymentHub {
       ).execute(&payment.state.db).await?;
     }
 
-    let student = payment.state.student().find(payment.student_id()).await?;
-    BillingSummary::new(student).await?.sync_paid_status().await?;
+    let settled = payment.settle().await?;
+    Ok((payment, settled))
+  }
+}
 
-    Ok(payment)
+impl Payment {
+  /* Organization payments get one fiscal invoice and receipt for all of them instead. */
+  pub async fn issue_and_send_receipt(&self, settled: &[Box<dyn BillingCharge>]) -> Result<()> {
+    let student = self.state.student().find(self.student_id()).await?;
+    let maybe_fiscal_invoice = self.state.fiscal_invoice()
+      .issue(FiscalCustomer::from_student(&student), self.attrs.currency, Some(self.attrs.id), None, settled)
+      .await?;
+
+    /* The payment is already stored, a failing email must not make the processor retry it. */
+    if let Err(e) = self.send_receipt(&student, settled, maybe_fiscal_invoice.as_ref()).await {
+      rocket::warn!("Could not send receipt for payment {}: {:?}", self.attrs.id, e);
+    }
+    Ok(())
+  }
+
+  /* Marks as paid every charge the student's balance now covers. Doing it again is harmless. */
+  pub async fn settle(&self) -> Result<Vec<Box<dyn BillingCharge>>> {
+    let mut student = self.state.student().find(self.student_id()).await?;
+    let settled = BillingSummary::new(student.clone()).await?.sync_paid_status().await?;
+    student.clear_delinquency_if_settled().await?;
+    Ok(settled)
+  }
+
+  /* Receipts are linked from emails, so the token is derived from the payment itself instead of stored. */
+  pub fn access_token(&self) -> String {
+    access_token_for(&self.state, &format!("receipt:{}", self.attrs.id))
+  }
+
+  pub fn check_access_token(&self, token: &str) -> Result<()> {
+    check_access_token_for(&self.state, &format!("receipt:{}", self.attrs.id), token)
+  }
+
+  pub fn receipt_link(&self) -> String {
+    format!(
+      "{}/receipt?student_id={}&payment_id={}&token={}",
+      self.state.settings.checkout_domain,
+      self.attrs.student_id,
+      self.attrs.id,
+      self.access_token(),
+    )
+  }
+
+  /* Sent for every payment a student makes, the fiscal invoice is attached when one was issued.
+   * Organizations get the same receipt from their own invoice, see OrganizationInvoice::send_receipt. */
+  pub async fn send_receipt(&self, student: &Student, settled: &[Box<dyn BillingCharge>], fiscal_invoice: Option<&FiscalInvoice>) -> Result<()> {
+    let locale = student.attrs.locale;
+    let billing = BillingSummary::new(student.clone()).await?;
+    let outstanding = (billing.balance_in(self.attrs.currency) * Decimal::NEGATIVE_ONE).max(Decimal::ZERO);
+
+    let charges: Vec<serde_json::Value> = settled.iter()
+      .filter(|c| c.currency() == self.attrs.currency)
+      .map(|c| serde_json::json!({ "description": c.description(locale), "amount": c.amount() }))
+      .collect();
+
+    let mut context = tera::Context::new();
+    context.insert("full_name", &student.attrs.full_name);
+    context.insert("amount", &self.attrs.amount);
+    context.insert("currency", self.attrs.currency.symbol());
+    context.insert("payment_method", self.attrs.payment_method.name());
+    context.insert("charges", &charges);
+    context.insert("outstanding", &outstanding);
+    context.insert("receipt_link", &self.receipt_link());
+    context.insert("fiscal_invoice_number", &fiscal_invoice.map(|i| i.full_number()));
+
+    let attachments = match fiscal_invoice {
+      Some(invoice) => vec![(invoice.pdf_filename(), invoice.pdf().await?)],
+      None => vec![],
+    };
+
+    self.state.send_email_with_attachments(
+      Sender::Billing,
+      student.recipient(),
+      locale.text(Text::PaymentReceiptSubject),
+      "emails/payment_receipt",
+      &context,
+      &attachments,
+    ).await?;
+    Ok(())
+  }
+
+  /* Organization payments are split per student, but they share the organization's invoice. */
+  pub async fn fiscal_invoice(&self) -> sqlx::Result<Option<FiscalInvoice>> {
+    let select = self.state.fiscal_invoice().select().kind_eq(&FiscalDocumentKind::Invoice);
+    match self.attrs.organization_invoice_id {
+      Some(ref id) => select.organization_invoice_id_eq(&Some(*id)).optional().await,
+      None => select.payment_id_eq(&Some(self.attrs.id)).optional().await,
+    }
+  }
+
+  pub async fn refunds(&self) -> sqlx::Result<Vec<Refund>> {
+    self.state.refund().select()
+      .payment_id_eq(self.id())
+      .order_by(RefundOrderBy::Id)
+      .all().await
+  }
+
+  /* The payment row stays locked while the refund is checked and stored, so two refunds
+   * recorded at once can't add up to more than was paid.
+   * A refund takes back part of the sale, like the credit note issued for it. The paid charges
+   * it came from are re-priced down by the same amount, newest first, so they stay paid and
+   * the student doesn't end up owing what we gave back. */
+  pub async fn refund(&self, form: RefundForm) -> Result<Refund> {
+    let mut tx = self.state.db.begin().await?;
+    sqlx::query!("SELECT id FROM payments WHERE id = $1 FOR UPDATE", self.attrs.id)
+      .fetch_one(&mut tx).await?;
+
+    let refunded = sqlx::query_scalar!(
+      r#"SELECT COALESCE(SUM(amount), 0) as "refunded!" FROM refunds WHERE payment_id = $1"#,
+      self.attrs.id,
+    ).fetch_one(&mut tx).await?;
+    let refundable = self.attrs.amount - refunded;
+    let amount = form.amount.unwrap_or(refundable);
+
+    if amount <= Decimal::ZERO || amount > refundable {
+      return Err(Error::invalid("amount", ValidationMessage::RefundOutOfRange(refundable)));
+    }
+
+    let refund_id = sqlx::query_scalar!(
+      "INSERT INTO refunds (payment_id, student_id, created_at, amount, currency, fees, reason, clearing_data)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8) RETURNING id",
+      self.attrs.id,
+      self.attrs.student_id,
+      Utc::now(),
+      amount,
+      self.attrs.currency as Currency,
+      form.fees,
+      form.reason,
+      form.clearing_data.unwrap_or_default(),
+    ).fetch_one(&mut tx).await?;
+
+    let charges = sqlx::query!(
+      r#"SELECT kind as "kind!", id as "id!", price as "price!" FROM (
+          SELECT 'subscription'::text as kind, id, price, paid_at FROM subscriptions WHERE student_id = $1 AND currency = $2 AND paid
+          UNION ALL
+          SELECT 'degree'::text as kind, id, price, paid_at FROM degrees WHERE student_id = $1 AND currency = $2 AND paid
+        ) charges WHERE price > 0 ORDER BY paid_at DESC, id DESC"#,
+      self.attrs.student_id,
+      self.attrs.currency as Currency,
+    ).fetch_all(&mut tx).await?;
+
+    let mut left = amount;
+    for charge in charges {
+      if left.is_zero() {
+        break;
+      }
+      let taken = left.min(charge.price);
+      left -= taken;
+
+      if charge.kind == "subscription" {
+        sqlx::query!("UPDATE subscriptions SET price = price - $2 WHERE id = $1", charge.id, taken)
+          .execute(&mut tx).await?;
+      } else {
+        sqlx::query!("UPDATE degrees SET price = price - $2 WHERE id = $1", charge.id, taken)
+          .execute(&mut tx).await?;
+      }
+    }
+
+    tx.commit().await?;
+
+    let refund = self.state.refund().find(&refund_id).await?;
+    if let Some(fiscal_invoice) = self.fiscal_invoice().await? {
+      fiscal_invoice.issue_credit_note(&refund).await?;
+    }
+
+    Ok(refund)
+  }
+
+  pub async fn receipt_pdf(&self) -> Result<Vec<u8>> {
+    let student = self.state.student().find(self.student_id()).await?;
+    let fiscal_invoice = self.fiscal_invoice().await?;
+
+    let mut context = tera::Context::new();
+    context.insert("payment", &self);
+    context.insert("currency", self.attrs.currency.symbol());
+    context.insert("full_name", &student.attrs.full_name);
+    context.insert("email", &student.attrs.email);
+    context.insert("fiscal_invoice_number", &fiscal_invoice.map(|i| i.full_number()));
+    crate::pdf::render("pdfs/receipt", &context)
   }
 }
 
@@ -50,27 +244,39 @@ impl PaymentHub {
       .payment_method_eq(&PaymentMethod::BtcPay)
       .optional().await?;
 
-    if maybe_invoice.as_ref().and_then(|i| i.attrs.payment_id ).is_some() {
-      return Ok(None)
+    if let Some(invoice) = maybe_invoice {
+      return invoice.make_payment(None).await;
     }
 
-    if let Some(invoice) = maybe_invoice {
-      Ok(Some(invoice.make_payment(None).await?))
-    } else {
-      Ok(None)
+    let maybe_organization_invoice = self.state.organization_invoice().select()
+      .external_id_eq(&webhook.invoice_id)
+      .payment_method_eq(&PaymentMethod::BtcPay)
+      .paid_eq(&false)
+      .optional().await?;
+
+    if let Some(invoice) = maybe_organization_invoice {
+      invoice.make_payment(None).await?;
     }
+
+    Ok(None)
   }
 
-  pub async fn from_invoice(&self, invoice_id: i32) -> Result<Option<Payment>> {
-    let maybe_invoice = self.state.invoice().select()
-      .id_eq(&invoice_id)
-      .payment_id_is_set(false)
+  pub async fn from_organization_invoice(&self, organization_invoice_id: i32) -> Result<Vec<Payment>> {
+    let maybe_invoice = self.state.organization_invoice().select()
+      .id_eq(&organization_invoice_id)
+      .paid_eq(&false)
       .optional().await?;
 
-    if let Some(invoice) = maybe_invoice {
-      Ok(Some(invoice.make_payment(None).await?))
-    } else {
-      Ok(None)
+    match maybe_invoice {
+      Some(invoice) => invoice.make_payment(None).await,
+      None => Ok(vec![]),
+    }
+  }
+
+  pub async fn from_invoice(&self, invoice_id: i32) -> Result<Option<Payment>> {
+    match self.state.invoice().select().id_eq(&invoice_id).optional().await? {
+      Some(invoice) => invoice.make_payment(None).await,
+      None => Ok(None),
     }
   }
 
@@ -87,24 +293,62 @@ impl PaymentHub {
         .stripe_customer_id_eq(&Some(customer_id.clone()))
         .optional().await?;
 
+      /* We never invoice in other currencies, so a payment in one can't be matched to anything. */
+      let currency: Currency = match i.currency {
+        Some(c) => match serde_json::from_value(serde_json::json!(c.to_string())) {
+          Ok(currency) => currency,
+          Err(_) => {
+            rocket::warn!("Ignoring Stripe invoice {:?} paid in unknown currency {}", i.id, c);
+            return Ok(None);
+          }
+        },
+        None => Currency::Eur,
+      };
+
       if let Some(student) = maybe_student {
         let amount = Decimal::new(i.amount_paid.ok_or(Error::validation("amount_paid", "missing"))?, 2);
         let maybe_invoice = self.state.invoice().select()
           .amount_eq(&amount)
+          .currency_eq(&currency)
           .student_id_eq(student.id())
           .payment_method_eq(&PaymentMethod::Stripe)
           .optional().await?;
 
+        if let Some(invoice) = maybe_invoice {
+          return invoice.make_payment(Some(&serde_json::to_string(&i)?)).await;
+        }
+
         Ok(Some(self.insert().use_struct(InsertPayment{
           student_id: student.attrs.id,
           created_at: Utc::now(),
           amount: amount,
+          currency: currency,
           fees: Decimal::ZERO,
           payment_method: PaymentMethod::Stripe,
           clearing_data: serde_json::to_string(&i)?,
-          invoice_id: maybe_invoice.map(|i| i.attrs.id),
+          invoice_id: None,
+          organization_invoice_id: None,
         }).create_and_pay_invoice().await?))
       } else {
+        let maybe_organization = self.state.organization().select()
+          .stripe_customer_id_eq(&Some(customer_id))
+          .optional().await?;
+
+        if let Some(organization) = maybe_organization {
+          let amount = Decimal::new(i.amount_paid.ok_or(Error::validation("amount_paid", "missing"))?, 2);
+          let maybe_invoice = self.state.organization_invoice().select()
+            .organization_id_eq(organization.id())
+            .amount_eq(&amount)
+            .currency_eq(&currency)
+            .payment_method_eq(&PaymentMethod::Stripe)
+            .paid_eq(&false)
+            .optional().await?;
+
+          if let Some(invoice) = maybe_invoice {
+            invoice.make_payment(Some(&serde_json::to_string(&i)?)).await?;
+          }
+        }
+
         Ok(None)
       }
     } else {
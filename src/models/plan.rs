@@ -1,10 +1,36 @@
 use super::*;
 
+/* Prices are final prices, tax included. See TaxAssessment for how they split. */
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct Plan {
   pub code: PlanCode,
+  #[serde(default)]
+  pub currency: Currency,
   pub signup: Decimal,
   pub degree: Decimal,
+  #[serde(default)]
+  pub local_prices: Vec<LocalPrice>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct LocalPrice {
+  pub currency: Currency,
+  pub signup: Decimal,
+  pub degree: Decimal,
+}
+
+impl Plan {
+  pub fn in_currency(&self, currency: Currency) -> Plan {
+    match self.local_prices.iter().find(|p| p.currency == currency) {
+      Some(local) => Plan{
+        currency: local.currency,
+        signup: local.signup,
+        degree: local.degree,
+        ..self.clone()
+      },
+      None => self.clone(),
+    }
+  }
 }
 
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
@@ -12,7 +38,6 @@ pub struct Plans {
   pub global: Plan,
   pub europe: Plan,
   pub latam: Plan,
-  pub guest: Plan,
 }
 
 impl Plans {
@@ -21,7 +46,13 @@ impl Plans {
       PlanCode::Global => self.global.clone(),
       PlanCode::Europe => self.europe.clone(),
       PlanCode::Latam  => self.latam.clone(),
-      PlanCode::Guest  => self.guest.clone(),
+      PlanCode::Guest  => Plan{
+        code: PlanCode::Guest,
+        currency: Currency::Eur,
+        signup: Decimal::ZERO,
+        degree: Decimal::ZERO,
+        local_prices: vec![],
+      },
     }
   }
 }
@@ -33,6 +64,8 @@ pub enum PlanCode {
   Global,
   Europe,
   Latam,
+  /* Guests used to be enrolled for free on this plan, now they get a full scholarship on
+   * their country's plan. It's kept for their old subscriptions. */
   Guest,
 }
 
@@ -1,23 +1,50 @@
 use serde::{Deserialize, Serialize};
-use stripe::PriceId;
 
-#[derive(PartialEq, Debug, Clone, Copy, Serialize, Deserialize)]
+#[derive(sqlx::Type, PartialEq, Eq, Hash, Debug, Clone, Copy, Serialize, Deserialize, FromFormField)]
+#[sqlx(type_name = "program", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
 pub enum Program {
+  #[field(value = "zero_to_hero")]
   ZeroToHero,
+  #[field(value = "academy")]
   Academy,
 }
 
+impl Default for Program {
+  fn default() -> Self {
+    Program::ZeroToHero
+  }
+}
+
+impl Program {
+  pub fn all() -> Vec<Program> {
+    vec![Program::ZeroToHero, Program::Academy]
+  }
+
+  pub fn name(&self) -> &'static str {
+    match self {
+      Program::ZeroToHero => "Zero to Hero",
+      Program::Academy => "Academy",
+    }
+  }
+}
+
+/* Anything that is configured differently for each program: prices, LearnDash groups, Discord roles. */
 #[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
-pub struct Programs {
-  pub zero_to_hero: PriceId,
-  pub academy: PriceId,
+pub struct Programs<T> {
+  pub zero_to_hero: T,
+  pub academy: T,
 }
 
-impl Programs {
-  pub fn price(&self, program: &Program) -> &PriceId {
+impl<T> Programs<T> {
+  pub fn get(&self, program: Program) -> &T {
     match program {
       Program::ZeroToHero => &self.zero_to_hero,
       Program::Academy => &self.academy,
     }
   }
+
+  pub fn iter(&self) -> impl Iterator<Item = (Program, &T)> {
+    Program::all().into_iter().map(move |p| (p, self.get(p)))
+  }
 }
@@ -0,0 +1,236 @@
+use crate::error::Result;
+use super::*;
+
+/* A payment our processors say was completed, in their own terms. */
+#[derive(Debug, Clone, Serialize)]
+pub struct RemotePayment {
+  pub payment_method: PaymentMethod,
+  pub external_id: String,
+  pub amount: Decimal,
+  pub currency: String,
+  #[serde(skip_serializing)]
+  pub clearing_data: String,
+}
+
+impl RemotePayment {
+  pub fn matches(&self, amount: Decimal, currency: Currency) -> bool {
+    self.amount == amount && self.currency.eq_ignore_ascii_case(currency.code())
+  }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Discrepancy {
+  AmountMismatch {
+    remote: RemotePayment,
+    invoice_id: Option<i32>,
+    organization_invoice_id: Option<i32>,
+    expected_amount: Decimal,
+    expected_currency: Currency,
+  },
+  UnknownExternalId {
+    remote: RemotePayment,
+  },
+  Failed {
+    remote: RemotePayment,
+    error: String,
+  },
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RecoveredPayment {
+  pub external_id: String,
+  pub invoice_id: Option<i32>,
+  pub organization_invoice_id: Option<i32>,
+  pub payment_ids: Vec<i32>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ReconciliationReport {
+  pub since: UtcDateTime,
+  pub checked: usize,
+  pub already_paid: usize,
+  pub recovered: Vec<RecoveredPayment>,
+  pub discrepancies: Vec<Discrepancy>,
+}
+
+impl ReconciliationReport {
+  /* Payments that never got a webhook are created through the same path webhooks use.
+   * Anything that doesn't add up is only reported, someone has to look at it.
+   * A payment that fails to be recorded is reported too, and the rest are still checked. */
+  pub async fn build(site: &Site, since: UtcDateTime) -> Result<ReconciliationReport> {
+    let mut remotes = stripe_payments(site, since).await?;
+    remotes.append(&mut btcpay_payments(site, since).await?);
+
+    let mut report = ReconciliationReport{
+      since,
+      checked: remotes.len(),
+      already_paid: 0,
+      recovered: vec![],
+      discrepancies: vec![],
+    };
+
+    for remote in remotes.into_iter() {
+      if let Err(e) = report.reconcile(site, remote.clone()).await {
+        report.discrepancies.push(Discrepancy::Failed{ remote, error: format!("{:?}", e) });
+      }
+    }
+
+    Ok(report)
+  }
+
+  async fn reconcile(&mut self, site: &Site, remote: RemotePayment) -> Result<()> {
+    let maybe_invoice = site.invoice().select()
+      .external_id_eq(&remote.external_id)
+      .payment_method_eq(&remote.payment_method)
+      .optional().await?;
+
+    if let Some(invoice) = maybe_invoice {
+      if invoice.attrs.payment_id.is_some() {
+        self.already_paid += 1;
+      } else if !remote.matches(invoice.attrs.amount, invoice.attrs.currency) {
+        self.discrepancies.push(Discrepancy::AmountMismatch{
+          invoice_id: Some(invoice.attrs.id),
+          organization_invoice_id: None,
+          expected_amount: invoice.attrs.amount,
+          expected_currency: invoice.attrs.currency,
+          remote,
+        });
+      } else {
+        match invoice.make_payment(Some(&remote.clearing_data)).await? {
+          Some(payment) => self.recovered.push(RecoveredPayment{
+            external_id: remote.external_id,
+            invoice_id: Some(invoice.attrs.id),
+            organization_invoice_id: None,
+            payment_ids: vec![payment.attrs.id],
+          }),
+          None => self.already_paid += 1,
+        }
+      }
+      return Ok(());
+    }
+
+    let maybe_organization_invoice = site.organization_invoice().select()
+      .external_id_eq(&remote.external_id)
+      .payment_method_eq(&remote.payment_method)
+      .optional().await?;
+
+    if let Some(invoice) = maybe_organization_invoice {
+      if invoice.attrs.paid {
+        self.already_paid += 1;
+      } else if !remote.matches(invoice.attrs.amount, invoice.attrs.currency) {
+        self.discrepancies.push(Discrepancy::AmountMismatch{
+          invoice_id: None,
+          organization_invoice_id: Some(invoice.attrs.id),
+          expected_amount: invoice.attrs.amount,
+          expected_currency: invoice.attrs.currency,
+          remote,
+        });
+      } else {
+        let payments = invoice.make_payment(Some(&remote.clearing_data)).await?;
+        if payments.is_empty() {
+          self.already_paid += 1;
+        } else {
+          self.recovered.push(RecoveredPayment{
+            external_id: remote.external_id,
+            invoice_id: None,
+            organization_invoice_id: Some(invoice.attrs.id),
+            payment_ids: payments.iter().map(|p| p.attrs.id).collect(),
+          });
+        }
+      }
+      return Ok(());
+    }
+
+    self.discrepancies.push(Discrepancy::UnknownExternalId{ remote });
+    Ok(())
+  }
+}
+
+/* Our Stripe invoices are Checkout Sessions, their id is the invoice's external_id. */
+async fn stripe_payments(site: &Site, since: UtcDateTime) -> Result<Vec<RemotePayment>> {
+  #[derive(Deserialize)]
+  struct Session {
+    id: String,
+    payment_status: String,
+    amount_total: Option<i64>,
+    currency: Option<String>,
+  }
+
+  #[derive(Deserialize)]
+  struct SessionList {
+    data: Vec<serde_json::Value>,
+    has_more: bool,
+  }
+
+  let mut payments = vec![];
+  let mut starting_after: Option<String> = None;
+
+  loop {
+    let mut params = serde_json::json!({ "limit": 100, "created": { "gte": since.timestamp() } });
+    if let Some(ref id) = starting_after {
+      params["starting_after"] = serde_json::json!(id);
+    }
+
+    let page: SessionList = site.stripe.get_query("/checkout/sessions", params).await?;
+
+    for raw in page.data.iter() {
+      let session: Session = serde_json::from_value(raw.clone())?;
+      starting_after = Some(session.id.clone());
+
+      if session.payment_status != "paid" {
+        continue;
+      }
+
+      payments.push(RemotePayment{
+        payment_method: PaymentMethod::Stripe,
+        external_id: session.id,
+        amount: Decimal::new(session.amount_total.unwrap_or(0), 2),
+        currency: session.currency.unwrap_or_default(),
+        clearing_data: raw.to_string(),
+      });
+    }
+
+    if !page.has_more || page.data.is_empty() {
+      break;
+    }
+  }
+
+  Ok(payments)
+}
+
+async fn btcpay_payments(site: &Site, since: UtcDateTime) -> Result<Vec<RemotePayment>> {
+  let mut payments = vec![];
+
+  for invoice in btcpay::list_invoices(site, since).await?.into_iter().filter(|i| i.is_settled()) {
+    payments.push(RemotePayment{
+      payment_method: PaymentMethod::BtcPay,
+      clearing_data: serde_json::to_string(&invoice)?,
+      external_id: invoice.id,
+      amount: invoice.amount,
+      currency: invoice.currency,
+    });
+  }
+
+  Ok(payments)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn matches_amount_and_currency_code() {
+    let remote = RemotePayment{
+      payment_method: PaymentMethod::Stripe,
+      external_id: "cs_test_1".into(),
+      amount: Decimal::new(10000, 2),
+      currency: "eur".into(),
+      clearing_data: "{}".into(),
+    };
+
+    assert!(remote.matches(Decimal::new(100, 0), Currency::Eur));
+    assert!(!remote.matches(Decimal::new(100, 0), Currency::Usd));
+    assert!(!remote.matches(Decimal::new(99, 0), Currency::Eur));
+  }
+}
@@ -0,0 +1,31 @@
+use super::*;
+
+make_sqlx_model!{
+  state: Site,
+  table: refunds,
+  struct Refund {
+    #[sqlx_search_as(int4)]
+    id: i32,
+    #[sqlx_search_as(int4)]
+    payment_id: i32,
+    #[sqlx_search_as(int4)]
+    student_id: i32,
+    created_at: UtcDateTime,
+    amount: Decimal,
+    #[sqlx_search_as(currency)]
+    currency: Currency,
+    fees: Decimal,
+    reason: String,
+    clearing_data: String,
+  }
+}
+
+/* Refunds are sent from the Stripe or BTCPay dashboards, then recorded here. */
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+pub struct RefundForm {
+  pub amount: Option<Decimal>,
+  #[serde(default)]
+  pub fees: Decimal,
+  pub reason: String,
+  pub clearing_data: Option<String>,
+}
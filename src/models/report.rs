@@ -0,0 +1,277 @@
+use crate::error::Result;
+use super::*;
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize, FromFormField)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportPeriod {
+  Day,
+  Week,
+  Month,
+}
+
+impl ReportPeriod {
+  /* As understood by postgres' date_trunc */
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      ReportPeriod::Day => "day",
+      ReportPeriod::Week => "week",
+      ReportPeriod::Month => "month",
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ReportRange {
+  pub from: UtcDateTime,
+  pub until: UtcDateTime,
+  pub period: ReportPeriod,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct FunnelRow {
+  pub period: Option<UtcDateTime>,
+  pub new_students: i64,
+  pub new_subscriptions: i64,
+  pub paid_subscriptions: i64,
+  pub conversion_rate: Option<f64>,
+  pub avg_days_to_pay: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RevenueRow {
+  pub period: UtcDateTime,
+  pub plan_code: PlanCode,
+  pub payment_method: Option<PaymentMethod>,
+  pub currency: Currency,
+  pub count: i64,
+  pub revenue: Decimal,
+  pub revenue_eur: Option<Decimal>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DegreeRevenueRow {
+  pub period: UtcDateTime,
+  pub program: Program,
+  pub currency: Currency,
+  pub count: i64,
+  pub revenue: Decimal,
+  pub revenue_eur: Option<Decimal>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CollectionsRow {
+  pub period: UtcDateTime,
+  pub plan_code: Option<PlanCode>,
+  pub payment_method: PaymentMethod,
+  pub currency: Currency,
+  pub payments: i64,
+  pub collected: Decimal,
+  pub fees: Decimal,
+  pub refunded: Decimal,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ReceivablesRow {
+  pub currency: Currency,
+  pub students: i64,
+  pub outstanding: Decimal,
+  pub outstanding_eur: Option<Decimal>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RevenueReport {
+  pub subscriptions: Vec<RevenueRow>,
+  pub degrees: Vec<DegreeRevenueRow>,
+  pub collections: Vec<CollectionsRow>,
+}
+
+impl ReportRange {
+  /* Subscriptions are counted in the period they were created, and converted if they were paid since. */
+  pub async fn funnel(&self, site: &Site) -> Result<Vec<FunnelRow>> {
+    let mut rows: BTreeMap<UtcDateTime, FunnelRow> = BTreeMap::new();
+
+    let students = sqlx::query!(
+      r#"SELECT date_trunc($1, created_at) as "period!", count(*) as "count!"
+        FROM students WHERE created_at >= $2 AND created_at < $3
+        GROUP BY 1"#,
+      self.period.as_str(),
+      self.from,
+      self.until,
+    ).fetch_all(&site.db).await?;
+
+    for r in students {
+      rows.entry(r.period).or_default().new_students = r.count;
+    }
+
+    let subscriptions = sqlx::query!(
+      r#"SELECT date_trunc($1, created_at) as "period!",
+          count(*) as "created!",
+          count(*) FILTER (WHERE paid) as "paid!",
+          (avg(EXTRACT(EPOCH FROM (paid_at - created_at))) FILTER (WHERE paid) / 86400)::float8 as days_to_pay
+        FROM subscriptions WHERE created_at >= $2 AND created_at < $3
+        GROUP BY 1"#,
+      self.period.as_str(),
+      self.from,
+      self.until,
+    ).fetch_all(&site.db).await?;
+
+    for r in subscriptions {
+      let row = rows.entry(r.period).or_default();
+      row.new_subscriptions = r.created;
+      row.paid_subscriptions = r.paid;
+      row.avg_days_to_pay = r.days_to_pay;
+    }
+
+    Ok(rows.into_iter().map(|(period, mut row)| {
+      row.period = Some(period);
+      if row.new_subscriptions > 0 {
+        row.conversion_rate = Some(row.paid_subscriptions as f64 / row.new_subscriptions as f64);
+      }
+      row
+    }).collect())
+  }
+
+  pub async fn revenue(&self, site: &Site) -> Result<RevenueReport> {
+    let mut subscriptions = vec![];
+    let mut degrees = vec![];
+    let mut collections = vec![];
+
+    /* Charges don't point to the payment that settled them, that's the student's last payment
+     * in the same currency by the time they were paid. Students can switch payment methods,
+     * so it's not the one in their profile. Fully discounted charges have no payment at all. */
+    let subscription_rows = sqlx::query!(
+      r#"SELECT date_trunc($1, sub.paid_at) as "period!",
+          sub.plan_code as "plan_code!: PlanCode",
+          p.payment_method as "payment_method: PaymentMethod",
+          sub.currency as "currency!: Currency",
+          count(*) as "count!",
+          COALESCE(sum(sub.price), 0) as "revenue!"
+        FROM subscriptions sub
+        LEFT JOIN LATERAL (
+          SELECT payment_method FROM payments
+          WHERE student_id = sub.student_id AND currency = sub.currency AND created_at <= sub.paid_at
+          ORDER BY created_at DESC LIMIT 1
+        ) p ON true
+        WHERE sub.paid AND sub.paid_at >= $2 AND sub.paid_at < $3
+        GROUP BY 1, 2, 3, 4 ORDER BY 1, 2, 3, 4"#,
+      self.period.as_str(),
+      self.from,
+      self.until,
+    ).fetch_all(&site.db).await?;
+
+    for r in subscription_rows {
+      subscriptions.push(RevenueRow{
+        revenue_eur: to_eur(site, r.revenue, r.currency, r.period).await?,
+        period: r.period,
+        plan_code: r.plan_code,
+        payment_method: r.payment_method,
+        currency: r.currency,
+        count: r.count,
+        revenue: r.revenue,
+      });
+    }
+
+    let degree_rows = sqlx::query!(
+      r#"SELECT date_trunc($1, paid_at) as "period!",
+          program as "program!: Program",
+          currency as "currency!: Currency",
+          count(*) as "count!",
+          COALESCE(sum(price), 0) as "revenue!"
+        FROM degrees
+        WHERE paid AND paid_at >= $2 AND paid_at < $3
+        GROUP BY 1, 2, 3 ORDER BY 1, 2, 3"#,
+      self.period.as_str(),
+      self.from,
+      self.until,
+    ).fetch_all(&site.db).await?;
+
+    for r in degree_rows {
+      degrees.push(DegreeRevenueRow{
+        revenue_eur: to_eur(site, r.revenue, r.currency, r.period).await?,
+        period: r.period,
+        program: r.program,
+        currency: r.currency,
+        count: r.count,
+        revenue: r.revenue,
+      });
+    }
+
+    /* Payments are told apart by the plan of the enrollment they were for, the student's latest
+     * one in that currency. Degrees are billed on the plan of their subscription anyway. */
+    let collection_rows = sqlx::query!(
+      r#"SELECT date_trunc($1, p.created_at) as "period!",
+          sub.plan_code as "plan_code: PlanCode",
+          p.payment_method as "payment_method!: PaymentMethod",
+          p.currency as "currency!: Currency",
+          count(*) as "payments!",
+          COALESCE(sum(p.amount), 0) as "collected!",
+          COALESCE(sum(p.fees), 0) as "fees!",
+          COALESCE(sum(r.amount), 0) as "refunded!"
+        FROM payments p
+        LEFT JOIN (SELECT payment_id, sum(amount) as amount FROM refunds GROUP BY 1) r ON r.payment_id = p.id
+        LEFT JOIN LATERAL (
+          SELECT plan_code FROM subscriptions
+          WHERE student_id = p.student_id AND currency = p.currency AND created_at <= p.created_at
+          ORDER BY created_at DESC LIMIT 1
+        ) sub ON true
+        WHERE p.created_at >= $2 AND p.created_at < $3
+        GROUP BY 1, 2, 3, 4 ORDER BY 1, 2, 3, 4"#,
+      self.period.as_str(),
+      self.from,
+      self.until,
+    ).fetch_all(&site.db).await?;
+
+    for r in collection_rows {
+      collections.push(CollectionsRow{
+        period: r.period,
+        plan_code: r.plan_code,
+        payment_method: r.payment_method,
+        currency: r.currency,
+        payments: r.payments,
+        collected: r.collected,
+        fees: r.fees,
+        refunded: r.refunded,
+      });
+    }
+
+    Ok(RevenueReport{ subscriptions, degrees, collections })
+  }
+}
+
+/* What every student owes right now, computed the same way as BillingSummary but for everyone at once. */
+pub async fn receivables(site: &Site) -> Result<Vec<ReceivablesRow>> {
+  let rows = sqlx::query!(
+    r#"WITH ledger AS (
+        SELECT student_id, currency, -price as amount FROM subscriptions WHERE active
+        UNION ALL SELECT student_id, currency, -price FROM degrees
+        UNION ALL SELECT student_id, currency, amount FROM payments
+        UNION ALL SELECT student_id, currency, -amount FROM refunds
+      ), balances AS (
+        SELECT student_id, currency, sum(amount) as balance FROM ledger GROUP BY 1, 2
+      )
+      SELECT currency as "currency!: Currency", count(*) as "students!", COALESCE(-sum(balance), 0) as "outstanding!"
+      FROM balances WHERE balance < 0
+      GROUP BY 1 ORDER BY 1"#,
+  ).fetch_all(&site.db).await?;
+
+  let mut receivables = vec![];
+  for r in rows {
+    receivables.push(ReceivablesRow{
+      outstanding_eur: to_eur(site, r.outstanding, r.currency, Utc::now()).await?,
+      currency: r.currency,
+      students: r.students,
+      outstanding: r.outstanding,
+    });
+  }
+  Ok(receivables)
+}
+
+/* Reports still make sense without a rate loaded for some currency, they just lack the euro column. */
+async fn to_eur(site: &Site, amount: Decimal, currency: Currency, at: UtcDateTime) -> Result<Option<Decimal>> {
+  match site.exchange_rate().to_eur(amount, currency, at).await {
+    Ok(eur) => Ok(Some(eur)),
+    Err(Error::Validation{ .. }) => Ok(None),
+    Err(e) => Err(e),
+  }
+}
@@ -0,0 +1,198 @@
+use crate::error::Result;
+use super::*;
+
+make_sqlx_model!{
+  state: Site,
+  table: scholarships,
+  struct Scholarship {
+    #[sqlx_search_as(int4)]
+    id: i32,
+    #[sqlx_search_as(int4)]
+    student_id: i32,
+    #[sqlx_search_as(program)]
+    program: Program,
+    created_at: UtcDateTime,
+    kind: ScholarshipKind,
+    value: Decimal,
+    sponsor: Option<String>,
+    reason: String,
+    #[sqlx_search_as(scholarship_status)]
+    status: ScholarshipStatus,
+    reviewed_at: Option<UtcDateTime>,
+    expires_at: Option<UtcDateTime>,
+  }
+}
+
+#[derive(sqlx::Type, Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[sqlx(type_name = "scholarship_kind", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum ScholarshipKind {
+  Percentage,
+  Fixed,
+}
+
+impl ScholarshipKind {
+  /* Never below zero. */
+  pub fn discounted(&self, value: Decimal, amount: Decimal) -> Decimal {
+    let discounted = match self {
+      ScholarshipKind::Percentage => amount * (Decimal::ONE_HUNDRED - value) / Decimal::ONE_HUNDRED,
+      ScholarshipKind::Fixed => amount - value,
+    };
+    discounted.max(Decimal::ZERO).round_dp(2)
+  }
+}
+
+#[derive(sqlx::Type, Debug, Clone, Copy, PartialEq, Deserialize, Serialize, FromFormField)]
+#[sqlx(type_name = "scholarship_status", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum ScholarshipStatus {
+  #[field(value = "requested")]
+  Requested,
+  #[field(value = "approved")]
+  Approved,
+  #[field(value = "rejected")]
+  Rejected,
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+pub struct ScholarshipRequest {
+  pub kind: ScholarshipKind,
+  pub value: Decimal,
+  pub sponsor: Option<String>,
+  pub reason: String,
+  pub expires_at: Option<UtcDateTime>,
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+pub struct ScholarshipForm {
+  pub student_id: i32,
+  pub program: Program,
+  pub request: ScholarshipRequest,
+}
+
+impl ScholarshipHub {
+  pub async fn request(&self, student_id: i32, program: Program, request: ScholarshipRequest) -> Result<Scholarship> {
+    let valid_value = match request.kind {
+      ScholarshipKind::Percentage => request.value > Decimal::ZERO && request.value <= Decimal::ONE_HUNDRED,
+      ScholarshipKind::Fixed => request.value > Decimal::ZERO,
+    };
+
+    if !valid_value {
+      return Err(Error::validation("value", "a percentage must be between 0 and 100, a fixed discount must be positive"));
+    }
+
+    Ok(self.insert().use_struct(InsertScholarship{
+      student_id,
+      program,
+      created_at: Utc::now(),
+      kind: request.kind,
+      value: request.value,
+      sponsor: request.sponsor,
+      reason: request.reason,
+      status: ScholarshipStatus::Requested,
+      reviewed_at: None,
+      expires_at: request.expires_at,
+    }).save().await?)
+  }
+
+  pub async fn applicable_for(&self, student_id: i32, program: Program) -> Result<Option<Scholarship>> {
+    let now = Utc::now();
+    Ok(self.select()
+      .student_id_eq(&student_id)
+      .program_eq(&program)
+      .status_eq(&ScholarshipStatus::Approved)
+      .order_by(ScholarshipOrderBy::Id)
+      .all().await?
+      .into_iter()
+      .filter(|s| s.attrs.expires_at.map(|e| e > now).unwrap_or(true))
+      .last())
+  }
+}
+
+impl Scholarship {
+  /* Percentages apply to every charge. A fixed amount is taken off the program only once, so what
+   * the subscription already got is not given again to its degree, and the other way around. */
+  pub async fn discounted_for(&self, subscription_id: i32, kind: &str, charge_id: i32, amount: Decimal) -> Result<Decimal> {
+    if self.attrs.kind == ScholarshipKind::Percentage {
+      return Ok(self.attrs.kind.discounted(self.attrs.value, amount));
+    }
+
+    let used = sqlx::query_scalar!(
+      r#"SELECT COALESCE(SUM(list_price - price), 0) as "used!" FROM (
+          SELECT 'subscription'::text as kind, id, list_price, price FROM subscriptions WHERE id = $1 AND scholarship_id = $2
+          UNION ALL
+          SELECT 'degree'::text as kind, id, list_price, price FROM degrees WHERE subscription_id = $1 AND scholarship_id = $2
+        ) charges WHERE NOT (kind = $3 AND id = $4)"#,
+      subscription_id,
+      self.attrs.id,
+      kind,
+      charge_id,
+    ).fetch_one(&self.state.db).await?;
+
+    let left = (self.attrs.value - used).max(Decimal::ZERO);
+    Ok(self.attrs.kind.discounted(left, amount))
+  }
+
+  /* Approving re-prices every unpaid charge of the program. Open invoices were
+   * issued for the old amounts, so they are voided first and a new one is issued.
+   * An invoice that is being paid right now can't be voided, and stops the approval. */
+  pub async fn approve(&mut self) -> Result<()> {
+    let student = self.state.student().find(&self.attrs.student_id).await?;
+    student.void_open_invoices().await?;
+
+    self.review(ScholarshipStatus::Approved).await?;
+    self.apply_to_unpaid_charges(&student).await?;
+
+    BillingSummary::new(student).await?.invoice_all_not_invoiced_yet().await?;
+    Ok(())
+  }
+
+  pub async fn reject(&mut self) -> Result<()> {
+    self.review(ScholarshipStatus::Rejected).await
+  }
+
+  pub async fn apply_to_unpaid_charges(&self, student: &Student) -> Result<()> {
+    let billing = BillingSummary::new(student.clone()).await?;
+    for mut charge in billing.unpaid_charges.into_iter() {
+      if charge.program() == self.attrs.program {
+        charge.apply_scholarship(self).await?;
+      }
+    }
+    Ok(())
+  }
+
+  async fn review(&mut self, status: ScholarshipStatus) -> Result<()> {
+    if self.attrs.status != ScholarshipStatus::Requested {
+      return Err(Error::validation("status", "scholarship was already reviewed"));
+    }
+
+    let now = Utc::now();
+    sqlx::query!(
+      "UPDATE scholarships SET status = $2, reviewed_at = $3 WHERE id = $1",
+      self.attrs.id,
+      status as ScholarshipStatus,
+      now,
+    ).execute(&self.state.db).await?;
+    self.attrs.status = status;
+    self.attrs.reviewed_at = Some(now);
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn computes_discounted_prices() {
+    let percentage = ScholarshipKind::Percentage;
+    let fixed = ScholarshipKind::Fixed;
+
+    assert_eq!(percentage.discounted(Decimal::new(50, 0), Decimal::new(150, 0)), Decimal::new(75, 0));
+    assert_eq!(percentage.discounted(Decimal::new(100, 0), Decimal::new(150, 0)), Decimal::ZERO);
+    assert_eq!(percentage.discounted(Decimal::new(33, 0), Decimal::new(100, 0)), Decimal::new(67, 0));
+    assert_eq!(percentage.discounted(Decimal::new(15, 0), Decimal::new(9999, 2)), Decimal::new(8499, 2));
+    assert_eq!(fixed.discounted(Decimal::new(40, 0), Decimal::new(100, 0)), Decimal::new(60, 0));
+    assert_eq!(fixed.discounted(Decimal::new(400, 0), Decimal::new(100, 0)), Decimal::ZERO);
+  }
+}
@@ -0,0 +1,266 @@
+use crate::error::Result;
+use super::*;
+
+make_sqlx_model!{
+  state: Site,
+  table: sent_emails,
+  struct SentEmail {
+    #[sqlx_search_as(int4)]
+    id: i32,
+    #[sqlx_search_as(int4)]
+    student_id: Option<i32>,
+    created_at: UtcDateTime,
+    updated_at: UtcDateTime,
+    sender: Sender,
+    #[sqlx_search_as(varchar)]
+    to_email: String,
+    to_name: String,
+    #[sqlx_search_as(varchar)]
+    template: String,
+    subject: String,
+    context: String,
+    body_hash: String,
+    has_attachments: bool,
+    #[sqlx_search_as(varchar)]
+    provider_message_id: Option<String>,
+    #[sqlx_search_as(email_status)]
+    status: EmailStatus,
+    error: Option<String>,
+    locale: Locale,
+  }
+}
+
+#[derive(sqlx::Type, Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[sqlx(type_name = "email_status", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum EmailStatus {
+  Queued,
+  Sent,
+  Failed,
+  Delivered,
+  Opened,
+  Bounced,
+  Complained,
+}
+
+impl EmailStatus {
+  /* Provider events may arrive out of order, a status only ever moves forward. */
+  pub fn rank(self) -> u8 {
+    match self {
+      EmailStatus::Queued => 0,
+      EmailStatus::Sent => 1,
+      EmailStatus::Failed => 2,
+      EmailStatus::Delivered => 2,
+      EmailStatus::Opened => 3,
+      EmailStatus::Bounced => 4,
+      EmailStatus::Complained => 4,
+    }
+  }
+
+  /* Sendinblue transactional webhook event names. Clicks count as opens, others are ignored. */
+  pub fn from_sendinblue_event(event: &str) -> Option<EmailStatus> {
+    match event {
+      "delivered" => Some(EmailStatus::Delivered),
+      "opened" | "unique_opened" | "click" => Some(EmailStatus::Opened),
+      "hard_bounce" | "soft_bounce" | "blocked" | "invalid_email" => Some(EmailStatus::Bounced),
+      "spam" => Some(EmailStatus::Complained),
+      _ => None,
+    }
+  }
+}
+
+pub struct Recipient {
+  pub email: String,
+  pub name: String,
+  pub student_id: Option<i32>,
+  pub locale: Locale,
+}
+
+impl Recipient {
+  pub fn new(email: &str, name: &str, locale: Locale) -> Recipient {
+    Recipient{ email: email.to_string(), name: name.to_string(), student_id: None, locale }
+  }
+}
+
+impl Student {
+  pub fn recipient(&self) -> Recipient {
+    Recipient{
+      email: self.attrs.email.clone(),
+      name: self.attrs.full_name.clone(),
+      student_id: Some(self.attrs.id),
+      locale: self.attrs.locale,
+    }
+  }
+
+  pub async fn sent_emails(&self) -> sqlx::Result<Vec<SentEmail>> {
+    self.state.sent_email().select()
+      .student_id_eq(&Some(self.attrs.id))
+      .order_by(SentEmailOrderBy::Id)
+      .all().await
+  }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SendinblueEvent {
+  pub event: String,
+  #[serde(rename = "message-id")]
+  pub message_id: Option<String>,
+  pub reason: Option<String>,
+}
+
+impl SentEmailHub {
+  /* Events for messages we don't know about, like campaigns, are ignored. */
+  pub async fn process_sendinblue_event(&self, event: &SendinblueEvent) -> Result<()> {
+    let status = match EmailStatus::from_sendinblue_event(&event.event) {
+      Some(s) => s,
+      None => return Ok(()),
+    };
+
+    let message_id = match event.message_id {
+      Some(ref id) => id.clone(),
+      None => return Ok(()),
+    };
+
+    if let Some(mut sent) = self.select().provider_message_id_eq(&Some(message_id)).optional().await? {
+      sent.update_status(status, event.reason.clone()).await?;
+    }
+
+    Ok(())
+  }
+}
+
+impl SentEmail {
+  pub async fn update_status(&mut self, status: EmailStatus, error: Option<String>) -> Result<()> {
+    if status.rank() <= self.attrs.status.rank() {
+      return Ok(());
+    }
+
+    let now = Utc::now();
+    sqlx::query!(
+      "UPDATE sent_emails SET status = $2, error = COALESCE($3, error), updated_at = $4 WHERE id = $1",
+      self.attrs.id,
+      status as EmailStatus,
+      error,
+      now,
+    ).execute(&self.state.db).await?;
+
+    self.attrs.status = status;
+    self.attrs.updated_at = now;
+    if error.is_some() {
+      self.attrs.error = error;
+    }
+    Ok(())
+  }
+
+  /* Renders the same template with the same context again, and logs it as a new email.
+   * Attachments are not kept, those emails have to be sent again from whatever produced them.
+   * Credentials are stripped again, in case they were logged before we left them out. */
+  pub async fn resend(&self) -> Result<SentEmail> {
+    if self.attrs.has_attachments {
+      return Err(Error::validation("sent_email", "emails with attachments can't be resent from the log"));
+    }
+
+    let context = tera::Context::from_value(without_secrets(serde_json::from_str(&self.attrs.context)?))?;
+    let to = Recipient{
+      email: self.attrs.to_email.clone(),
+      name: self.attrs.to_name.clone(),
+      student_id: self.attrs.student_id,
+      locale: self.attrs.locale,
+    };
+
+    self.state.send_email(self.attrs.sender, to, &self.attrs.subject, &self.attrs.template, &context).await
+  }
+}
+
+/* Keys that could carry credentials are never written to the email log. */
+const SECRET_CONTEXT_KEYS: &[&str] = &["password", "pass", "secret", "secret_key", "api_key", "access_token"];
+
+pub fn without_secrets(mut context: serde_json::Value) -> serde_json::Value {
+  if let Some(map) = context.as_object_mut() {
+    for key in SECRET_CONTEXT_KEYS {
+      map.remove(*key);
+    }
+  }
+  context
+}
+
+/* Every variable any email template uses, with made up values or the ones of a real student.
+ * Templates only use some of them, but none of them fails for lack of a variable. */
+pub async fn preview_context(site: &Site, student: Option<Student>) -> Result<tera::Context> {
+  let mut context = tera::Context::new();
+  context.insert("full_name", "Satoshi Nakamoto");
+  context.insert("email", "satoshi@example.com");
+  context.insert("password_reset_link", &site.settings.wordpress.lost_password_url());
+  context.insert("new_wordpress_account", &true);
+  context.insert("program", Program::ZeroToHero.name());
+  context.insert("discord_verification_link", &format!("{}/discord-verification", site.settings.checkout_domain));
+  context.insert("checkout_link", &format!("{}/checkout", site.settings.checkout_domain));
+  context.insert("billing_link", &format!("{}/billing?student_id=1&token=preview", site.settings.checkout_domain));
+  context.insert("amount", &Decimal::new(100, 0));
+  context.insert("currency", Currency::Eur.symbol());
+  context.insert("reminder_count", &0);
+  context.insert("final_notice", &false);
+  context.insert("suspend_after_days", &site.settings.dunning.suspend_after_days);
+  context.insert("cohort", "Cohorte de prueba");
+  context.insert("invitation_link", &format!("{}/waitlist-invitation?token=preview", site.settings.checkout_domain));
+  context.insert("expires_at", &(Utc::now() + chrono::Duration::hours(site.settings.waitlist_invitation_hours)).format("%d/%m/%Y %H:%M UTC").to_string());
+  context.insert("fiscal_invoice_number", "A-000001");
+  context.insert("payment_method", PaymentMethod::Stripe.name());
+  context.insert("charges", &vec![serde_json::json!({ "description": "Zero to Hero", "amount": Decimal::new(100, 0) })]);
+  context.insert("outstanding", &Decimal::ZERO);
+  context.insert("receipt_link", &format!("{}/receipt?student_id=1&payment_id=1&token=preview", site.settings.checkout_domain));
+  context.insert("poap_link", "https://poap.xyz/claim/preview");
+  context.insert("certificate_id", "preview");
+
+  if let Some(student) = student {
+    context.insert("full_name", &student.attrs.full_name);
+    context.insert("email", &student.attrs.email);
+    context.insert("discord_verification_link", &student.discord_verification_link());
+    context.insert("billing_link", &student.billing_link());
+
+    let billing = BillingSummary::new(student).await?;
+    if let Some(subscription) = billing.subscriptions.first() {
+      context.insert("program", subscription.attrs.program.name());
+    }
+    if let Some(invoice) = billing.invoices.first() {
+      context.insert("checkout_link", &invoice.attrs.url);
+      context.insert("amount", &invoice.attrs.amount);
+      context.insert("currency", invoice.attrs.currency.symbol());
+      context.insert("reminder_count", &invoice.attrs.reminder_count);
+    }
+  }
+
+  Ok(context)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn maps_sendinblue_events_and_only_moves_forward() {
+    assert_eq!(EmailStatus::from_sendinblue_event("delivered"), Some(EmailStatus::Delivered));
+    assert_eq!(EmailStatus::from_sendinblue_event("unique_opened"), Some(EmailStatus::Opened));
+    assert_eq!(EmailStatus::from_sendinblue_event("hard_bounce"), Some(EmailStatus::Bounced));
+    assert_eq!(EmailStatus::from_sendinblue_event("spam"), Some(EmailStatus::Complained));
+    assert_eq!(EmailStatus::from_sendinblue_event("request"), None);
+
+    assert!(EmailStatus::Opened.rank() > EmailStatus::Delivered.rank());
+    assert!(EmailStatus::Bounced.rank() > EmailStatus::Opened.rank());
+    assert!(EmailStatus::Delivered.rank() > EmailStatus::Sent.rank());
+  }
+
+  #[test]
+  fn leaves_credentials_out_of_the_log() {
+    let mut context = tera::Context::new();
+    context.insert("full_name", "Satoshi Nakamoto");
+    context.insert("password", "hunter2");
+    context.insert("receipt_link", "https://example.com/receipt?token=1");
+
+    let logged = without_secrets(context.into_json());
+    assert_eq!(logged, serde_json::json!({
+      "full_name": "Satoshi Nakamoto",
+      "receipt_link": "https://example.com/receipt?token=1",
+    }));
+  }
+}
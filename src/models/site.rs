@@ -1,9 +1,14 @@
-use super::{StripePrices, DiscordSettings, WordpressSettings, BtcpaySettings, SendinblueSettings, Plans};
+use super::{SentEmail, InsertSentEmail, without_secrets, EmailStatus, Recipient, StripePrices, DiscordSettings, WordpressSettings, BtcpaySettings, SendinblueSettings, InvoicingSettings, AccountingSettings, DunningSettings, Plans, Programs};
 use serde::{Deserialize, Serialize};
 use stripe::Client;
 use sqlx::postgres::{PgPool, PgPoolOptions};
 use crate::error::*;
+use crate::http::{self, ApiClient, Integration};
+use crate::email::{self, Attachment, Email, EmailSettings, Sender};
+use crate::templating;
 use rocket::Config;
+use chrono::Utc;
+use sha2::{Digest, Sha256};
 
 pub type Db = PgPool;
 
@@ -16,30 +21,49 @@ pub struct SiteSettings {
   pub payment_error_redirect: String,
   pub stripe_secret_key: String,
   pub stripe_public_key: String,
-  pub stripe_prices: StripePrices,
+  pub stripe_prices: Programs<StripePrices>,
   pub stripe_events_secret: String,
   pub database_uri: String,
   pub discord: DiscordSettings,
   pub wordpress: WordpressSettings,
   pub btcpay: BtcpaySettings,
   pub sendinblue: SendinblueSettings,
-  pub pricing: Plans,
+  pub invoicing: InvoicingSettings,
+  pub accounting: AccountingSettings,
+  pub dunning: DunningSettings,
+  pub email: EmailSettings,
+  pub pricing: Programs<Plans>,
+  pub waitlist_invitation_hours: i64,
+  #[serde(default)]
+  pub templates_dir: Option<String>,
+  #[serde(default = "SiteSettings::default_jobs_enabled")]
+  pub jobs_enabled: bool,
 }
 
 impl SiteSettings {
+  fn default_jobs_enabled() -> bool {
+    true
+  }
+
   pub fn default() -> SiteSettings {
     Config::figment().extract().expect("Config could not be parsed")
   }
 
   pub async fn into_site(self) -> Result<Site> {
     let stripe = Client::new(&self.stripe_secret_key);
-    self.stripe_prices.validate_all(&stripe).await?;
+    for (_, prices) in self.stripe_prices.iter() {
+      prices.validate_all(&stripe).await?;
+    }
+    if let Some(ref dir) = self.templates_dir {
+      templating::load_overrides(std::path::Path::new(dir))?;
+    }
+
     let db = PgPoolOptions::new()
       .max_connections(5)
       .connect(&self.database_uri)
       .await?;
 
-    Ok(Site{ stripe, db, settings: self })
+    Ok(Site{ stripe, db, http: http::build_client()?, settings: self })
   }
 }
 
@@ -47,9 +71,118 @@ impl SiteSettings {
 pub struct Site {
   pub db: Db,
   pub stripe: Client,
+  pub http: reqwest::Client,
   pub settings: SiteSettings,
 }
 
+impl Site {
+  pub fn wordpress_api(&self) -> ApiClient {
+    let wp = &self.settings.wordpress;
+    ApiClient::new(&self.http, Integration::Wordpress, &wp.api_url)
+      .header("Authorization", format!("Basic {}", base64::encode(format!("{}:{}", wp.user, wp.pass))))
+  }
+
+  /* The public site, for the few things WordPress only does through its own forms. */
+  pub fn wordpress_site(&self) -> ApiClient {
+    ApiClient::new(&self.http, Integration::Wordpress, self.settings.wordpress.site_url())
+  }
+
+  pub fn discord_api(&self) -> ApiClient {
+    let discord = &self.settings.discord;
+    ApiClient::new(&self.http, Integration::Discord, &discord.api_url)
+      .header("Authorization", format!("Bot {}", discord.bot_secret_token))
+  }
+
+  /* Our own application credentials, for exchanging and refreshing OAuth tokens. */
+  pub fn discord_oauth_api(&self) -> ApiClient {
+    let discord = &self.settings.discord;
+    ApiClient::new(&self.http, Integration::Discord, &discord.api_url)
+      .header("Authorization", format!("Basic {}", base64::encode(format!("{}:{}", discord.client_id, discord.client_secret))))
+  }
+
+  /* Acting on behalf of a student that authorized us through OAuth. */
+  pub fn discord_user_api(&self, access_token: &str) -> ApiClient {
+    ApiClient::new(&self.http, Integration::Discord, &self.settings.discord.api_url)
+      .header("Authorization", format!("Bearer {}", access_token))
+  }
+
+  pub fn sendinblue_api(&self) -> ApiClient {
+    ApiClient::new(&self.http, Integration::Sendinblue, &self.settings.sendinblue.api_url)
+      .header("api-key", self.settings.sendinblue.api_key.clone())
+  }
+
+  pub fn btcpay_api(&self) -> ApiClient {
+    let btcpay = &self.settings.btcpay;
+    ApiClient::new(&self.http, Integration::Btcpay, &format!("{}/api/v1/stores/{}", btcpay.base_url, btcpay.store_id))
+      .header("Authorization", format!("token {}", btcpay.api_key))
+  }
+
+  pub async fn send_email(&self, from: Sender, to: Recipient, subject: &str, template: &str, context: &tera::Context) -> Result<SentEmail> {
+    self.send_email_with_attachments(from, to, subject, template, context, &[]).await
+  }
+
+  /* Every email is logged before it's handed to the backend, so failed ones show up too.
+   * Only a hash of the body is kept, the context is enough to render it again.
+   * Credentials in the context are left out of the log, see without_secrets.
+   * Templates are rendered in the recipient's language when there's a translation. */
+  pub async fn send_email_with_attachments(
+    &self,
+    from: Sender,
+    to: Recipient,
+    subject: &str,
+    template: &str,
+    context: &tera::Context,
+    attachments: &[(String, Vec<u8>)],
+  ) -> Result<SentEmail> {
+    let html = templating::render(&to.locale.template(template), context)?;
+    let now = Utc::now();
+
+    let mut sent = self.sent_email().insert().use_struct(InsertSentEmail{
+      student_id: to.student_id,
+      created_at: now,
+      updated_at: now,
+      sender: from,
+      to_email: to.email.clone(),
+      to_name: to.name.clone(),
+      template: template.to_string(),
+      subject: subject.to_string(),
+      context: without_secrets(context.clone().into_json()).to_string(),
+      body_hash: hex::encode(Sha256::digest(html.as_bytes())),
+      has_attachments: !attachments.is_empty(),
+      provider_message_id: None,
+      status: EmailStatus::Queued,
+      error: None,
+      locale: to.locale,
+    }).save().await?;
+
+    let email = Email{
+      from: self.settings.email.identity(from).clone(),
+      to_name: to.name,
+      to_email: to.email,
+      subject: subject.to_string(),
+      html,
+      attachments: attachments.iter().map(|(filename, content)| Attachment::new(filename, content)).collect(),
+    };
+
+    match email::sender_for(self).send(&email).await {
+      Ok(message_id) => {
+        sqlx::query!(
+          "UPDATE sent_emails SET status = 'sent', provider_message_id = $2, updated_at = now() WHERE id = $1",
+          sent.attrs.id,
+          message_id,
+        ).execute(&self.db).await?;
+        sent.attrs.status = EmailStatus::Sent;
+        sent.attrs.provider_message_id = message_id;
+        Ok(sent)
+      },
+      Err(e) => {
+        sent.update_status(EmailStatus::Failed, Some(e.to_string())).await?;
+        Err(e)
+      }
+    }
+  }
+}
+
 #[cfg(test)]
 mod test {
   use super::*;
@@ -60,7 +193,8 @@ mod test {
   use std::str::FromStr;
   use stripe::PriceId;
   use sqlx::types::Decimal;
-  use crate::models::{PlanCode, Plan};
+  use crate::models::{PlanCode, Plan, LocalPrice, Currency};
+  use crate::email::{EmailBackend, Identity};
 
   #[test]
   fn site_config_parsing() {
@@ -76,24 +210,33 @@ mod test {
         admin_key="supersecret"
         payment_success_redirect="https://dao.education/muchas-gracias"
         payment_error_redirect="https://dao.education/error-al-pagar"
+        waitlist_invitation_hours=48
 
-        [global.pricing]
+        [global.pricing.zero_to_hero]
         global = { code = "global", signup = 200, degree = 500 }
         europe = { code = "europe", signup = 150, degree = 375 }
-        latam = { code = "latam",  signup = 100, degree = 250 }
-        guest = { code = "guest",  signup =   0, degree =   0 }
+        latam = { code = "latam",  signup = 100, degree = 250, local_prices = [{ currency = "ars", signup = 30000, degree = 75000 }] }
+
+        [global.pricing.academy]
+        global = { code = "global", signup = 400, degree = 500 }
+        europe = { code = "europe", signup = 300, degree = 375 }
+        latam = { code = "latam",  signup = 200, degree = 250, local_prices = [{ currency = "ars", signup = 60000, degree = 75000 }] }
 
         [global.discord]
         guild_id="1000"
         bot_secret_token="SUPERSECRET"
         client_id="1002"
+        client_secret="DISCORDCLIENTSECRET"
         student_role_id="1001"
+        program_role_ids = { zero_to_hero = "1003", academy = "1004" }
 
         [global.wordpress]
         api_url="https://daocriptoacademy.com/wp-json/"
         user="user"
         pass="password"
         student_group_id=1
+        program_group_ids = { zero_to_hero = 2, academy = 3 }
+        degree_courses = { zero_to_hero = [10, 11], academy = [20] }
 
         [global.btcpay]
         base_url = "https://btcpay.constata.eu"
@@ -103,14 +246,54 @@ mod test {
 
         [global.sendinblue]
         api_key = "Sendinblueapikey"
+        webhooks_secret = "SENDINBLUESECRET"
+        api_url = "http://localhost:4010/v3"
+
+        [global.invoicing]
+        issuer_name = "DAO Education"
+        issuer_tax_number = "B00000000"
+        issuer_address = "Calle Falsa 123, Madrid, España"
+        issuer_country = "ES"
+        series_prefix = "A"
+        default_tax_rate = 0
+        tax_rates = { ES = 0.21 }
 
-        [global.stripe_prices]
-        global_fzth_signup= "1"
-        global_fzth_degree= "3"
-        latam_fzth_signup= "4"
-        latam_fzth_degree= "6"
-        europe_fzth_signup= "7"
-        europe_fzth_degree= "9"
+        [global.accounting]
+        receivables_account = "430"
+        revenue_account = "705"
+        tax_payable_account = "477"
+        fees_account = "626"
+        stripe_account = "572001"
+        btcpay_account = "572002"
+
+        [global.dunning]
+        reminder_days = [1, 3, 7, 14]
+        suspend_after_days = 21
+        payment_link_hours = 24
+
+        [global.email]
+        sender = { name = "DAO Education", email = "dao.education@constata.eu", reply_to = "info@dao.education" }
+        billing_sender = { name = "Tesorería DAO Education", email = "dao.education@constata.eu", reply_to = "tesoreria@dao.education" }
+
+        [global.email.backend]
+        kind = "file_sink"
+        path = "/tmp/daoe-emails.jsonl"
+
+        [global.stripe_prices.zero_to_hero]
+        global_signup= "1"
+        global_degree= "3"
+        latam_signup= "4"
+        latam_degree= "6"
+        europe_signup= "7"
+        europe_degree= "9"
+
+        [global.stripe_prices.academy]
+        global_signup= "10"
+        global_degree= "12"
+        latam_signup= "13"
+        latam_degree= "15"
+        europe_signup= "16"
+        europe_degree= "18"
     "#,
     );
 
@@ -120,6 +303,35 @@ mod test {
       .expect("Config could not be parsed");
 
     let mkprice = |a| { PriceId::from_str(a).unwrap() };
+    let mkplans = |signups: [i64; 3], ars_signup: i64| {
+      Plans{
+        global: Plan{
+          code: PlanCode::Global,
+          currency: Currency::Eur,
+          signup: Decimal::new(signups[0],0),
+          degree: Decimal::new(500,0),
+          local_prices: vec![],
+        },
+        europe: Plan{
+          code: PlanCode::Europe,
+          currency: Currency::Eur,
+          signup: Decimal::new(signups[1],0),
+          degree: Decimal::new(375,0),
+          local_prices: vec![],
+        },
+        latam: Plan{
+          code: PlanCode::Latam,
+          currency: Currency::Eur,
+          signup: Decimal::new(signups[2],0),
+          degree: Decimal::new(250,0),
+          local_prices: vec![LocalPrice{
+            currency: Currency::Ars,
+            signup: Decimal::new(ars_signup,0),
+            degree: Decimal::new(75000,0),
+          }],
+        },
+      }
+    };
 
     assert_eq!(
       site,
@@ -133,39 +345,32 @@ mod test {
         payment_success_redirect: "https://dao.education/muchas-gracias".into(),
         payment_error_redirect: "https://dao.education/error-al-pagar".into(),
         admin_key: "supersecret".into(),
-        pricing: Plans{
-          global: Plan{
-            code: PlanCode::Global,
-            signup: Decimal::new(200,0),
-            degree: Decimal::new(500,0),
-          },
-          europe: Plan{
-            code: PlanCode::Europe,
-            signup: Decimal::new(150,0),
-            degree: Decimal::new(375,0),
-          },
-          latam: Plan{
-            code: PlanCode::Latam,
-            signup: Decimal::new(100,0),
-            degree: Decimal::new(250,0),
-          },
-          guest: Plan{
-            code: PlanCode::Guest,
-            signup: Decimal::ZERO,
-            degree: Decimal::ZERO,
-          },
+        waitlist_invitation_hours: 48,
+        templates_dir: None,
+        jobs_enabled: true,
+        pricing: Programs{
+          zero_to_hero: mkplans([200, 150, 100], 30000),
+          academy: mkplans([400, 300, 200], 60000),
         },
         discord: DiscordSettings{
           guild_id: "1000".into(),
           bot_secret_token: "SUPERSECRET".into(),
           client_id: "1002".into(),
+          client_secret: "DISCORDCLIENTSECRET".into(),
           student_role_id: "1001".into(),
+          program_role_ids: Programs{
+            zero_to_hero: "1003".into(),
+            academy: "1004".into(),
+          },
+          api_url: "https://discord.com/api/v9".into(),
         },
         wordpress: WordpressSettings {
           api_url: "https://daocriptoacademy.com/wp-json/".into(),
           user: "user".into(),
           pass: "password".into(),
           student_group_id: 1,
+          program_group_ids: Programs{ zero_to_hero: 2, academy: 3 },
+          degree_courses: Some(Programs{ zero_to_hero: vec![10, 11], academy: vec![20] }),
         },
         btcpay: BtcpaySettings {
           base_url: "https://btcpay.constata.eu".into(),
@@ -175,16 +380,85 @@ mod test {
         },
         sendinblue: SendinblueSettings {
           api_key: "Sendinblueapikey".into(),
+          webhooks_secret: "SENDINBLUESECRET".into(),
+          api_url: "http://localhost:4010/v3".into(),
         },
-        stripe_prices: StripePrices {
-          global_fzth_signup: mkprice("1"),
-          global_fzth_degree: mkprice("3"),
-          latam_fzth_signup: mkprice("4"),
-          latam_fzth_degree: mkprice("6"),
-          europe_fzth_signup: mkprice("7"),
-          europe_fzth_degree: mkprice("9"),
+        invoicing: InvoicingSettings {
+          issuer_name: "DAO Education".into(),
+          issuer_tax_number: "B00000000".into(),
+          issuer_address: "Calle Falsa 123, Madrid, España".into(),
+          issuer_country: "ES".into(),
+          series_prefix: "A".into(),
+          default_tax_rate: Decimal::ZERO,
+          tax_rates: vec![("ES".to_string(), Decimal::new(21, 2))].into_iter().collect(),
+          vat_check_url: None,
+        },
+        accounting: AccountingSettings {
+          receivables_account: "430".into(),
+          revenue_account: "705".into(),
+          tax_payable_account: "477".into(),
+          fees_account: "626".into(),
+          stripe_account: "572001".into(),
+          btcpay_account: "572002".into(),
+        },
+        dunning: DunningSettings {
+          reminder_days: vec![1, 3, 7, 14],
+          suspend_after_days: 21,
+          payment_link_hours: 24,
+        },
+        email: EmailSettings {
+          backend: EmailBackend::FileSink{ path: "/tmp/daoe-emails.jsonl".into() },
+          sender: Identity {
+            name: "DAO Education".into(),
+            email: "dao.education@constata.eu".into(),
+            reply_to: Some("info@dao.education".into()),
+          },
+          billing_sender: Some(Identity {
+            name: "Tesorería DAO Education".into(),
+            email: "dao.education@constata.eu".into(),
+            reply_to: Some("tesoreria@dao.education".into()),
+          }),
+        },
+        stripe_prices: Programs{
+          zero_to_hero: StripePrices {
+            global_signup: mkprice("1"),
+            global_degree: mkprice("3"),
+            latam_signup: mkprice("4"),
+            latam_degree: mkprice("6"),
+            europe_signup: mkprice("7"),
+            europe_degree: mkprice("9"),
+          },
+          academy: StripePrices {
+            global_signup: mkprice("10"),
+            global_degree: mkprice("12"),
+            latam_signup: mkprice("13"),
+            latam_degree: mkprice("15"),
+            europe_signup: mkprice("16"),
+            europe_degree: mkprice("18"),
+          },
         }
       }
     );
   }
+
+  #[test]
+  fn accepts_legacy_stripe_price_keys() {
+    let provider = Toml::string(
+      r#"
+        global_fzth_signup="price_1"
+        global_fzth_monthly="price_2"
+        global_fzth_degree="price_3"
+        latam_fzth_signup="price_4"
+        latam_fzth_monthly="price_5"
+        latam_fzth_degree="price_6"
+        europe_fzth_signup="price_7"
+        europe_fzth_monthly="price_8"
+        europe_fzth_degree="price_9"
+      "#,
+    );
+
+    let prices: StripePrices = Figment::new().merge(provider).extract().expect("Legacy prices could not be parsed");
+    assert_eq!(prices.global_signup, PriceId::from_str("price_1").unwrap());
+    assert_eq!(prices.europe_degree, PriceId::from_str("price_9").unwrap());
+  }
 }
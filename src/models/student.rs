@@ -18,7 +18,6 @@ make_sqlx_model!{
     current_subscription_id: Option<i32>,
     #[sqlx_search_as(varchar)]
     wordpress_user: Option<String>,
-    wordpress_initial_password: Option<String>,
     discord_user_id: Option<String>,
     discord_handle: Option<String>,
     #[sqlx_search_as(varchar)]
@@ -26,44 +25,111 @@ make_sqlx_model!{
     #[sqlx_search_as(varchar)]
     stripe_customer_id: Option<String>,
     payment_method: PaymentMethod,
+    #[sqlx_search_as(int4)]
+    organization_id: Option<i32>,
+    vat_number: Option<String>,
+    delinquent_since: Option<UtcDateTime>,
+    locale: Locale,
   }
 }
 
 impl InsertStudentHub {
-  pub async fn save_and_subscribe(self, plan: Plan) -> Result<Student> {
+  pub async fn save_and_subscribe(self, plan: Plan, program: Program, cohort: Option<&Cohort>) -> Result<Student> {
     let tx = self.state.db.begin().await?;
     let student = self.save().await?;
-    
-    student.state.subscription()
+    student.subscribe(plan, program, cohort).await?;
+    tx.commit().await?;
+
+    Ok(student)
+  }
+}
+
+impl Student {
+  pub async fn subscriptions(&self) -> sqlx::Result<Vec<Subscription>> {
+    self.state.subscription().select().student_id_eq(self.id()).active_eq(&true).all().await
+  }
+
+  pub async fn subscription(&self, program: Program) -> sqlx::Result<Subscription> {
+    self.state.subscription().select()
+      .student_id_eq(self.id())
+      .program_eq(&program)
+      .active_eq(&true)
+      .one().await
+  }
+
+  pub async fn subscribe(&self, plan: Plan, program: Program, cohort: Option<&Cohort>) -> Result<Subscription> {
+    let existing = self.state.subscription().select()
+      .student_id_eq(self.id())
+      .program_eq(&program)
+      .active_eq(&true)
+      .optional().await?;
+
+    if existing.is_some() {
+      return Err(Error::invalid("program", ValidationMessage::AlreadyEnrolled));
+    }
+
+    if let Some(c) = cohort {
+      c.validate_program(program)?;
+      if !c.accepts_enrollment().await? {
+        return Err(Error::invalid("cohort_id", ValidationMessage::CohortNotAcceptingEnrollments));
+      }
+    }
+
+    let mut subscription = self.state.subscription()
       .insert().use_struct(InsertSubscription{
         created_at: Utc::now(),
-        student_id: student.attrs.id,
+        student_id: self.attrs.id,
         active: true,
         price: plan.signup,
+        list_price: plan.signup,
+        currency: plan.currency,
+        scholarship_id: None,
         paid: false,
         plan_code: plan.code.clone(),
+        program: program,
+        cohort_id: cohort.map(|c| c.attrs.id),
         paid_at: None,
         stripe_subscription_id: None,
       }).save().await?;
 
-    tx.commit().await?;
+    if let Some(scholarship) = self.state.scholarship().applicable_for(self.attrs.id, program).await? {
+      subscription.apply_scholarship(&scholarship).await?;
+    }
 
-    Ok(student)
+    Ok(subscription)
   }
-}
 
-impl Student {
-  pub async fn subscription(&self) -> sqlx::Result<Subscription> {
-    self.state.subscription().select().student_id_eq(self.id()).active_eq(&true).one().await
+  pub fn tax_assessment(&self) -> TaxAssessment {
+    self.state.settings.invoicing.assess(&self.attrs.country, self.attrs.vat_number.as_deref())
   }
 
+  pub async fn fiscal_invoices(&self) -> sqlx::Result<Vec<FiscalInvoice>> {
+    self.state.fiscal_invoice().select()
+      .student_id_eq(&Some(self.attrs.id))
+      .order_by(FiscalInvoiceOrderBy::Id)
+      .all().await
+  }
+
+  pub async fn scholarships(&self) -> sqlx::Result<Vec<Scholarship>> {
+    self.state.scholarship().select()
+      .student_id_eq(self.id())
+      .order_by(ScholarshipOrderBy::Id)
+      .all().await
+  }
+
+  /* Discord sends the student back to our frontend with a code, which is exchanged server side in process_discord_response. */
   pub fn discord_verification_link(&self) -> Option<String> {
+    let discord = &self.state.settings.discord;
     self.attrs.discord_verification.as_ref().map(|token|{
-      format!("https://discord.com/api/oauth2/authorize?response_type=token&client_id={}&state={}&scope=identify%20email%20guilds.join&redirect_uri={}/discord-success",
-      self.state.settings.discord.client_id,
-      &token,
-      self.state.settings.checkout_domain,
-    )})
+      let mut url = reqwest::Url::parse("https://discord.com/api/oauth2/authorize").expect("static url is valid");
+      url.query_pairs_mut()
+        .append_pair("response_type", "code")
+        .append_pair("client_id", &discord.client_id)
+        .append_pair("state", token)
+        .append_pair("scope", "identify email guilds.join")
+        .append_pair("redirect_uri", &discord.redirect_uri(&self.state.settings.checkout_domain));
+      url.to_string()
+    })
   }
 
   pub async fn setup_discord_verification(&mut self) -> Result<()> {
@@ -101,116 +167,344 @@ impl Student {
     Ok(customer_id)
   }
 
-  pub async fn setup_wordpress(&mut self) -> Result<()> {
+  /* Returns whether a new WordPress account was created. Students that already had one
+   * with the same email, because they signed up on the site before paying, keep it. */
+  pub async fn setup_wordpress(&mut self) -> Result<bool> {
     if self.attrs.wordpress_user.is_some() {
-      return Ok(())
+      return Ok(false)
     }
 
-    let wp = &self.state.settings.wordpress;
-    let auth = format!("Basic {}", base64::encode(format!("{}:{}", wp.user, wp.pass)));
+    let (user_id, created) = match self.find_wordpress_user().await? {
+      Some(id) => (id, false),
+      None => match self.create_wordpress_user().await? {
+        Some(id) => (id, true),
+        /* Someone registered this email on the site between our lookup and the creation. */
+        None => {
+          let id = self.find_wordpress_user().await?
+            .ok_or(Error::validation("wordpress_user", "email is taken but no user has it"))?;
+          (id, false)
+        },
+      },
+    };
 
-    let password = gen_passphrase();
+    sqlx::query!(
+      "UPDATE students SET wordpress_user = $2 WHERE id = $1",
+      self.attrs.id,
+      &user_id.to_string(),
+    ).execute(&self.state.db).await?;
+    self.attrs.wordpress_user = Some(user_id.to_string());
 
+    if created {
+      self.request_wordpress_password_reset().await?;
+    }
+
+    Ok(created)
+  }
+
+  /* WordPress searches logins, emails and urls alike, so results are checked for an exact email match. */
+  pub async fn find_wordpress_user(&self) -> Result<Option<i32>> {
     #[derive(Deserialize)]
     struct WordpressUser {
       id: i32,
+      email: String,
     }
 
-    let user: WordpressUser = ureq::post(&format!("{}/wp/v2/users/", wp.api_url))
-      .set("Authorization", &auth)
-      .send_json(serde_json::json!({
-        "username": self.attrs.full_name,
-        "password": &password,
+    let users: Vec<WordpressUser> = self.state.wordpress_api()
+      .get_with_params("/wp/v2/users", &[("context", "edit"), ("search", &self.attrs.email)])
+      .await?
+      .json()?;
+
+    Ok(users.into_iter().find(|u| u.email.eq_ignore_ascii_case(&self.attrs.email)).map(|u| u.id))
+  }
+
+  /* None when the email is already registered. Taken usernames are retried with the next candidate. */
+  async fn create_wordpress_user(&self) -> Result<Option<i32>> {
+    #[derive(Deserialize)]
+    struct WordpressUser {
+      id: i32,
+    }
+
+    let api = self.state.wordpress_api();
+    for username in wordpress_usernames(&self.attrs.full_name, &self.attrs.email, self.attrs.id) {
+      let response = api.post("/wp/v2/users/", &serde_json::json!({
+        "username": username,
+        "password": gen_passphrase(),
         "email": self.attrs.email,
-      }))?
-      .into_json()?;
+      })).await;
 
-    sqlx::query!(
-      "UPDATE students SET wordpress_user = $2, wordpress_initial_password = $3 WHERE id = $1",
-      self.attrs.id,
-      &user.id.to_string(),
-      &password,
-    ).execute(&self.state.db).await?;
+      match response {
+        Ok(created) => return Ok(Some(created.json::<WordpressUser>()?.id)),
+        Err(Error::Wordpress(ref e)) if e.message.contains("existing_user_email") => return Ok(None),
+        Err(Error::Wordpress(ref e)) if e.message.contains("existing_user_login") => continue,
+        Err(e) => return Err(e),
+      }
+    }
+
+    Err(Error::validation("wordpress_user", "could not find a free username"))
+  }
+
+  /* The password the user is created with is thrown away, nobody ever sees it.
+   * WordPress emails the student a link to choose their own, the same one they'd get for a forgotten password. */
+  pub async fn request_wordpress_password_reset(&self) -> Result<()> {
+    self.state.wordpress_site()
+      .post_form("/wp-login.php?action=lostpassword", &serde_json::json!({ "user_login": self.attrs.email }))
+      .await?;
+    Ok(())
+  }
+
+  pub async fn grant_program_discord_role(&self, program: Program) -> Result<()> {
+    self.grant_discord_role(self.state.settings.discord.program_role_ids.get(program)).await
+  }
+
+  pub async fn join_wordpress_group(&self, group_id: i32) -> Result<()> {
+    self.state.wordpress_api()
+      .post(&self.wordpress_groups_path()?, &serde_json::json!({"group_ids":[group_id]}))
+      .await?;
+    Ok(())
+  }
 
-    ureq::post(&format!("{}/ldlms/v2/users/{}/groups", wp.api_url, user.id))
-      .set("Authorization", &auth)
-      .send_json(serde_json::json!({"group_ids":[wp.student_group_id]}))?;
+  pub async fn leave_wordpress_group(&self, group_id: i32) -> Result<()> {
+    self.state.wordpress_api()
+      .delete(&self.wordpress_groups_path()?, &serde_json::json!({"group_ids":[group_id]}))
+      .await?;
+    Ok(())
+  }
 
-    self.attrs.wordpress_user = Some(user.id.to_string());
-    self.attrs.wordpress_initial_password = Some(password);
+  pub async fn grant_discord_role(&self, role_id: &str) -> Result<()> {
+    self.state.discord_api().put(&self.discord_role_path(role_id)?, &serde_json::json![{}]).await?;
+    Ok(())
+  }
 
+  pub async fn revoke_discord_role(&self, role_id: &str) -> Result<()> {
+    self.state.discord_api().delete(&self.discord_role_path(role_id)?, &serde_json::json![{}]).await?;
     Ok(())
   }
 
+  pub fn wordpress_groups_path(&self) -> Result<String> {
+    let user_id = self.attrs.wordpress_user.as_ref()
+      .ok_or(Error::validation("wordpress_user", "student has no wordpress user yet"))?;
+    Ok(format!("/ldlms/v2/users/{}/groups", user_id))
+  }
+
+  pub fn discord_member_path(&self) -> Result<String> {
+    let discord_user_id = self.attrs.discord_user_id.as_ref()
+      .ok_or(Error::validation("discord_user_id", "student has not linked discord yet"))?;
+    Ok(format!("/guilds/{}/members/{}", self.state.settings.discord.guild_id, discord_user_id))
+  }
+
+  fn discord_role_path(&self, role_id: &str) -> Result<String> {
+    Ok(format!("{}/roles/{}", self.discord_member_path()?, role_id))
+  }
+
   pub async fn send_payment_reminder(&self) -> Result<()> {
-    let maybe_invoice = self.state.invoice().select()
+    let open_invoices = self.state.invoice().select()
       .student_id_eq(self.id())
-      .optional().await?;
+      .paid_eq(&false)
+      .expired_eq(&false)
+      .order_by(InvoiceOrderBy::Id)
+      .all().await?;
+
+    for invoice in open_invoices.iter().filter(|i| i.attrs.notified_on.is_none()) {
+      invoice.send_link(self, 0).await?;
+    }
+
+    Ok(())
+  }
+
+  /* Voids the checkout links the student could still pay, oldest first, and returns them. */
+  pub async fn void_open_invoices(&self) -> Result<Vec<Invoice>> {
+    let open_invoices = self.state.invoice().select()
+      .student_id_eq(self.id())
+      .paid_eq(&false)
+      .expired_eq(&false)
+      .order_by(InvoiceOrderBy::DueSince)
+      .all().await?;
+
+    for invoice in open_invoices.iter() {
+      invoice.void().await?;
+    }
+
+    Ok(open_invoices)
+  }
+
+  /* Voids every open invoice and replaces them with a single one per currency, in the
+   * payment method given or the one the student already had. */
+  pub async fn regenerate_invoices(&mut self, payment_method: Option<PaymentMethod>) -> Result<Vec<Invoice>> {
+    if self.attrs.organization_id.is_some() {
+      return Err(Error::validation("organization_id", "sponsored students are invoiced to their organization"));
+    }
 
-    match maybe_invoice {
-      None => Ok(()),
-      Some(invoice) => {
-        let mut context = tera::Context::new();
-        context.insert("full_name", &self.attrs.full_name);
-        context.insert("checkout_link", &invoice.attrs.url);
-        self.send_email("Acerca de tu pago a DAO Education", "emails/payment_link", &context)
+    let open_invoices = self.void_open_invoices().await?;
+
+    if let Some(method) = payment_method {
+      sqlx::query!(
+        "UPDATE students SET payment_method = $2 WHERE id = $1",
+        self.attrs.id,
+        method as PaymentMethod,
+      ).execute(&self.state.db).await?;
+      self.attrs.payment_method = method;
+    }
+
+    let mut invoices = BillingSummary::new(self.clone()).await?.invoice_all_not_invoiced_yet().await?;
+
+    for invoice in invoices.iter_mut() {
+      if let Some(oldest) = open_invoices.iter().find(|i| i.attrs.currency == invoice.attrs.currency) {
+        invoice.keep_dunning_history(oldest).await?;
       }
+      invoice.send_link(self, invoice.attrs.reminder_count).await?;
     }
+
+    Ok(invoices)
   }
 
-  pub fn send_welcome_email(&mut self) -> Result<()> {
+  /* Links sent to students to manage their own billing carry this token, derived like receipt ones. */
+  pub fn access_token(&self) -> String {
+    access_token_for(&self.state, &format!("student:{}", self.attrs.id))
+  }
+
+  pub fn check_access_token(&self, token: &str) -> Result<()> {
+    check_access_token_for(&self.state, &format!("student:{}", self.attrs.id), token)
+  }
+
+  /* Payment emails link here so students can switch payment method or get a fresh checkout link. */
+  pub fn billing_link(&self) -> String {
+    format!(
+      "{}/billing?student_id={}&token={}",
+      self.state.settings.checkout_domain,
+      self.attrs.id,
+      self.access_token(),
+    )
+  }
+
+  /* Students that keep ignoring payment reminders lose access to everything on_paid granted. */
+  pub async fn mark_delinquent(&mut self) -> Result<()> {
+    let now = Utc::now();
+    sqlx::query!("UPDATE students SET delinquent_since = $2 WHERE id = $1", self.attrs.id, now)
+      .execute(&self.state.db).await?;
+    self.attrs.delinquent_since = Some(now);
+
+    self.reconcile_access(&Access::managed(&self.state).await?, false).await?;
+
     let mut context = tera::Context::new();
     context.insert("full_name", &self.attrs.full_name);
+    self.send_email(Sender::Billing, self.attrs.locale.text(Text::AccessSuspendedSubject), "emails/access_suspended", &context).await
+  }
+
+  /* Called after every payment, access is only restored once nothing is owed in any currency. */
+  pub async fn clear_delinquency_if_settled(&mut self) -> Result<()> {
+    if self.attrs.delinquent_since.is_none() {
+      return Ok(());
+    }
+
+    let billing = BillingSummary::new(self.clone()).await?;
+    if billing.balances.iter().any(|b| b.balance.is_sign_negative() && !b.balance.is_zero()) {
+      return Ok(());
+    }
+
+    sqlx::query!("UPDATE students SET delinquent_since = NULL WHERE id = $1", self.attrs.id)
+      .execute(&self.state.db).await?;
+    self.attrs.delinquent_since = None;
+
+    self.reconcile_access(&Access::managed(&self.state).await?, false).await?;
+    Ok(())
+  }
+
+  pub async fn send_welcome_email(&mut self, program: Program, new_wordpress_account: bool) -> Result<()> {
+    let mut context = tera::Context::new();
+    context.insert("full_name", &self.attrs.full_name);
+    context.insert("program", program.name());
     context.insert("email", &self.attrs.email);
-    context.insert("password", &self.attrs.wordpress_initial_password);
+    context.insert("password_reset_link", &self.state.settings.wordpress.lost_password_url());
+    context.insert("new_wordpress_account", &new_wordpress_account);
     context.insert("discord_verification_link", &self.discord_verification_link());
-    self.send_email("Te damos la bienvenida a DAO Education", "emails/welcome", &context)
+    self.send_email(Sender::Default, self.attrs.locale.text(Text::WelcomeSubject), "emails/welcome", &context).await
   }
 
-  fn send_email(&self, subject: &str, template: &str, context: &tera::Context) -> Result<()> {
-    let html = TEMPLATES.render(template, &context)?;
+  async fn send_email(&self, from: Sender, subject: &str, template: &str, context: &tera::Context) -> Result<()> {
+    self.state.send_email(from, self.recipient(), subject, template, context).await?;
+    Ok(())
+  }
+}
 
-    ureq::post("https://api.sendinblue.com/v3/smtp/email")
-      .set("api-key", &self.state.settings.sendinblue.api_key)
-      .send_json(serde_json::json!({
-        "sender": {
-          "name": "DAO Education",
-          "email": "dao.education@constata.eu",
-        },
-        "to": [{
-          "email": &self.attrs.email,
-          "name": &self.attrs.full_name,
-        }],
-        "replyTo":{"email":"info@dao.education"},
-        "subject": subject,
-        "htmlContent": html
-      }))?;
+/* WordPress logins are shown in urls and comments, so they're kept to lowercase ascii.
+ * The name alone is tried first, then made unique with the student id. */
+pub fn wordpress_usernames(full_name: &str, email: &str, student_id: i32) -> Vec<String> {
+  let slug = |text: &str| -> String {
+    let mut slug = String::new();
+    for c in text.to_lowercase().chars() {
+      let c = match c {
+        'á' | 'à' | 'â' | 'ã' | 'ä' => 'a',
+        'é' | 'è' | 'ê' | 'ë' => 'e',
+        'í' | 'ì' | 'î' | 'ï' => 'i',
+        'ó' | 'ò' | 'ô' | 'õ' | 'ö' => 'o',
+        'ú' | 'ù' | 'û' | 'ü' => 'u',
+        'ñ' => 'n',
+        'ç' => 'c',
+        c => c,
+      };
+      if c.is_ascii_alphanumeric() {
+        slug.push(c);
+      } else if !slug.is_empty() && !slug.ends_with('.') {
+        slug.push('.');
+      }
+    }
+    slug.trim_end_matches('.').to_string()
+  };
 
-    Ok(())
+  let mut base = slug(full_name);
+  if base.is_empty() {
+    base = slug(email.split('@').next().unwrap_or(""));
+  }
+  if base.is_empty() {
+    base = "student".to_string();
   }
+  base.truncate(50);
+
+  vec![
+    base.clone(),
+    format!("{}.{}", base, student_id),
+    format!("{}.{}.{}", base, student_id, gen_passphrase().replace('+', ".")),
+  ]
 }
 
 impl StudentHub {
-  pub async fn process_discord_response(&self, discord: DiscordToken) -> Result<String> {
+  /* The student is found by the state before the code is exchanged, so a code obtained
+   * for someone else's account can't be attached to this student. */
+  pub async fn process_discord_response(&self, authorization: DiscordAuthorization) -> Result<String> {
     let conf = &self.state.settings.discord;
-    let student = self.select().discord_verification_eq(&Some(discord.state.clone())).one().await?;
-    let profile: DiscordProfile = ureq::get("https://discord.com/api/v9/users/@me")
-      .set("Authorization", &format!("Bearer {}", discord.access_token))
-      .call()?
-      .into_json()?;
+    if authorization.state.is_empty() {
+      return Err(Error::invalid("state", ValidationMessage::InvalidAccessToken));
+    }
+    let student = self.select()
+      .discord_verification_eq(&Some(authorization.state.clone()))
+      .optional().await?
+      .ok_or_else(|| Error::invalid("state", ValidationMessage::InvalidAccessToken))?;
+
+    let token = self.state.discord_grant().exchange_code(&authorization.code).await?;
+    let profile: DiscordProfile = self.state.discord_user_api(&token.access_token)
+      .get("/users/@me").await?
+      .json()?;
 
     let handle = format!("{}#{}", &profile.username, &profile.discriminator);
 
-    let member_url = format!("https://discord.com/api/v9/guilds/{}/members/{}", conf.guild_id, profile.id);
+    let mut grant = self.state.discord_grant().store(student.attrs.id, &profile.id, &token).await?;
+    let membership = grant.join_guild().await?;
+    rocket::info!("Student {} linked discord user {}: {:?}", student.attrs.id, profile.id, membership);
+
+    let bot = self.state.discord_api();
+    let member_path = format!("/guilds/{}/members/{}", conf.guild_id, profile.id);
+    bot.put(&format!("{}/roles/{}", &member_path, conf.student_role_id), &serde_json::json![{}]).await?;
 
-    let _ignored_because_it_may_be_member = ureq::request("PUT", &member_url)
-      .set("Authorization", &format!("Bot {}", conf.bot_secret_token))
-      .send_json(serde_json::json![{"access_token": discord.access_token}])?;
+    for subscription in student.subscriptions().await?.iter().filter(|s| s.attrs.paid) {
+      let mut role_ids = vec![conf.program_role_ids.get(subscription.attrs.program).clone()];
+      if let Some(role_id) = subscription.cohort().await?.and_then(|c| c.attrs.discord_role_id) {
+        role_ids.push(role_id);
+      }
 
-    let _ignored_because_it_may_have_role =
-      ureq::request("PUT", &format!("{}/roles/{}", &member_url, conf.student_role_id))
-        .set("Authorization", &format!("Bot {}", conf.bot_secret_token))
-        .send_json(serde_json::json![{}])?;
+      for role_id in role_ids {
+        bot.put(&format!("{}/roles/{}", &member_path, role_id), &serde_json::json![{}]).await?;
+      }
+    }
 
     sqlx::query!(
       "UPDATE students SET discord_handle = $2, discord_user_id = $3 WHERE id = $1",
@@ -222,3 +516,20 @@ impl StudentHub {
     Ok(handle)
   }
 }
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn makes_slug_safe_wordpress_usernames() {
+    let usernames = wordpress_usernames("Juan Pérez Muñoz", "juan@example.com", 7);
+    assert_eq!(usernames[0], "juan.perez.munoz");
+    assert_eq!(usernames[1], "juan.perez.munoz.7");
+    assert!(usernames[2].starts_with("juan.perez.munoz.7."));
+
+    assert_eq!(wordpress_usernames("  ¡Hola!  ", "x@example.com", 1)[0], "hola");
+    assert_eq!(wordpress_usernames("李小龍", "bruce.lee+dao@example.com", 1)[0], "bruce.lee.dao");
+    assert_eq!(wordpress_usernames("李小龍", "@example.com", 1)[0], "student");
+  }
+}
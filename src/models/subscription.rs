@@ -13,8 +13,17 @@ make_sqlx_model!{
     #[sqlx_search_as(boolean)]
     active: bool,
     price: Decimal,
+    list_price: Decimal,
+    #[sqlx_search_as(currency)]
+    currency: Currency,
+    #[sqlx_search_as(int4)]
+    scholarship_id: Option<i32>,
     paid: bool,
     plan_code: PlanCode,
+    #[sqlx_search_as(program)]
+    program: Program,
+    #[sqlx_search_as(int4)]
+    cohort_id: Option<i32>,
     paid_at: Option<UtcDateTime>,
     #[sqlx_search_as(varchar)]
     stripe_subscription_id: Option<String>,
@@ -25,8 +34,59 @@ impl Subscription {
   pub async fn on_paid(&self) -> Result<()> {
     let mut student = self.state.student().find(self.student_id()).await?;
     student.setup_discord_verification().await?;
-    student.setup_wordpress().await?;
-    student.send_welcome_email()?;
+    let new_wordpress_account = student.setup_wordpress().await?;
+    student.sync_wordpress_groups().await?;
+    if student.attrs.discord_user_id.is_some() {
+      student.grant_program_discord_role(self.attrs.program).await?;
+    }
+    if let Some(cohort) = self.cohort().await? {
+      cohort.grant_access(&student).await?;
+    }
+    student.send_welcome_email(self.attrs.program, new_wordpress_account).await?;
+
+    Ok(())
+  }
+
+  pub async fn cohort(&self) -> Result<Option<Cohort>> {
+    match self.attrs.cohort_id {
+      Some(id) => Ok(Some(self.state.cohort().find(&id).await?)),
+      None => Ok(None),
+    }
+  }
+
+  pub async fn move_to_cohort(&mut self, cohort: &Cohort, force: bool) -> Result<()> {
+    cohort.validate_program(self.attrs.program)?;
+
+    if self.attrs.cohort_id == Some(cohort.attrs.id) {
+      return Ok(());
+    }
+
+    let seat_lock = cohort.lock_seats().await?;
+    if !force && cohort.seats_left().await? == 0 {
+      return Err(Error::invalid("cohort_id", ValidationMessage::CohortIsFull));
+    }
+
+    let previous = self.cohort().await?;
+
+    sqlx::query!(
+      "UPDATE subscriptions SET cohort_id = $2 WHERE id = $1",
+      self.attrs.id,
+      cohort.attrs.id,
+    ).execute(&self.state.db).await?;
+    self.attrs.cohort_id = Some(cohort.attrs.id);
+    seat_lock.commit().await?;
+
+    if self.attrs.paid {
+      let student = self.state.student().find(self.student_id()).await?;
+      if let Some(ref p) = previous {
+        p.revoke_access(&student).await?;
+      }
+      cohort.grant_access(&student).await?;
+    }
+
+    if let Some(p) = previous {
+      self.state.waitlist_entry().invite_next_for(&p).await?;
+    }
 
     Ok(())
   }
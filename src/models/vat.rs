@@ -0,0 +1,381 @@
+use crate::error::Result;
+use crate::http::{ApiClient, Integration};
+use super::*;
+
+pub const EU_COUNTRIES: [&str; 27] = [
+  "AT", "BE", "BG", "HR", "CY", "CZ", "DK", "EE", "FI", "FR", "DE", "GR", "HU", "IE",
+  "IT", "LV", "LT", "LU", "MT", "NL", "PL", "PT", "RO", "SK", "SI", "ES", "SE",
+];
+
+pub fn is_eu(country: &str) -> bool {
+  EU_COUNTRIES.contains(&country)
+}
+
+/* VAT numbers use the ISO country code, except for Greece. */
+fn vat_prefix(country: &str) -> &str {
+  match country {
+    "GR" => "EL",
+    other => other,
+  }
+}
+
+fn country_for_prefix(prefix: &str) -> Option<&'static str> {
+  let country = if prefix == "EL" { "GR" } else { prefix };
+  EU_COUNTRIES.iter().find(|c| **c == country).cloned()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VatNumber {
+  pub country: String,
+  pub number: String,
+}
+
+impl VatNumber {
+  /* Students and organizations write anything in tax_number: a national id, a foreign
+   * tax id, or an EU VAT number with or without its prefix.
+   * Only customers in the EU can have a VAT number, anyone else keeps their tax number as is,
+   * even if it happens to start with an EU prefix like a Mexican RFC does.
+   * A number with an explicit EU prefix must be valid. Otherwise we only treat it as a
+   * VAT number if it validates for the customer country, and leave it alone if not. */
+  pub fn parse(country: &str, tax_number: Option<&str>) -> Result<Option<VatNumber>> {
+    let normalized: String = match tax_number {
+      Some(t) => t.chars().filter(|c| c.is_ascii_alphanumeric()).collect::<String>().to_uppercase(),
+      None => return Ok(None),
+    };
+
+    if !is_eu(country) {
+      return Ok(None);
+    }
+
+    if normalized.len() > 2 {
+      if let Some(prefixed_country) = country_for_prefix(&normalized[..2]) {
+        let vat = VatNumber{ country: prefixed_country.to_string(), number: normalized[2..].to_string() };
+        if !vat.is_valid() {
+          return Err(Error::invalid("tax_number", ValidationMessage::InvalidVatNumber));
+        }
+        return Ok(Some(vat));
+      }
+    }
+
+    let vat = VatNumber{ country: country.to_string(), number: normalized };
+    Ok(if vat.is_valid() { Some(vat) } else { None })
+  }
+
+  pub fn is_valid(&self) -> bool {
+    let n = self.number.as_str();
+    let digits = |len: usize| n.len() == len && all_digits(n);
+
+    match vat_prefix(&self.country) {
+      "AT" => n.len() == 9 && n.starts_with('U') && all_digits(&n[1..]) && check_at(&n[1..]),
+      "BE" => digits(10) && (n.starts_with('0') || n.starts_with('1')) && check_be(n),
+      "DE" => digits(9) && check_de(n),
+      "DK" => digits(8) && check_weighted(n, &[2, 7, 6, 5, 4, 3, 2, 1]) % 11 == 0,
+      "ES" => n.len() == 9 && check_es(n),
+      "FI" => digits(8) && check_fi(n),
+      "FR" => n.len() == 11 && all_digits(&n[2..]) && check_fr(n),
+      "IT" => digits(11) && luhn(n),
+      "LU" => digits(8) && number(&n[..6]) % 89 == number(&n[6..]),
+      "NL" => n.len() == 12 && all_digits(&n[..9]) && &n[9..10] == "B" && all_digits(&n[10..]) && check_nl(n),
+      "PL" => digits(10) && check_weighted(n, &[6, 5, 7, 2, 3, 4, 5, 6, 7]) % 11 == digit(n, 9),
+      "PT" => digits(9) && check_pt(n),
+      "SE" => digits(12) && n.ends_with("01") && luhn(&n[..10]),
+      "BG" => (n.len() == 9 || n.len() == 10) && all_digits(n),
+      "CY" => n.len() == 9 && all_digits(&n[..8]) && n.chars().last().map(|c| c.is_ascii_alphabetic()).unwrap_or(false),
+      "CZ" => (8..=10).contains(&n.len()) && all_digits(n),
+      "EE" | "EL" => digits(9),
+      "HR" => digits(11),
+      "HU" | "MT" | "SI" => digits(8),
+      "IE" => (n.len() == 8 || n.len() == 9) && n.chars().next().map(|c| c.is_ascii_digit()).unwrap_or(false),
+      "LT" => (n.len() == 9 || n.len() == 12) && all_digits(n),
+      "LV" => digits(11),
+      "RO" => (2..=10).contains(&n.len()) && all_digits(n),
+      "SK" => digits(10),
+      _ => false,
+    }
+  }
+
+  /* The online check asks the VIES service when configured. Without it, or when the
+   * service can't be reached, a number that passes the local checks is accepted. */
+  pub async fn verify_online(&self, site: &Site) -> Result<()> {
+    let base_url = match site.settings.invoicing.vat_check_url {
+      Some(ref url) => url,
+      None => return Ok(()),
+    };
+
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct ViesResponse {
+      is_valid: bool,
+    }
+
+    let response = ApiClient::new(&site.http, Integration::Vies, base_url)
+      .get(&format!("/ms/{}/vat/{}", vat_prefix(&self.country), self.number))
+      .await
+      .ok()
+      .and_then(|r| r.json::<ViesResponse>().ok());
+
+    match response {
+      Some(ViesResponse{ is_valid: false }) => Err(Error::invalid("tax_number", ValidationMessage::VatNumberNotInVies)),
+      _ => Ok(()),
+    }
+  }
+
+  /* Parses and verifies, returning the normalized number to store. */
+  pub async fn validate(site: &Site, country: &str, tax_number: Option<&str>) -> Result<Option<String>> {
+    match VatNumber::parse(country, tax_number)? {
+      Some(vat) => {
+        vat.verify_online(site).await?;
+        Ok(Some(vat.to_string()))
+      },
+      None => Ok(None),
+    }
+  }
+}
+
+impl std::fmt::Display for VatNumber {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}{}", vat_prefix(&self.country), self.number)
+  }
+}
+
+fn all_digits(s: &str) -> bool {
+  !s.is_empty() && s.chars().all(|c| c.is_ascii_digit())
+}
+
+fn digit(s: &str, i: usize) -> u64 {
+  (s.as_bytes()[i] - b'0') as u64
+}
+
+fn number(s: &str) -> u64 {
+  s.parse().unwrap_or(0)
+}
+
+fn check_weighted(s: &str, weights: &[u64]) -> u64 {
+  weights.iter().enumerate().map(|(i, w)| digit(s, i) * w).sum()
+}
+
+fn luhn(s: &str) -> bool {
+  let sum: u64 = s.bytes().rev().enumerate().map(|(i, b)| {
+    let d = (b - b'0') as u64;
+    if i % 2 == 1 {
+      let doubled = d * 2;
+      if doubled > 9 { doubled - 9 } else { doubled }
+    } else {
+      d
+    }
+  }).sum();
+  sum % 10 == 0
+}
+
+fn check_at(s: &str) -> bool {
+  let sum: u64 = (0..7).map(|i| {
+    let product = digit(s, i) * if i % 2 == 0 { 1 } else { 2 };
+    product / 10 + product % 10
+  }).sum();
+  (10 - (sum + 4) % 10) % 10 == digit(s, 7)
+}
+
+fn check_be(s: &str) -> bool {
+  97 - number(&s[..8]) % 97 == number(&s[8..])
+}
+
+fn check_de(s: &str) -> bool {
+  let mut product = 10;
+  for i in 0..8 {
+    let mut sum = (digit(s, i) + product) % 10;
+    if sum == 0 {
+      sum = 10;
+    }
+    product = (2 * sum) % 11;
+  }
+  let check = 11 - product;
+  (if check == 10 { 0 } else { check }) == digit(s, 8)
+}
+
+fn check_es(s: &str) -> bool {
+  const DNI_LETTERS: &[u8] = b"TRWAGMYFPDXBNJZSQVHLCKE";
+  let first = s.as_bytes()[0];
+  let last = s.as_bytes()[8];
+
+  /* Individuals: DNI, or NIE where the leading X, Y or Z stands for 0, 1 or 2. */
+  let dni_digits = match first {
+    b'0'..=b'9' => Some(s[..8].to_string()),
+    b'X' => Some(format!("0{}", &s[1..8])),
+    b'Y' => Some(format!("1{}", &s[1..8])),
+    b'Z' => Some(format!("2{}", &s[1..8])),
+    _ => None,
+  };
+
+  if let Some(d) = dni_digits {
+    return all_digits(&d) && DNI_LETTERS[(number(&d) % 23) as usize] == last;
+  }
+
+  /* Companies: CIF, with a control digit or letter. */
+  if !first.is_ascii_alphabetic() || !all_digits(&s[1..8]) {
+    return false;
+  }
+  let sum: u64 = (0..7).map(|i| {
+    let d = digit(&s[1..8], i);
+    if i % 2 == 0 {
+      let doubled = d * 2;
+      doubled / 10 + doubled % 10
+    } else {
+      d
+    }
+  }).sum();
+  let control = ((10 - sum % 10) % 10) as usize;
+  last == b'0' + control as u8 || last == b"JABCDEFGHI"[control]
+}
+
+fn check_fi(s: &str) -> bool {
+  let rest = check_weighted(s, &[7, 9, 10, 5, 8, 4, 2]) % 11;
+  rest != 1 && (if rest == 0 { 0 } else { 11 - rest }) == digit(s, 7)
+}
+
+/* Numeric keys are derived from the SIREN, alphanumeric keys can only be checked online. */
+fn check_fr(s: &str) -> bool {
+  let key = &s[..2];
+  if !all_digits(key) {
+    return key.chars().all(|c| c.is_ascii_alphanumeric());
+  }
+  number(key) == (12 + 3 * (number(&s[2..]) % 97)) % 97
+}
+
+/* Old numbers derive from the BSN and pass the 11 check, newer ones pass ISO 7064 mod 97 over the full string. */
+fn check_nl(s: &str) -> bool {
+  let eleven = check_weighted(s, &[9, 8, 7, 6, 5, 4, 3, 2]) % 11 == digit(s, 8);
+
+  let expanded: String = format!("NL{}", s).chars().map(|c| {
+    if c.is_ascii_alphabetic() { (c as u32 - 'A' as u32 + 10).to_string() } else { c.to_string() }
+  }).collect();
+  let mod97 = expanded.bytes().fold(0u64, |acc, b| (acc * 10 + (b - b'0') as u64) % 97) == 1;
+
+  eleven || mod97
+}
+
+fn check_pt(s: &str) -> bool {
+  let check = 11 - check_weighted(s, &[9, 8, 7, 6, 5, 4, 3, 2]) % 11;
+  (if check >= 10 { 0 } else { check }) == digit(s, 8)
+}
+
+#[derive(sqlx::Type, Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[sqlx(type_name = "tax_treatment", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum TaxTreatment {
+  Domestic,
+  EuConsumer,
+  ReverseCharge,
+  Export,
+}
+
+/* How a customer is taxed. It never changes what they pay, only how the total
+ * splits between taxable base and tax. */
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct TaxAssessment {
+  pub treatment: TaxTreatment,
+  pub rate: Decimal,
+}
+
+impl TaxAssessment {
+  pub fn split(&self, total: Decimal) -> (Decimal, Decimal) {
+    split_tax(total, self.rate)
+  }
+
+  pub fn note(&self, total: Decimal, currency: Currency) -> String {
+    match self.treatment {
+      TaxTreatment::ReverseCharge => "Operación con inversión del sujeto pasivo".to_string(),
+      _ if self.rate.is_zero() => "Operación no sujeta a IVA".to_string(),
+      _ => format!("IVA incluido ({}%): {} {}", (self.rate * Decimal::ONE_HUNDRED).normalize(), self.split(total).1, currency.symbol()),
+    }
+  }
+}
+
+impl InvoicingSettings {
+  pub fn assess(&self, country: &str, vat_number: Option<&str>) -> TaxAssessment {
+    let treatment = if country == self.issuer_country {
+      TaxTreatment::Domestic
+    } else if is_eu(country) && vat_number.is_some() {
+      TaxTreatment::ReverseCharge
+    } else if is_eu(country) {
+      TaxTreatment::EuConsumer
+    } else {
+      TaxTreatment::Export
+    };
+
+    let rate = match treatment {
+      TaxTreatment::ReverseCharge => Decimal::ZERO,
+      _ => self.tax_rate_for(country),
+    };
+
+    TaxAssessment{ treatment, rate }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn vat(country: &str, number: &str) -> VatNumber {
+    VatNumber{ country: country.to_string(), number: number.to_string() }
+  }
+
+  #[test]
+  fn validates_vat_number_checksums() {
+    for (country, number) in [
+      ("AT", "U13585627"), ("BE", "0403170701"), ("DE", "136695976"), ("DK", "13585628"),
+      ("ES", "B58378431"), ("ES", "12345678Z"), ("FI", "20774740"), ("FR", "40303265045"),
+      ("IT", "00743110157"), ("LU", "15027442"), ("NL", "004495445B01"), ("PL", "5260250274"),
+      ("PT", "501964843"), ("SE", "556188840401"),
+    ] {
+      assert!(vat(country, number).is_valid(), "{}{} should be valid", country, number);
+    }
+
+    assert!(!vat("DE", "136695977").is_valid());
+    assert!(!vat("ES", "12345678A").is_valid());
+    assert!(!vat("IT", "00743110158").is_valid());
+    assert!(!vat("NL", "004495445C01").is_valid());
+  }
+
+  #[test]
+  fn parses_tax_numbers_as_vat_numbers() {
+    assert_eq!(VatNumber::parse("DE", Some("de 136.695.976")).unwrap(), Some(vat("DE", "136695976")));
+    assert_eq!(VatNumber::parse("DE", Some("136695976")).unwrap(), Some(vat("DE", "136695976")));
+    assert_eq!(VatNumber::parse("GR", Some("EL094259216")).unwrap().unwrap().to_string(), "EL094259216");
+    assert_eq!(VatNumber::parse("AR", Some("20-12345678-3")).unwrap(), None);
+    assert_eq!(VatNumber::parse("MX", Some("DEGJ800101ABC")).unwrap(), None);
+    assert_eq!(VatNumber::parse("US", Some("DE136695977")).unwrap(), None);
+    assert_eq!(VatNumber::parse("DE", Some("personal id")).unwrap(), None);
+    assert_eq!(VatNumber::parse("DE", None).unwrap(), None);
+    assert!(VatNumber::parse("DE", Some("DE136695977")).is_err());
+  }
+
+  #[test]
+  fn assesses_tax_treatment() {
+    let settings = InvoicingSettings{
+      issuer_name: "DAO Education".into(),
+      issuer_tax_number: "B00000000".into(),
+      issuer_address: "Madrid".into(),
+      issuer_country: "ES".into(),
+      series_prefix: "A".into(),
+      default_tax_rate: Decimal::ZERO,
+      tax_rates: vec![
+        ("ES".to_string(), Decimal::new(21, 2)),
+        ("DE".to_string(), Decimal::new(19, 2)),
+      ].into_iter().collect(),
+      vat_check_url: None,
+    };
+
+    let domestic = settings.assess("ES", Some("ESB58378431"));
+    assert_eq!(domestic, TaxAssessment{ treatment: TaxTreatment::Domestic, rate: Decimal::new(21, 2) });
+
+    let consumer = settings.assess("DE", None);
+    assert_eq!(consumer, TaxAssessment{ treatment: TaxTreatment::EuConsumer, rate: Decimal::new(19, 2) });
+
+    let business = settings.assess("DE", Some("DE136695976"));
+    assert_eq!(business, TaxAssessment{ treatment: TaxTreatment::ReverseCharge, rate: Decimal::ZERO });
+    assert_eq!(business.split(Decimal::new(300, 0)), (Decimal::new(300, 0), Decimal::ZERO));
+
+    let export = settings.assess("AR", None);
+    assert_eq!(export, TaxAssessment{ treatment: TaxTreatment::Export, rate: Decimal::ZERO });
+  }
+}
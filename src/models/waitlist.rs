@@ -0,0 +1,275 @@
+use crate::error::Result;
+use super::*;
+use chrono::Duration;
+
+make_sqlx_model!{
+  state: Site,
+  table: waitlist_entries,
+  struct WaitlistEntry {
+    #[sqlx_search_as(int4)]
+    id: i32,
+    #[sqlx_search_as(int4)]
+    cohort_id: Option<i32>,
+    #[sqlx_search_as(program)]
+    program: Program,
+    created_at: UtcDateTime,
+    country: String,
+    #[sqlx_search_as(varchar)]
+    email: String,
+    full_name: String,
+    phone: Option<String>,
+    tax_number: Option<String>,
+    tax_address: Option<String>,
+    referral_code: Option<String>,
+    payment_method: PaymentMethod,
+    #[sqlx_search_as(waitlist_status)]
+    status: WaitlistStatus,
+    #[sqlx_search_as(varchar)]
+    invitation_token: Option<String>,
+    invited_at: Option<UtcDateTime>,
+    invitation_expires_at: Option<UtcDateTime>,
+    locale: Locale,
+  }
+}
+
+#[derive(sqlx::Type, Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[sqlx(type_name = "waitlist_status", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum WaitlistStatus {
+  Waiting,
+  Invited,
+  Expired,
+  Accepted,
+}
+
+impl WaitlistEntryHub {
+  pub async fn join(&self, form: PublicStudentForm, country: &Country) -> Result<WaitlistEntry> {
+    let existing = self.select()
+      .email_eq(&form.email)
+      .program_eq(&form.program)
+      .status_eq(&WaitlistStatus::Waiting)
+      .optional().await?;
+
+    if let Some(entry) = existing {
+      return Ok(entry);
+    }
+
+    VatNumber::parse(&country.0, form.tax_number.as_deref())?;
+
+    Ok(self.insert().use_struct(InsertWaitlistEntry{
+      cohort_id: form.cohort_id,
+      program: form.program,
+      created_at: Utc::now(),
+      country: country.0.clone(),
+      email: form.email,
+      full_name: form.full_name,
+      phone: form.phone,
+      tax_number: form.tax_number,
+      tax_address: form.tax_address,
+      referral_code: form.referral_code,
+      payment_method: form.payment_method,
+      status: WaitlistStatus::Waiting,
+      invitation_token: None,
+      invited_at: None,
+      invitation_expires_at: None,
+      locale: form.locale.unwrap_or_else(|| Locale::from_country(&country.0)),
+    }).save().await?)
+  }
+
+  pub async fn by_invitation_token(&self, token: &str) -> sqlx::Result<WaitlistEntry> {
+    self.select().invitation_token_eq(&Some(token.to_string())).one().await
+  }
+
+  /* Expires stale invitations and then offers every free seat to the next person in line.
+   * This is run periodically, and also right after a seat is known to be freed.
+   * A failure with one cohort doesn't stop the others. */
+  pub async fn process(&self) -> Result<()> {
+    self.expire_invitations().await?;
+
+    for program in Program::all() {
+      for cohort in self.state.cohort().open(program).await? {
+        if let Err(e) = self.invite_next_for(&cohort).await {
+          rocket::warn!("Waitlist processing failed for cohort {}: {:?}", cohort.attrs.id, e);
+        }
+      }
+    }
+
+    Ok(())
+  }
+
+  pub async fn expire_invitations(&self) -> Result<()> {
+    let invited = self.select().status_eq(&WaitlistStatus::Invited).all().await?;
+    let now = Utc::now();
+
+    for mut entry in invited.into_iter() {
+      if entry.attrs.invitation_expires_at.map(|e| e < now).unwrap_or(true) {
+        entry.set_status(WaitlistStatus::Expired).await?;
+      }
+    }
+
+    Ok(())
+  }
+
+  /* Entries waiting for this specific cohort, or for any cohort of its program, in order of arrival.
+   * Someone we can't email keeps waiting, and the seat goes to the next person in line. */
+  pub async fn invite_next_for(&self, cohort: &Cohort) -> Result<()> {
+    if !cohort.attrs.enrollment_open {
+      return Ok(());
+    }
+
+    let waiting = self.select()
+      .program_eq(&cohort.attrs.program)
+      .status_eq(&WaitlistStatus::Waiting)
+      .order_by(WaitlistEntryOrderBy::CreatedAt)
+      .all().await?;
+
+    let mut candidates = waiting.into_iter()
+      .filter(|e| e.attrs.cohort_id.map(|id| id == cohort.attrs.id).unwrap_or(true));
+
+    let seat_lock = cohort.lock_seats().await?;
+    while cohort.seats_left().await? > 0 {
+      match candidates.next() {
+        Some(mut entry) => if let Err(e) = entry.invite(cohort).await {
+          rocket::warn!("Could not invite waitlist entry {}: {:?}", entry.attrs.id, e);
+        },
+        None => break,
+      }
+    }
+    seat_lock.commit().await?;
+
+    Ok(())
+  }
+}
+
+impl WaitlistEntry {
+  pub fn public_student_form(&self) -> PublicStudentForm {
+    PublicStudentForm{
+      email: self.attrs.email.clone(),
+      full_name: self.attrs.full_name.clone(),
+      phone: self.attrs.phone.clone(),
+      tax_number: self.attrs.tax_number.clone(),
+      tax_address: self.attrs.tax_address.clone(),
+      referral_code: self.attrs.referral_code.clone(),
+      payment_method: self.attrs.payment_method,
+      program: self.attrs.program,
+      cohort_id: self.attrs.cohort_id,
+      scholarship: None,
+      locale: Some(self.attrs.locale),
+    }
+  }
+
+  pub fn invitation_link(&self) -> Option<String> {
+    self.attrs.invitation_token.as_ref().and_then(|token| self.invitation_link_for(token))
+  }
+
+  /* Passphrases are joined with "+", which has to be escaped to survive the query string. */
+  fn invitation_link_for(&self, token: &str) -> Option<String> {
+    let mut url = reqwest::Url::parse(&format!("{}/waitlist-invitation", self.state.settings.checkout_domain)).ok()?;
+    url.query_pairs_mut().append_pair("token", token);
+    Some(url.to_string())
+  }
+
+  /* The email goes out first, an invitation is only stored once the person could actually receive it.
+   * If sending fails the entry keeps waiting and the next run tries again. */
+  pub async fn invite(&mut self, cohort: &Cohort) -> Result<()> {
+    let now = Utc::now();
+    let expires_at = now + Duration::hours(self.state.settings.waitlist_invitation_hours);
+    let token = gen_passphrase();
+
+    let mut context = tera::Context::new();
+    context.insert("full_name", &self.attrs.full_name);
+    context.insert("program", self.attrs.program.name());
+    context.insert("cohort", &cohort.attrs.name);
+    context.insert("invitation_link", &self.invitation_link_for(&token));
+    context.insert("expires_at", &expires_at.format("%d/%m/%Y %H:%M UTC").to_string());
+    self.state.send_email(
+      Sender::Default,
+      Recipient::new(&self.attrs.email, &self.attrs.full_name, self.attrs.locale),
+      self.attrs.locale.text(Text::WaitlistInvitationSubject),
+      "emails/waitlist_invitation",
+      &context,
+    ).await?;
+
+    sqlx::query!(
+      "UPDATE waitlist_entries SET status = 'invited', cohort_id = $2, invitation_token = $3, invited_at = $4, invitation_expires_at = $5 WHERE id = $1",
+      self.attrs.id,
+      cohort.attrs.id,
+      token,
+      now,
+      expires_at,
+    ).execute(&self.state.db).await?;
+
+    self.attrs.status = WaitlistStatus::Invited;
+    self.attrs.cohort_id = Some(cohort.attrs.id);
+    self.attrs.invitation_token = Some(token);
+    self.attrs.invited_at = Some(now);
+    self.attrs.invitation_expires_at = Some(expires_at);
+    Ok(())
+  }
+
+  /* Signs up the invited person with the data they left when joining the waitlist.
+   * Everything that can be checked upfront is checked before touching the invitation.
+   * Then, holding the cohort's seat lock, the invitation hands its seat over to the new subscription.
+   * Should the signup still fail, the invitation is put back as it was. */
+  pub async fn accept(mut self) -> Result<Student> {
+    let still_valid = self.attrs.status == WaitlistStatus::Invited &&
+      self.attrs.invitation_expires_at.map(|e| e > Utc::now()).unwrap_or(false);
+
+    if !still_valid {
+      return Err(Error::invalid("invitation", ValidationMessage::InvitationNoLongerValid));
+    }
+
+    let form = self.public_student_form();
+    let program = form.program;
+    let country = Country(self.attrs.country.clone());
+    let cohort = self.state.cohort().for_enrollment(program, form.cohort_id).await?;
+    let insert_student = form.into_insert_student(&country, &self.state).await?;
+    let seat_lock = match cohort {
+      Some(ref c) => Some(c.lock_seats().await?),
+      None => None,
+    };
+
+    /* Only one of two concurrent accepts gets past this. */
+    let consumed = sqlx::query!(
+      "UPDATE waitlist_entries SET status = 'accepted' WHERE id = $1 AND status = 'invited'",
+      self.attrs.id,
+    ).execute(&self.state.db).await?.rows_affected();
+
+    if consumed == 0 {
+      return Err(Error::invalid("invitation", ValidationMessage::InvitationNoLongerValid));
+    }
+    self.attrs.status = WaitlistStatus::Accepted;
+
+    let signup = self.state.student().insert()
+      .use_struct(insert_student)
+      .save_and_subscribe(country.plan(program), program, cohort.as_ref()).await;
+
+    let student = match signup {
+      Ok(student) => student,
+      Err(e) => {
+        self.set_status(WaitlistStatus::Invited).await?;
+        return Err(e);
+      }
+    };
+
+    if let Some(lock) = seat_lock {
+      lock.commit().await?;
+    }
+
+    let billing = BillingSummary::new(student).await?;
+    billing.invoice_all_not_invoiced_yet().await?;
+    billing.student.send_payment_reminder().await?;
+
+    Ok(billing.student)
+  }
+
+  async fn set_status(&mut self, status: WaitlistStatus) -> Result<()> {
+    sqlx::query!(
+      "UPDATE waitlist_entries SET status = $2 WHERE id = $1",
+      self.attrs.id,
+      status as WaitlistStatus,
+    ).execute(&self.state.db).await?;
+    self.attrs.status = status;
+    Ok(())
+  }
+}
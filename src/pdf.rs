@@ -0,0 +1,207 @@
+use crate::{error::Result, templating};
+
+/* A minimal PDF writer for our invoices and receipts.
+ * Templates render to plain text with a tiny markup, one line per PDF line:
+ *   "# Title"       large bold line
+ *   "## Subtitle"   bold line
+ *   "a | b | c"     table row, the first column is wider, the rest are right side columns
+ *   ""              vertical space
+ * Only the standard Helvetica fonts are used, so nothing needs to be embedded. */
+
+const PAGE_WIDTH: f32 = 595.0;
+const PAGE_HEIGHT: f32 = 842.0;
+const MARGIN: f32 = 50.0;
+const WRAP_AT: usize = 95;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Style {
+  Title,
+  Subtitle,
+  Body,
+}
+
+impl Style {
+  fn font(&self) -> &'static str {
+    match self {
+      Style::Body => "F1",
+      _ => "F2",
+    }
+  }
+
+  fn size(&self) -> f32 {
+    match self {
+      Style::Title => 16.0,
+      Style::Subtitle => 12.0,
+      Style::Body => 10.0,
+    }
+  }
+
+  fn leading(&self) -> f32 {
+    self.size() * 1.5
+  }
+}
+
+pub fn render(template: &str, context: &tera::Context) -> Result<Vec<u8>> {
+  Ok(from_text(&templating::render(template, context)?))
+}
+
+pub fn from_text(text: &str) -> Vec<u8> {
+  let mut pages: Vec<Vec<u8>> = vec![];
+  let mut page: Vec<u8> = vec![];
+  let mut y = PAGE_HEIGHT - MARGIN;
+
+  for raw in text.lines() {
+    let raw = raw.trim_end();
+    let (style, line) = if let Some(rest) = raw.strip_prefix("# ") {
+      (Style::Title, rest)
+    } else if let Some(rest) = raw.strip_prefix("## ") {
+      (Style::Subtitle, rest)
+    } else {
+      (Style::Body, raw.trim_start())
+    };
+
+    let rows = if line.contains(" | ") { vec![line.to_string()] } else { wrap(line) };
+
+    for row in rows {
+      if y - style.leading() < MARGIN {
+        pages.push(std::mem::take(&mut page));
+        y = PAGE_HEIGHT - MARGIN;
+      }
+      y -= style.leading();
+
+      if row.contains(" | ") {
+        let cells: Vec<&str> = row.split(" | ").collect();
+        let right_columns = (cells.len() - 1) as f32;
+        let first_width = (PAGE_WIDTH - MARGIN * 2.0) * 0.5;
+        let column_width = (PAGE_WIDTH - MARGIN * 2.0 - first_width) / right_columns.max(1.0);
+        for (i, cell) in cells.iter().enumerate() {
+          let x = if i == 0 { MARGIN } else { MARGIN + first_width + column_width * (i - 1) as f32 };
+          write_text(&mut page, style, x, y, cell.trim());
+        }
+      } else if !row.is_empty() {
+        write_text(&mut page, style, MARGIN, y, &row);
+      }
+    }
+  }
+  pages.push(page);
+
+  assemble(pages)
+}
+
+fn wrap(line: &str) -> Vec<String> {
+  let mut rows = vec![];
+  let mut current = String::new();
+
+  for word in line.split(' ') {
+    if !current.is_empty() && current.chars().count() + word.chars().count() + 1 > WRAP_AT {
+      rows.push(std::mem::take(&mut current));
+    }
+    if !current.is_empty() {
+      current.push(' ');
+    }
+    current.push_str(word);
+  }
+  rows.push(current);
+  rows
+}
+
+fn write_text(page: &mut Vec<u8>, style: Style, x: f32, y: f32, text: &str) {
+  page.extend_from_slice(format!("BT /{} {} Tf 1 0 0 1 {:.2} {:.2} Tm (", style.font(), style.size(), x, y).as_bytes());
+  page.extend(encode(text));
+  page.extend_from_slice(b") Tj ET\n");
+}
+
+/* Standard fonts use WinAnsiEncoding, which matches latin-1 for the accents and ñ we use. */
+fn encode(text: &str) -> Vec<u8> {
+  let mut out = vec![];
+  for c in text.chars() {
+    match c {
+      '(' | ')' | '\\' => {
+        out.push(b'\\');
+        out.push(c as u8);
+      }
+      '€' => out.push(0x80),
+      c if (c as u32) < 0x100 => out.push(c as u32 as u8),
+      _ => out.push(b'?'),
+    }
+  }
+  out
+}
+
+fn assemble(pages: Vec<Vec<u8>>) -> Vec<u8> {
+  let mut objects: Vec<Vec<u8>> = vec![];
+  let page_count = pages.len();
+  let first_page_object = 5;
+
+  let kids: Vec<String> = (0..page_count).map(|i| format!("{} 0 R", first_page_object + i * 2)).collect();
+
+  objects.push(b"<< /Type /Catalog /Pages 2 0 R >>".to_vec());
+  objects.push(format!("<< /Type /Pages /Kids [{}] /Count {} >>", kids.join(" "), page_count).into_bytes());
+  objects.push(b"<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica /Encoding /WinAnsiEncoding >>".to_vec());
+  objects.push(b"<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica-Bold /Encoding /WinAnsiEncoding >>".to_vec());
+
+  for (i, content) in pages.into_iter().enumerate() {
+    objects.push(format!(
+      "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {} {}] /Resources << /Font << /F1 3 0 R /F2 4 0 R >> >> /Contents {} 0 R >>",
+      PAGE_WIDTH, PAGE_HEIGHT, first_page_object + i * 2 + 1
+    ).into_bytes());
+
+    let mut stream = format!("<< /Length {} >>\nstream\n", content.len()).into_bytes();
+    stream.extend(content);
+    stream.extend_from_slice(b"\nendstream");
+    objects.push(stream);
+  }
+
+  let mut out = b"%PDF-1.4\n".to_vec();
+  let mut offsets = vec![];
+
+  for (i, object) in objects.iter().enumerate() {
+    offsets.push(out.len());
+    out.extend_from_slice(format!("{} 0 obj\n", i + 1).as_bytes());
+    out.extend_from_slice(object);
+    out.extend_from_slice(b"\nendobj\n");
+  }
+
+  let xref_offset = out.len();
+  out.extend_from_slice(format!("xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1).as_bytes());
+  for offset in offsets {
+    out.extend_from_slice(format!("{:010} 00000 n \n", offset).as_bytes());
+  }
+  out.extend_from_slice(format!(
+    "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF\n",
+    objects.len() + 1,
+    xref_offset
+  ).as_bytes());
+
+  out
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn renders_text_into_pdf_pages() {
+    let one_page = from_text("# Factura A2022-000001\n\nDescripción | Base | IVA | Total\nSubscripción (Academy) | 123.97 | 26.03 | 150.00");
+    let as_string = String::from_utf8_lossy(&one_page);
+
+    assert!(one_page.starts_with(b"%PDF-1.4\n"));
+    assert!(one_page.ends_with(b"%%EOF\n"));
+    assert!(as_string.contains("/Count 1"));
+    assert!(as_string.contains("(Factura A2022-000001) Tj"));
+    assert!(as_string.contains("Subscripci"));
+    assert!(as_string.contains("\\(Academy\\)"));
+
+    let long = (0..200).map(|i| format!("line {}", i)).collect::<Vec<String>>().join("\n");
+    let several_pages = String::from_utf8_lossy(&from_text(&long)).to_string();
+    assert!(several_pages.contains("/Count 5"));
+  }
+
+  #[test]
+  fn wraps_long_lines() {
+    let line = "palabra ".repeat(30);
+    let rows = wrap(line.trim());
+    assert_eq!(rows.len(), 3);
+    assert!(rows.iter().all(|r| r.chars().count() <= WRAP_AT));
+  }
+}
@@ -0,0 +1,138 @@
+use crate::{error::Result, EMBEDDED_TEMPLATES, TEMPLATES};
+use std::{
+  path::{Path, PathBuf},
+  sync::Mutex,
+  time::SystemTime,
+};
+use tera::Tera;
+
+type Snapshot = Vec<(PathBuf, SystemTime)>;
+
+lazy_static::lazy_static! {
+  static ref LAST_SEEN: Mutex<Option<Snapshot>> = Mutex::new(None);
+}
+
+pub fn embedded() -> Tera {
+  let mut tera = Tera::default();
+  tera.add_raw_templates(EMBEDDED_TEMPLATES.to_vec()).expect("No static");
+  tera
+}
+
+pub fn render(name: &str, context: &tera::Context) -> Result<String> {
+  Ok(TEMPLATES.read().expect("templates lock poisoned").render(name, context)?)
+}
+
+pub fn exists(name: &str) -> bool {
+  TEMPLATES.read().expect("templates lock poisoned").get_template_names().any(|n| n == name)
+}
+
+pub fn names() -> Vec<String> {
+  let mut names: Vec<String> = TEMPLATES.read().expect("templates lock poisoned")
+    .get_template_names()
+    .map(|n| n.to_string())
+    .collect();
+  names.sort();
+  names
+}
+
+/* "en/emails/welcome.html.tera" in the templates dir overrides "en/emails/welcome". */
+pub fn template_name(relative_path: &Path) -> String {
+  relative_path.with_extension("").with_extension("")
+    .components()
+    .map(|c| c.as_os_str().to_string_lossy().to_string())
+    .collect::<Vec<String>>()
+    .join("/")
+}
+
+/* Starts over from the embedded templates every time, so deleting an override restores the default.
+ * If any file fails to parse nothing is replaced, and emails keep going out with the previous templates.
+ * The files are remembered either way, a broken template is only retried once it changes. */
+pub fn load_overrides(dir: &Path) -> Result<usize> {
+  let files = template_files(dir)?;
+  *LAST_SEEN.lock().expect("templates lock poisoned") = Some(files.clone());
+
+  let mut overrides = vec![];
+  for (path, _) in files.iter() {
+    let name = template_name(path.strip_prefix(dir).unwrap_or(path));
+    overrides.push((name, std::fs::read_to_string(path)?));
+  }
+
+  let mut tera = embedded();
+  tera.add_raw_templates(overrides.iter().map(|(n, c)| (n.as_str(), c.as_str())).collect::<Vec<_>>())?;
+  *TEMPLATES.write().expect("templates lock poisoned") = tera;
+
+  Ok(overrides.len())
+}
+
+/* Polled by a background job, it's cheaper than it sounds for a handful of files.
+ * Any file added, removed or modified counts, not just the newest one. */
+pub fn reload_if_changed(dir: &Path) -> Result<bool> {
+  let files = template_files(dir)?;
+  if Some(&files) == LAST_SEEN.lock().expect("templates lock poisoned").as_ref() {
+    return Ok(false);
+  }
+
+  load_overrides(dir)?;
+  Ok(true)
+}
+
+fn template_files(dir: &Path) -> Result<Snapshot> {
+  let mut found = vec![];
+  let mut pending = vec![dir.to_path_buf()];
+
+  while let Some(current) = pending.pop() {
+    for entry in std::fs::read_dir(&current)? {
+      let entry = entry?;
+      let path = entry.path();
+      if path.is_dir() {
+        pending.push(path);
+      } else if path.extension().map(|e| e == "tera").unwrap_or(false) {
+        found.push((path, entry.metadata()?.modified()?));
+      }
+    }
+  }
+
+  found.sort();
+  Ok(found)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn names_templates_after_their_path() {
+    assert_eq!(template_name(Path::new("emails/welcome.html.tera")), "emails/welcome");
+    assert_eq!(template_name(Path::new("pt/emails/payment_link.html.tera")), "pt/emails/payment_link");
+    assert_eq!(template_name(Path::new("pdfs/receipt.txt.tera")), "pdfs/receipt");
+  }
+
+  #[test]
+  fn overrides_and_keeps_previous_templates_on_errors() {
+    let dir = std::env::temp_dir().join(format!("daoe-templates-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("emails")).unwrap();
+    std::fs::write(dir.join("emails/testing_override.html.tera"), "Hola {{ full_name }}").unwrap();
+
+    assert_eq!(load_overrides(&dir).unwrap(), 1);
+    let mut context = tera::Context::new();
+    context.insert("full_name", "Testing Testinger");
+    assert_eq!(render("emails/testing_override", &context).unwrap(), "Hola Testing Testinger");
+    assert!(exists("emails/welcome"));
+    assert!(!reload_if_changed(&dir).unwrap());
+
+    std::fs::write(dir.join("emails/testing_removed.html.tera"), "Chau {{ full_name }}").unwrap();
+    assert!(reload_if_changed(&dir).unwrap());
+    assert!(exists("emails/testing_removed"));
+    std::fs::remove_file(dir.join("emails/testing_removed.html.tera")).unwrap();
+    assert!(reload_if_changed(&dir).unwrap());
+    assert!(!exists("emails/testing_removed"));
+
+    std::fs::write(dir.join("emails/testing_override.html.tera"), "Hola {{ full_name").unwrap();
+    assert!(reload_if_changed(&dir).is_err());
+    assert_eq!(render("emails/testing_override", &context).unwrap(), "Hola Testing Testinger");
+    assert!(!reload_if_changed(&dir).unwrap());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+    *TEMPLATES.write().unwrap() = embedded();
+  }
+}